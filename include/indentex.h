@@ -0,0 +1,93 @@
+/* C API of indentex, generated by cbindgen from src/capi.rs, do not edit */
+
+#ifndef INDENTEX_H
+#define INDENTEX_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The source has been transpiled, `out` points to the LaTeX code
+#define INDENTEX_OK 0
+
+// The source contains errors, see the diagnostics
+#define INDENTEX_TRANSPILATION_ERROR 1
+
+// A required pointer is null or a string is not valid UTF-8
+#define INDENTEX_INVALID_ARGUMENT 2
+
+// indentex panicked, this is a bug
+#define INDENTEX_INTERNAL_ERROR 3
+
+// Transpilation options, pointers may be null if the corresponding count is zero
+typedef struct indentex_options {
+  bool flatten_output;
+  bool prepend_do_not_edit_notice;
+  // Path of the source file used to resolve includes and references, may be null
+  const char *path;
+  // Names which are set for `# if` blocks
+  const char *const *defines;
+  size_t num_defines;
+  // `name=value` assignments for `{{name}}` placeholders
+  const char *const *variables;
+  size_t num_variables;
+} indentex_options;
+
+typedef struct indentex_diagnostic {
+  bool is_error;
+  // Included file the diagnostic belongs to, null for the transpiled source
+  char *file;
+  // One-based line number
+  size_t line;
+  // One-based column in characters, 0 if the diagnostic is about the whole line
+  size_t column;
+  char *message;
+} indentex_diagnostic;
+
+typedef struct indentex_diagnostics {
+  struct indentex_diagnostic *items;
+  size_t len;
+} indentex_diagnostics;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Transpile `len` bytes of UTF-8 encoded indentex source
+//
+// On success `*out` is set to the NUL-terminated LaTeX code, otherwise to null. If `diags` is not
+// null, `*diags` is set to the diagnostics in any case except for invalid arguments. Free both
+// with `indentex_string_free` and `indentex_diagnostics_free`. If `options` is null, the
+// do-not-edit notice is prepended and nothing else is set.
+//
+// # Safety
+//
+// `source` must point to `len` readable bytes, `out` must be valid for writes, and `options` and
+// `diags` must be null or valid. All strings in `options` must be NUL-terminated.
+int indentex_transpile(const char *source,
+                       size_t len,
+                       const struct indentex_options *options,
+                       char **out,
+                       struct indentex_diagnostics **diags);
+
+// Free a string returned by indentex, null is ignored
+//
+// # Safety
+//
+// `s` must be null or returned by indentex and not freed before.
+void indentex_string_free(char *s);
+
+// Free diagnostics returned by indentex, null is ignored
+//
+// # Safety
+//
+// `diags` must be null or returned by indentex and not freed before.
+void indentex_diagnostics_free(struct indentex_diagnostics *diags);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* INDENTEX_H */
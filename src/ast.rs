@@ -0,0 +1,357 @@
+use std::sync::Arc;
+use std::vec::Vec;
+use handlers::HashlineHandler;
+use parsers::{Command, Environment, Item};
+
+
+// Lines and columns are one-based, the end column points after the last character
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+// Nodes added by filters may omit the span and any empty or false fields
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Node {
+    // Spans from the hashline to the last line of the body
+    Environment {
+        #[serde(flatten)]
+        environment: Environment,
+        #[serde(default)]
+        span: Span,
+        #[serde(default)]
+        children: Vec<Node>,
+    },
+    Command {
+        #[serde(flatten)]
+        command: Command,
+        #[serde(default)]
+        span: Span,
+    },
+    Item {
+        #[serde(flatten)]
+        item: Item,
+        #[serde(default)]
+        span: Span,
+    },
+    // Any other line including blank ones, which is passed through unchanged
+    Text {
+        text: String,
+        #[serde(default)]
+        span: Span,
+    },
+    Comment {
+        text: String,
+        #[serde(default)]
+        span: Span,
+    },
+    // Lines a hashline handler replaced a hashline with
+    Custom {
+        name: String,
+        lines: Vec<String>,
+        #[serde(default)]
+        span: Span,
+    },
+}
+
+impl Node {
+    pub fn span(&self) -> &Span {
+        match *self {
+            Node::Environment { ref span, .. } |
+            Node::Command { ref span, .. } |
+            Node::Item { ref span, .. } |
+            Node::Text { ref span, .. } |
+            Node::Comment { ref span, .. } |
+            Node::Custom { ref span, .. } => span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename = "document")]
+pub struct SyntaxTree {
+    pub children: Vec<Node>,
+}
+
+impl SyntaxTree {
+    /// One line per node, indented by the nesting level
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        write_nodes(&self.children, 0, &mut text);
+        text
+    }
+}
+
+fn write_nodes(nodes: &[Node], depth: usize, text: &mut String) {
+    for node in nodes {
+        let (kind, name) = match *node {
+            Node::Environment { ref environment, .. } => ("environment", environment.name()),
+            Node::Command { ref command, .. } => ("command", command.name.as_str()),
+            Node::Item { .. } => ("item", ""),
+            Node::Text { .. } => ("text", ""),
+            Node::Comment { .. } => ("comment", ""),
+            Node::Custom { ref name, .. } => ("custom", name.as_str()),
+        };
+        let s = node.span();
+        text.push_str(&format!("{:ind$}{}{}{} {}:{}-{}:{}\n",
+                               "",
+                               kind,
+                               if name.is_empty() { "" } else { " " },
+                               name,
+                               s.start_line,
+                               s.start_column,
+                               s.end_line,
+                               s.end_column,
+                               ind = 2 * depth));
+        if let Node::Environment { ref children, .. } = *node {
+            write_nodes(children, depth + 1, text);
+        }
+    }
+}
+
+/// Problem in the structure of the lines, the line is zero-based
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+// Open environments together with the line of their hashline and the children parsed so far
+type EnvStack = Vec<(Environment, usize, Vec<Node>)>;
+
+/// Parse right-trimmed lines into a tree of environments and lines
+///
+/// Environments are closed as soon as a line is not indented deeper than their hashline. The ones
+/// opened by `# begin` are closed by `# end` instead, hence their body may stay on their level.
+pub fn parse<T: AsRef<str>>(lines: &[T]) -> SyntaxTree {
+    parse_with(lines, &[])
+}
+
+/// Parse like `parse`, giving the handlers the first refusal on each hashline
+pub fn parse_with<T: AsRef<str>>(lines: &[T], handlers: &[Arc<dyn HashlineHandler>]) -> SyntaxTree {
+    parse_checked(lines, handlers, &mut Vec::new())
+}
+
+/// Parse like `parse_with`, reporting `# begin` and `# end` lines which do not match
+pub fn parse_checked<T: AsRef<str>>(lines: &[T], handlers: &[Arc<dyn HashlineHandler>], errors: &mut Vec<ParseError>)
+                                    -> SyntaxTree {
+    use handlers::{apply_handlers, Custom};
+    use parsers::Hashline::{Command, Item, OpenEnv, PlainLine};
+    use parsers::{parse_closer, process_line};
+    use transpile::scan_indents;
+
+    let mut env_stack: EnvStack = Vec::new();
+    let mut children = Vec::new();
+
+    let adjusted_indents = scan_indents(lines);
+    // Unclosed environments end before the blank lines at the end, like the ones closed by indentation
+    let content_end = lines.iter().rposition(|l| !l.as_ref().is_empty()).map_or(0, |i| i + 1);
+
+    for (line_num, line) in lines.iter().enumerate() {
+        let line = line.as_ref();
+        let list_like_active = env_stack.last().is_some_and(|(e, _, _)| e.is_list_like());
+        let span = line_span(line, line_num, line_num, line);
+
+        let node = if let Some(name) = parse_closer(line) {
+            // Environments opened after the closed one end here as well
+            match env_stack.iter().rposition(|(e, _, _)| e.is_closed_explicitly()) {
+                Some(i) => {
+                    let (ref opened, start, _) = env_stack[i];
+                    if !name.is_empty() && name != opened.name() {
+                        errors.push(ParseError {
+                            line: line_num,
+                            message: format!("'# end {}' does not match '# begin {}' on line {}",
+                                             name, opened.name(), start + 1),
+                        });
+                    }
+                    while env_stack.len() > i {
+                        close_innermost(&mut env_stack, &mut children, lines, line_num);
+                    }
+                }
+                None => errors.push(ParseError { line: line_num, message: "'# end' without '# begin'".to_string() }),
+            }
+            None
+        } else {
+            match apply_handlers(line, handlers) {
+                Some((name, Custom::Lines(lines))) => Some(Node::Custom { name, lines, span }),
+                Some((_, Custom::Environment(e))) => {
+                    env_stack.push((e, line_num, Vec::new()));
+                    None
+                }
+                None => match process_line(line, list_like_active) {
+                    OpenEnv(e) => {
+                        env_stack.push((e, line_num, Vec::new()));
+                        None
+                    }
+                    Command(command) => Some(Node::Command { command, span }),
+                    Item(item) => Some(Node::Item { item, span }),
+                    PlainLine(text) => {
+                        if text.trim_start().starts_with('%') {
+                            Some(Node::Comment { text, span })
+                        } else {
+                            Some(Node::Text { text, span })
+                        }
+                    }
+                },
+            }
+        };
+        if let Some(n) = node {
+            match env_stack.last_mut() {
+                Some(&mut (_, _, ref mut c)) => c.push(n),
+                None => children.push(n),
+            }
+        }
+
+        let next_indent = adjusted_indents[line_num + 1];
+        while let Some((e, start, _)) = env_stack.last() {
+            if e.is_closed_explicitly() {
+                // The body may stay on the level of the hashline
+                if e.indent_depth() <= next_indent && line_num + 1 < content_end {
+                    break;
+                }
+                errors.push(ParseError {
+                    line: *start,
+                    message: format!("environment '{}' is not closed by '# end'", e.name()),
+                });
+            } else if e.indent_depth() < next_indent {
+                break;
+            }
+            close_innermost(&mut env_stack, &mut children, lines, line_num);
+        }
+    }
+
+    SyntaxTree { children }
+}
+
+// Close the innermost open environment on line `end`
+fn close_innermost<T: AsRef<str>>(env_stack: &mut EnvStack, children: &mut Vec<Node>, lines: &[T], end: usize) {
+    // `unwrap()` is safe here since we have already checked if the stack is empty
+    let (environment, start, c) = env_stack.pop().unwrap();
+    let span = line_span(lines[start].as_ref(), start, end, lines[end].as_ref());
+    let n = Node::Environment { environment, span, children: c };
+    match env_stack.last_mut() {
+        Some(&mut (_, _, ref mut c)) => c.push(n),
+        None => children.push(n),
+    }
+}
+
+// From the first non-blank character of `first` to the end of `last`
+fn line_span(first: &str, start: usize, end: usize, last: &str) -> Span {
+    Span {
+        start_line: start + 1,
+        start_column: first.chars().count() - first.trim_start().chars().count() + 1,
+        end_line: end + 1,
+        end_column: last.chars().count() + 1,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse() {
+        use super::{parse, Node, Span};
+
+        let tree = parse(&["# itemize:", "  * a", "  % b", "", "  # c: d", "e"]);
+        assert_eq!(tree.children.len(), 2);
+        match tree.children[0] {
+            Node::Environment { ref environment, ref span, ref children } => {
+                assert_eq!(environment.name(), "itemize");
+                assert_eq!(*span, Span { start_line: 1, start_column: 1, end_line: 5, end_column: 9 });
+                let kinds: Vec<_> = children.iter()
+                    .map(|c| match *c {
+                        Node::Item { .. } => "item",
+                        Node::Comment { .. } => "comment",
+                        Node::Text { .. } => "text",
+                        Node::Command { .. } => "command",
+                        Node::Environment { .. } => "environment",
+                        Node::Custom { .. } => "custom",
+                    })
+                    .collect();
+                assert_eq!(kinds, ["item", "comment", "text", "command"]);
+            }
+            _ => panic!("expected an environment"),
+        }
+        assert_eq!(*tree.children[1].span(), Span { start_line: 6, start_column: 1, end_line: 6, end_column: 2 });
+    }
+
+    #[test]
+    fn to_text() {
+        use super::parse;
+
+        assert_eq!(parse(&["# center:", "  # figure [h]:", "    # caption: x", "y"]).to_text(),
+                   "environment center 1:1-3:17\n  environment figure 2:3-3:17\n    command caption 3:5-3:17\n\
+                    text 4:1-4:2\n");
+    }
+
+    #[test]
+    fn explicit_environments() {
+        use super::parse_checked;
+
+        let mut errors = Vec::new();
+        let tree = parse_checked(&["# begin document", "# section: A", "# itemize:", "  * a", "b", "# end document", "c"],
+                                 &[],
+                                 &mut errors);
+        assert_eq!(tree.to_text(),
+                   "environment document 1:1-6:15\n  command section 2:1-2:13\n  environment itemize 3:1-4:6\n    \
+                    item 4:3-4:6\n  text 5:1-5:2\ntext 7:1-7:2\n");
+        assert!(errors.is_empty());
+
+        // Closing an environment closes the ones opened after it as well
+        let tree = parse_checked(&["# begin center", "  # quote:", "    a", "    # end"], &[], &mut errors);
+        assert_eq!(tree.to_text(), "environment center 1:1-4:10\n  environment quote 2:3-4:10\n    text 3:5-3:6\n");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn unmatched_closers() {
+        use super::parse_checked;
+
+        let mut errors = Vec::new();
+        let tree = parse_checked(&["# center:", "  # begin minipage", "  a", "# end minipage", "# begin figure",
+                                   "# end table", "# begin quote", "q"],
+                                 &[],
+                                 &mut errors);
+        assert_eq!(tree.to_text(),
+                   "environment center 1:1-3:4\n  environment minipage 2:3-3:4\n    text 3:3-3:4\n\
+                    environment figure 5:1-6:12\nenvironment quote 7:1-8:2\n  text 8:1-8:2\n");
+        let errors: Vec<_> = errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(errors,
+                   [(1, "environment 'minipage' is not closed by '# end'"),
+                    (3, "'# end' without '# begin'"),
+                    (5, "'# end table' does not match '# begin figure' on line 5"),
+                    (6, "environment 'quote' is not closed by '# end'")]);
+    }
+
+    #[test]
+    fn json() {
+        use super::parse;
+
+        let tree = parse(&["# itemize [x]:", "  * a"]);
+        assert_eq!(::serde_json::to_value(&tree).unwrap(),
+                   json!({
+                       "kind": "document",
+                       "children": [{
+                           "kind": "environment",
+                           "indent_depth": 0,
+                           "name": "itemize",
+                           "opts": "[x]",
+                           "comment": "",
+                           "is_list_like": true,
+                           "is_row_based": false,
+                           "is_closed_explicitly": false,
+                           "span": {"start_line": 1, "start_column": 1, "end_line": 2, "end_column": 6},
+                           "children": [{
+                               "kind": "item",
+                               "indent_depth": 2,
+                               "text": "a",
+                               "span": {"start_line": 2, "start_column": 3, "end_line": 2, "end_column": 6},
+                           }],
+                       }],
+                   }));
+    }
+}
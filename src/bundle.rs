@@ -0,0 +1,349 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use error::IndentexError;
+use transpile::{SourceLine, TranspileOptions};
+
+
+const INCLUDE_COMMAND: &str = "include";
+const SUBFILE_COMMAND: &str = "subfile";
+// `\include` starts and ends a new page
+const CLEARPAGE: &str = r"\clearpage";
+const BEGIN_DOCUMENT: &str = r"\begin{document}";
+const END_DOCUMENT: &str = r"\end{document}";
+
+
+pub struct Bundled {
+    pub text: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// A file to be inlined
+#[derive(Debug, PartialEq)]
+enum Source {
+    Indentex(PathBuf),
+    Latex(PathBuf),
+}
+
+struct Bundler {
+    options: TranspileOptions,
+    strip_comments: bool,
+    // References are resolved against the main file and then against the referencing file
+    root: PathBuf,
+    // Canonical paths of the files being inlined at the moment
+    stack: Vec<PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Transpile the main file and inline all `\input`, `\include` and `\subfile` targets
+pub fn bundle<T: AsRef<Path>>(main: T, options: &TranspileOptions, strip_comments: bool) -> Result<Bundled, IndentexError> {
+    use transpile::DO_NOT_EDIT_NOTICE;
+
+    let main = main.as_ref();
+    let root = main.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    let mut bundler = Bundler {
+        // Only the bundle itself gets a notice
+        options: TranspileOptions {
+            prepend_do_not_edit_notice: false,
+            base_dir: Some(root.clone()),
+            ..options.clone()
+        },
+        strip_comments,
+        root,
+        stack: main.canonicalize().into_iter().collect(),
+        diagnostics: Vec::new(),
+    };
+
+    let lines = bundler.load(&Source::Indentex(main.to_path_buf()))?;
+    let body = bundler.inline_lines(&lines, true);
+
+    if bundler.diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(IndentexError::Transpilation(bundler.diagnostics));
+    }
+
+    let mut text = String::with_capacity(DO_NOT_EDIT_NOTICE.len() + body.len() + 1);
+    if options.prepend_do_not_edit_notice {
+        text.push_str(DO_NOT_EDIT_NOTICE);
+    }
+    text.push_str(&body);
+    text.push('\n');
+
+    Ok(Bundled { text, diagnostics: bundler.diagnostics })
+}
+
+impl Bundler {
+    // Return the LaTeX lines of a file, located in the files their diagnostics refer to
+    fn load(&mut self, source: &Source) -> Result<Vec<SourceLine>, IndentexError> {
+        use file_utils::read_and_trim_lines_unchecked;
+        use transpile::transpile_path;
+
+        match *source {
+            Source::Indentex(ref p) => {
+                let result = transpile_path(p, &self.options);
+                let diagnostics = match result {
+                    Ok(ref t) => &t.diagnostics,
+                    Err(IndentexError::Transpilation(ref d)) => d,
+                    Err(e) => return Err(e),
+                };
+                self.diagnostics.extend(diagnostics.iter().map(|d| Diagnostic {
+                    file: d.file.clone().or_else(|| Some(p.clone())),
+                    ..d.clone()
+                }));
+                // The transpiled file is never written, hence its lines are located in the sources
+                Ok(result?.source_lines(p))
+            }
+            Source::Latex(ref p) => {
+                Ok(SourceLine::number_in_file(&read_and_trim_lines_unchecked(p)?, Some(Arc::new(p.clone()))))
+            }
+        }
+    }
+
+    // References in transpiled lines have already been checked by the transpiler
+    fn inline_lines(&mut self, lines: &[SourceLine], checked: bool) -> String {
+        let mut text = String::new();
+
+        for l in lines {
+            let line = if self.strip_comments {
+                match strip_line_comment(&l.text) {
+                    Some(s) => l.with_text(s),
+                    None => continue,
+                }
+            } else {
+                l.clone()
+            };
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            let inlined = self.inline_references(&line, checked);
+            text.push_str(&inlined);
+        }
+
+        text
+    }
+
+    fn inline_references(&mut self, line: &SourceLine, checked: bool) -> String {
+        use references::find_command;
+        use transpile::strip_comment;
+
+        // Paths relative to the file containing the reference are accepted as well
+        let dir = line.file.as_ref().and_then(|f| f.parent()).unwrap_or_else(|| Path::new(""));
+        let code = strip_comment(&line.text);
+        let mut inlined = String::with_capacity(line.text.len());
+        let mut pos = 0;
+
+        while let Some((start, command)) = find_command(&code[pos..]) {
+            let start = pos + start;
+            let arg_start = start + command.len() + r"\{".len();
+            let arg_end = match code[arg_start..].find('}') {
+                Some(e) => arg_start + e,
+                None => break,
+            };
+            let target = code[arg_start..arg_end].trim();
+            inlined.push_str(&line.text[pos..start]);
+
+            let contents = match self.resolve(target, dir) {
+                Some(source) => self.inline_file(source, command, line),
+                None if checked => None,
+                None => {
+                    let msg = format!("could not resolve reference '{}'", target);
+                    self.diagnostics.push(Diagnostic::warning(line, msg));
+                    None
+                }
+            };
+            match contents {
+                Some(ref c) if command == INCLUDE_COMMAND => {
+                    inlined.push_str(&format!("{}\n{}\n{}", CLEARPAGE, c, CLEARPAGE));
+                }
+                Some(ref c) => inlined.push_str(c),
+                None => inlined.push_str(&line.text[start..arg_end + 1]),
+            }
+            pos = arg_end + 1;
+        }
+        inlined.push_str(&line.text[pos..]);
+
+        inlined
+    }
+
+    fn inline_file(&mut self, source: Source, command: &str, line: &SourceLine) -> Option<String> {
+        let (path, transpiled) = match source {
+            Source::Indentex(ref p) => (p.clone(), true),
+            Source::Latex(ref p) => (p.clone(), false),
+        };
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.stack.contains(&canonical) {
+            let cycle = self.stack.iter()
+                .skip_while(|p| **p != canonical)
+                .chain(Some(&canonical))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            self.diagnostics.push(Diagnostic::error(line, format!("reference cycle: {}", cycle)));
+            return None;
+        }
+
+        let lines = match self.load(&source) {
+            Ok(l) => l,
+            // Transpilation errors have already been reported
+            Err(IndentexError::Transpilation(_)) => return None,
+            Err(e) => {
+                let msg = format!("could not inline '{}': {}", path.display(), e);
+                self.diagnostics.push(Diagnostic::error(line, msg));
+                return None;
+            }
+        };
+        let lines = if command == SUBFILE_COMMAND { document_body(lines) } else { lines };
+
+        self.stack.push(canonical);
+        let text = self.inline_lines(&lines, transpiled);
+        self.stack.pop();
+
+        Some(text)
+    }
+
+    fn resolve(&self, target: &str, dir: &Path) -> Option<Source> {
+        use file_utils::INDENTEX_EXTENSION;
+        use references::{indentex_source, TEX_EXTENSION};
+
+        // LaTeX appends `.tex` to targets without this extension
+        let mut candidates = vec![target.to_string()];
+        if !target.ends_with(TEX_EXTENSION) {
+            candidates.insert(0, format!("{}{}", target, TEX_EXTENSION));
+        }
+
+        for d in &[self.root.as_path(), dir] {
+            for c in &candidates {
+                let path = d.join(c);
+                // Prefer the sources over outputs, which might be outdated
+                if let Some(source) = indentex_source(&path).filter(|s| s.is_file()) {
+                    return Some(Source::Indentex(source));
+                }
+                if path.is_file() {
+                    return Some(if c.ends_with(INDENTEX_EXTENSION) {
+                        Source::Indentex(path)
+                    } else {
+                        Source::Latex(path)
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+// Remove a comment, `None` if the whole line is a comment
+fn strip_line_comment(line: &str) -> Option<String> {
+    use transpile::strip_comment;
+
+    let code = strip_comment(line);
+    if code.len() == line.len() {
+        Some(line.to_string())
+    } else if code.trim().is_empty() {
+        None
+    } else {
+        // Keep the `%` so that the line break is still ignored
+        Some(format!("{}%", code))
+    }
+}
+
+// Subfiles are complete documents, only their body is inlined
+fn document_body(lines: Vec<SourceLine>) -> Vec<SourceLine> {
+    let begin = lines.iter().position(|l| l.text.trim() == BEGIN_DOCUMENT);
+    let end = lines.iter().rposition(|l| l.text.trim() == END_DOCUMENT);
+    match (begin, end) {
+        (Some(b), Some(e)) if b < e => lines[b + 1..e].to_vec(),
+        _ => lines,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use test_utils::TempDir;
+    use transpile::TranspileOptions;
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("bundle_{}", name));
+        fs::create_dir_all(dir.join("ch")).unwrap();
+        dir
+    }
+
+    fn options() -> TranspileOptions {
+        TranspileOptions {
+            prepend_do_not_edit_notice: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn strip_line_comment() {
+        use super::strip_line_comment;
+
+        assert_eq!(strip_line_comment("a"), Some("a".to_string()));
+        assert_eq!(strip_line_comment(r"a \% b % c"), Some(r"a \% b %".to_string()));
+        assert_eq!(strip_line_comment("  % c"), None);
+    }
+
+    #[test]
+    fn document_body() {
+        use super::document_body;
+        use transpile::SourceLine;
+
+        let body = |l: &[&str]| document_body(SourceLine::number(l)).into_iter().map(|l| l.text).collect::<Vec<_>>();
+        assert_eq!(body(&[r"\documentclass{x}", r"\begin{document}", "a", r"\end{document}"]), ["a"]);
+        assert_eq!(body(&["a", "b"]), ["a", "b"]);
+    }
+
+    #[test]
+    fn bundle() {
+        use super::bundle;
+        use transpile::DO_NOT_EDIT_NOTICE;
+
+        let dir = temp_dir("inline");
+        fs::write(dir.join("main.inden.tex"), "# input: ch/a.inden.tex\n\\include{ch/b} % b\n\\input{gone}\n").unwrap();
+        fs::write(dir.join("ch/a.inden.tex"), "# emph: a\n\\input{none}\n").unwrap();
+        fs::write(dir.join("ch/b.tex"), "% comment\nb \\input{c}\n\\input{lost}\n").unwrap();
+        fs::write(dir.join("ch/c.tex"), "c").unwrap();
+
+        let bundled = bundle(dir.join("main.inden.tex"), &options(), false).unwrap();
+        assert_eq!(bundled.text,
+                   format!("{}\\emph{{a}}\n\\input{{none}}\n\\clearpage\n% comment\nb c\n\\input{{lost}}\n\
+                            \\clearpage % b\n\\input{{gone}}\n",
+                           DO_NOT_EDIT_NOTICE));
+        // Missing files in indentex sources are reported once by the transpiler, in LaTeX files by the bundler
+        let locations: Vec<_> = bundled.diagnostics
+            .iter()
+            .map(|d| (d.file.as_ref().unwrap().strip_prefix(&*dir).unwrap().to_path_buf(), d.line))
+            .collect();
+        assert_eq!(locations,
+                   [(PathBuf::from("main.inden.tex"), 3),
+                    (PathBuf::from("ch/a.inden.tex"), 2),
+                    (PathBuf::from("ch/b.tex"), 3)]);
+
+        let bundled = bundle(dir.join("main.inden.tex"), &options(), true).unwrap();
+        assert!(bundled.text
+            .ends_with("\\emph{a}\n\\input{none}\n\\clearpage\nb c\n\\input{lost}\n\\clearpage %\n\\input{gone}\n"));
+    }
+
+    #[test]
+    fn cycles() {
+        use super::bundle;
+
+        use error::IndentexError;
+
+        let dir = temp_dir("cycles");
+        fs::write(dir.join("main.inden.tex"), "\\input{ch/a}\n").unwrap();
+        fs::write(dir.join("ch/a.inden.tex"), "# emph: a\n\\input{main}\n").unwrap();
+
+        // The cycle is reported in the source of the transpiled file
+        match bundle(dir.join("main.inden.tex"), &options(), false) {
+            Err(IndentexError::Transpilation(d)) => {
+                assert_eq!(d.len(), 1);
+                assert_eq!((d[0].file.clone(), d[0].line), (Some(dir.join("ch/a.inden.tex")), 2));
+            }
+            _ => panic!("the cycle has not been reported"),
+        }
+    }
+}
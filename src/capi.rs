@@ -0,0 +1,282 @@
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+
+/// The source has been transpiled, `out` points to the LaTeX code
+pub const INDENTEX_OK: c_int = 0;
+/// The source contains errors, see the diagnostics
+pub const INDENTEX_TRANSPILATION_ERROR: c_int = 1;
+/// A required pointer is null or a string is not valid UTF-8
+pub const INDENTEX_INVALID_ARGUMENT: c_int = 2;
+/// indentex panicked, this is a bug
+pub const INDENTEX_INTERNAL_ERROR: c_int = 3;
+
+/// Transpilation options, pointers may be null if the corresponding count is zero
+#[repr(C)]
+pub struct IndentexOptions {
+    pub flatten_output: bool,
+    pub prepend_do_not_edit_notice: bool,
+    /// Path of the source file used to resolve includes and references, may be null
+    pub path: *const c_char,
+    /// Names which are set for `# if` blocks
+    pub defines: *const *const c_char,
+    pub num_defines: usize,
+    /// `name=value` assignments for `{{name}}` placeholders
+    pub variables: *const *const c_char,
+    pub num_variables: usize,
+}
+
+#[repr(C)]
+pub struct IndentexDiagnostic {
+    pub is_error: bool,
+    /// Included file the diagnostic belongs to, null for the transpiled source
+    pub file: *mut c_char,
+    /// One-based line number
+    pub line: usize,
+    /// One-based column in characters, 0 if the diagnostic is about the whole line
+    pub column: usize,
+    pub message: *mut c_char,
+}
+
+#[repr(C)]
+pub struct IndentexDiagnostics {
+    pub items: *mut IndentexDiagnostic,
+    pub len: usize,
+}
+
+/// Transpile `len` bytes of UTF-8 encoded indentex source
+///
+/// On success `*out` is set to the NUL-terminated LaTeX code, otherwise to null. If `diags` is not
+/// null, `*diags` is set to the diagnostics in any case except for invalid arguments. Free both
+/// with `indentex_string_free` and `indentex_diagnostics_free`. If `options` is null, the
+/// do-not-edit notice is prepended and nothing else is set.
+///
+/// # Safety
+///
+/// `source` must point to `len` readable bytes, `out` must be valid for writes, and `options` and
+/// `diags` must be null or valid. All strings in `options` must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn indentex_transpile(source: *const c_char,
+                                            len: usize,
+                                            options: *const IndentexOptions,
+                                            out: *mut *mut c_char,
+                                            diags: *mut *mut IndentexDiagnostics)
+                                            -> c_int {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    if out.is_null() {
+        return INDENTEX_INVALID_ARGUMENT;
+    }
+    *out = ptr::null_mut();
+    if !diags.is_null() {
+        *diags = ptr::null_mut();
+    }
+    if source.is_null() && len > 0 {
+        return INDENTEX_INVALID_ARGUMENT;
+    }
+
+    // Unwinding into C is undefined behaviour
+    catch_unwind(AssertUnwindSafe(|| transpile_source(source, len, options, out, diags)))
+        .unwrap_or(INDENTEX_INTERNAL_ERROR)
+}
+
+unsafe fn transpile_source(source: *const c_char,
+                           len: usize,
+                           options: *const IndentexOptions,
+                           out: *mut *mut c_char,
+                           diags: *mut *mut IndentexDiagnostics)
+                           -> c_int {
+    use std::ffi::CString;
+    use std::path::Path;
+    use std::slice;
+    use std::str;
+    use diagnostics::Diagnostic;
+    use transpile::transpile;
+
+    let bytes = if len == 0 { &[][..] } else { slice::from_raw_parts(source as *const u8, len) };
+    let (text, options, path) = match (str::from_utf8(bytes), convert_options(options)) {
+        (Ok(t), Some((o, p))) => (t, o, p),
+        _ => return INDENTEX_INVALID_ARGUMENT,
+    };
+
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    let transpiled = transpile(&lines, path.as_ref().map(Path::new), &options);
+
+    if !diags.is_null() {
+        *diags = into_c_diagnostics(&transpiled.diagnostics);
+    }
+    if transpiled.diagnostics.iter().any(Diagnostic::is_error) {
+        return INDENTEX_TRANSPILATION_ERROR;
+    }
+    match CString::new(transpiled.text) {
+        Ok(s) => {
+            *out = s.into_raw();
+            INDENTEX_OK
+        }
+        // The source contained a NUL character
+        Err(_) => INDENTEX_INVALID_ARGUMENT,
+    }
+}
+
+// Return `None` if a string is not valid UTF-8 or an assignment lacks the `=`
+unsafe fn convert_options(options: *const IndentexOptions) -> Option<(::transpile::TranspileOptions, Option<String>)> {
+    use transpile::TranspileOptions;
+
+    let mut converted = TranspileOptions {
+        prepend_do_not_edit_notice: true,
+        ..Default::default()
+    };
+    let o = match options.as_ref() {
+        Some(o) => o,
+        None => return Some((converted, None)),
+    };
+
+    converted.flatten_output = o.flatten_output;
+    converted.prepend_do_not_edit_notice = o.prepend_do_not_edit_notice;
+    for name in c_strings(o.defines, o.num_defines)? {
+        converted.defines.insert(name, true);
+    }
+    for assignment in c_strings(o.variables, o.num_variables)? {
+        let i = assignment.find('=')?;
+        converted.variables.insert(assignment[..i].to_string(), assignment[i + 1..].to_string());
+    }
+    let path = if o.path.is_null() { None } else { Some(c_string(o.path)?) };
+
+    Some((converted, path))
+}
+
+unsafe fn c_strings(strings: *const *const c_char, len: usize) -> Option<Vec<String>> {
+    use std::slice;
+
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    if strings.is_null() {
+        return None;
+    }
+    slice::from_raw_parts(strings, len).iter().map(|&s| if s.is_null() { None } else { c_string(s) }).collect()
+}
+
+unsafe fn c_string(s: *const c_char) -> Option<String> {
+    use std::ffi::CStr;
+
+    CStr::from_ptr(s).to_str().ok().map(String::from)
+}
+
+fn into_c_diagnostics(diagnostics: &[::diagnostics::Diagnostic]) -> *mut IndentexDiagnostics {
+    use std::ffi::CString;
+
+    // Interior NUL characters cannot be represented, hence drop everything after them
+    let to_c = |s: String| {
+        let end = s.find('\0').unwrap_or(s.len());
+        // `unwrap()` is safe here since the string has been truncated before the first NUL
+        CString::new(&s[..end]).unwrap().into_raw()
+    };
+    let items: Box<[IndentexDiagnostic]> = diagnostics.iter()
+        .map(|d| IndentexDiagnostic {
+            is_error: d.is_error(),
+            file: d.file.as_ref().map_or(ptr::null_mut(), |f| to_c(f.display().to_string())),
+            line: d.line,
+            column: d.column.unwrap_or(0),
+            message: to_c(d.message.clone()),
+        })
+        .collect();
+    let len = items.len();
+    Box::into_raw(Box::new(IndentexDiagnostics { items: Box::into_raw(items) as *mut IndentexDiagnostic, len }))
+}
+
+/// Free a string returned by indentex, null is ignored
+///
+/// # Safety
+///
+/// `s` must be null or returned by indentex and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn indentex_string_free(s: *mut c_char) {
+    use std::ffi::CString;
+
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Free diagnostics returned by indentex, null is ignored
+///
+/// # Safety
+///
+/// `diags` must be null or returned by indentex and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn indentex_diagnostics_free(diags: *mut IndentexDiagnostics) {
+    if diags.is_null() {
+        return;
+    }
+    let diags = Box::from_raw(diags);
+    let items = Box::from_raw(ptr::slice_from_raw_parts_mut(diags.items, diags.len));
+    for d in items.iter() {
+        indentex_string_free(d.file);
+        indentex_string_free(d.message);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn indentex_transpile() {
+        use std::ffi::{CStr, CString};
+        use std::ptr;
+        use super::{indentex_diagnostics_free, indentex_string_free, indentex_transpile, IndentexOptions,
+                    INDENTEX_INVALID_ARGUMENT, INDENTEX_OK, INDENTEX_TRANSPILATION_ERROR};
+
+        let source = "# if draft:\n  # section: {{title}}  \n";
+        let defines = [CString::new("draft").unwrap()];
+        let variables = [CString::new("title=A=B").unwrap()];
+        let define_ptrs: Vec<_> = defines.iter().map(|s| s.as_ptr()).collect();
+        let variable_ptrs: Vec<_> = variables.iter().map(|s| s.as_ptr()).collect();
+        let options = IndentexOptions {
+            flatten_output: false,
+            prepend_do_not_edit_notice: false,
+            path: ptr::null(),
+            defines: define_ptrs.as_ptr(),
+            num_defines: 1,
+            variables: variable_ptrs.as_ptr(),
+            num_variables: 1,
+        };
+
+        unsafe {
+            let mut out = ptr::null_mut();
+            let mut diags = ptr::null_mut();
+            let ret = indentex_transpile(source.as_ptr() as *const _, source.len(), &options, &mut out, &mut diags);
+            assert_eq!(ret, INDENTEX_OK);
+            assert_eq!(CStr::from_ptr(out).to_str(), Ok("\\section{A=B}\n"));
+            assert_eq!((*diags).len, 0);
+            indentex_string_free(out);
+            indentex_diagnostics_free(diags);
+
+            let source = "# else:\n";
+            let ret = indentex_transpile(source.as_ptr() as *const _, source.len(), ptr::null(), &mut out, &mut diags);
+            assert_eq!(ret, INDENTEX_TRANSPILATION_ERROR);
+            assert!(out.is_null());
+            assert_eq!((*diags).len, 1);
+            let d = &*(*diags).items;
+            assert!(d.is_error);
+            assert!(d.file.is_null());
+            assert_eq!(d.line, 1);
+            assert_eq!(d.column, 0);
+            assert!(!CStr::from_ptr(d.message).to_bytes().is_empty());
+            indentex_diagnostics_free(diags);
+
+            let source = "x\n# textbf: {a\n";
+            let ret = indentex_transpile(source.as_ptr() as *const _, source.len(), ptr::null(), &mut out, &mut diags);
+            assert_eq!(ret, INDENTEX_TRANSPILATION_ERROR);
+            let d = &*(*diags).items;
+            assert_eq!((d.line, d.column), (2, 11));
+            indentex_diagnostics_free(diags);
+
+            let invalid = [0xff_u8];
+            let ret = indentex_transpile(invalid.as_ptr() as *const _, 1, ptr::null(), &mut out, ptr::null_mut());
+            assert_eq!(ret, INDENTEX_INVALID_ARGUMENT);
+            assert_eq!(indentex_transpile(ptr::null(), 0, ptr::null(), ptr::null_mut(), ptr::null_mut()),
+                       INDENTEX_INVALID_ARGUMENT);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use parsers::{parse_hashline, HashlineParts};
+use transpile::SourceLine;
+
+
+const IF_KEYWORD: &str = "if";
+const ELIF_KEYWORD: &str = "elif";
+const ELSE_KEYWORD: &str = "else";
+const NEGATION: &str = "not ";
+
+
+// State of an `# if` / `# elif` / `# else` chain
+struct Chain {
+    indent_depth: usize,
+    // Whether one of the branches has already been kept
+    taken: bool,
+}
+
+/// Keep or drop the bodies of `# if` blocks, depending on the defined names
+pub fn evaluate_conditionals(lines: Vec<SourceLine>,
+                             defines: &HashMap<String, bool>,
+                             diagnostics: &mut Vec<Diagnostic>)
+                             -> Vec<SourceLine> {
+    use transpile::scan_indents;
+
+    let adjusted_indents = scan_indents(&lines);
+    let mut evaluated = Vec::with_capacity(lines.len());
+    let mut chain: Option<Chain> = None;
+
+    let mut i = 0;
+    while i < lines.len() {
+        let parts = match parse_hashline(&lines[i]) {
+            Some(p) if is_conditional(&p) => p,
+            _ => {
+                // Any other line on the same or a lower indentation level ends the chain
+                let ends_chain = match chain {
+                    Some(ref c) => !lines[i].text.is_empty() && adjusted_indents[i] <= c.indent_depth,
+                    None => false,
+                };
+                if ends_chain {
+                    chain = None;
+                }
+                evaluated.push(lines[i].clone());
+                i += 1;
+                continue;
+            }
+        };
+
+        // The body consists of all lines indented deeper than the hashline
+        let mut j = i + 1;
+        while j < lines.len() && adjusted_indents[j] > parts.indent_depth {
+            j += 1;
+        }
+        let line = &lines[i];
+
+        let keep = if parts.name == IF_KEYWORD {
+            let condition = evaluate_condition(&parts.opts, line, defines, diagnostics);
+            chain = Some(Chain { indent_depth: parts.indent_depth, taken: condition });
+            condition
+        } else {
+            match chain {
+                Some(ref mut c) if c.indent_depth == parts.indent_depth => {
+                    let condition = if parts.name == ELIF_KEYWORD {
+                        evaluate_condition(&parts.opts, line, defines, diagnostics)
+                    } else {
+                        if !parts.opts.is_empty() {
+                            diagnostics.push(Diagnostic::error(line, "'# else' does not take a condition"));
+                        }
+                        true
+                    };
+                    let keep = !c.taken && condition;
+                    c.taken |= keep;
+                    keep
+                }
+                _ => {
+                    let msg = format!("'# {}' without a preceding '# if'", parts.name);
+                    diagnostics.push(Diagnostic::error(line, msg));
+                    false
+                }
+            }
+        };
+        if parts.name == ELSE_KEYWORD {
+            chain = None;
+        }
+
+        if keep {
+            let body = dedent(&lines[i + 1..j], parts.indent_depth);
+            evaluated.extend(evaluate_conditionals(body, defines, diagnostics));
+        }
+        i = j;
+    }
+
+    evaluated
+}
+
+/// Whether a line is an `# if`, `# elif` or `# else`
+pub fn is_conditional_line<T: AsRef<str>>(line: T) -> bool {
+    parse_hashline(line).is_some_and(|p| is_conditional(&p))
+}
+
+/// Whether a line continues the chain of a preceding `# if`, i.e. is an `# elif` or `# else`
+pub fn continues_chain<T: AsRef<str>>(line: T) -> bool {
+    match parse_hashline(line) {
+        Some(ref p) => is_conditional(p) && p.name != IF_KEYWORD,
+        None => false,
+    }
+}
+
+#[inline]
+fn is_conditional(parts: &HashlineParts) -> bool {
+    let keyword = parts.name == IF_KEYWORD || parts.name == ELIF_KEYWORD || parts.name == ELSE_KEYWORD;
+    // Hashlines with arguments are plain commands, e.g. `# if: foo` becomes `\if{foo}`
+    keyword && parts.args.is_empty()
+}
+
+fn evaluate_condition(condition: &str,
+                      line: &SourceLine,
+                      defines: &HashMap<String, bool>,
+                      diagnostics: &mut Vec<Diagnostic>)
+                      -> bool {
+    let (negated, name) = match condition.strip_prefix(NEGATION) {
+        Some(n) => (true, n.trim()),
+        None => (false, condition),
+    };
+
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        diagnostics.push(Diagnostic::error(line, format!("invalid condition '{}'", condition)));
+        return false;
+    }
+
+    let value = match defines.get(name) {
+        Some(&v) => v,
+        None => {
+            let msg = format!("undefined name '{}' in condition, assuming it is not set", name);
+            diagnostics.push(Diagnostic::warning(line, msg));
+            false
+        }
+    };
+
+    value != negated
+}
+
+// Move the body to the indentation level of its hashline
+//
+// Only spaces are removed, since other whitespace may be wider than one byte.
+fn dedent(body: &[SourceLine], indent_depth: usize) -> Vec<SourceLine> {
+    let body_indent = body.iter()
+        .filter(|l| !l.text.is_empty())
+        .map(|l| l.text.len() - l.text.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(indent_depth);
+    let shift = body_indent.saturating_sub(indent_depth);
+
+    body.iter()
+        .map(|l| l.with_text(if l.text.is_empty() { String::new() } else { l.text[shift..].to_string() }))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use transpile::SourceLine;
+
+    fn evaluate_str(lines: &[&str], defines: &[(&str, bool)]) -> (Vec<String>, usize) {
+        use super::evaluate_conditionals;
+
+        let defines: HashMap<String, bool> = defines.iter().map(|&(k, v)| (k.to_string(), v)).collect();
+        let mut diagnostics = Vec::new();
+        let evaluated = evaluate_conditionals(SourceLine::number(lines), &defines, &mut diagnostics);
+        (evaluated.into_iter().map(|l| l.text).collect(), diagnostics.len())
+    }
+
+    #[test]
+    fn if_elif_else() {
+        let src = ["# if draft:", "  a", "  # b:", "    c", "# elif final:", "  d", "# else:", "  e", "f"];
+
+        assert_eq!(evaluate_str(&src, &[("draft", true), ("final", true)]),
+                   (vec!["a".to_string(), "# b:".to_string(), "  c".to_string(), "f".to_string()], 0));
+        assert_eq!(evaluate_str(&src, &[("draft", false), ("final", true)]),
+                   (vec!["d".to_string(), "f".to_string()], 0));
+        assert_eq!(evaluate_str(&src, &[("draft", false), ("final", false)]),
+                   (vec!["e".to_string(), "f".to_string()], 0));
+        // Undefined names are reported and treated as not set
+        assert_eq!(evaluate_str(&src, &[]),
+                   (vec!["e".to_string(), "f".to_string()], 2));
+    }
+
+    #[test]
+    fn nested_and_negated() {
+        let src = ["# foo:", "  # if not draft:", "    # if final:", "      a", "    b", "  c"];
+
+        assert_eq!(evaluate_str(&src, &[("draft", false), ("final", true)]),
+                   (vec!["# foo:".to_string(), "  a".to_string(), "  b".to_string(), "  c".to_string()], 0));
+        assert_eq!(evaluate_str(&src, &[("draft", true), ("final", true)]),
+                   (vec!["# foo:".to_string(), "  c".to_string()], 0));
+    }
+
+    #[test]
+    fn non_space_indentation() {
+        // A no-break space is kept in the indentation instead of being sliced
+        let src = ["# if draft:", "  a", " \u{a0}b", "  # if final:", "   \u{a0}c"];
+
+        assert_eq!(evaluate_str(&src, &[("draft", true), ("final", true)]),
+                   (vec![" a".to_string(), "\u{a0}b".to_string(), " \u{a0}c".to_string()], 0));
+    }
+
+    #[test]
+    fn invalid_chains() {
+        // A chain is ended by any other line on the same indentation level
+        let src = ["# if draft:", "  a", "b", "# else:", "  c", "# elif x y:", "  d", "# if: e"];
+
+        assert_eq!(evaluate_str(&src, &[("draft", true)]),
+                   (vec!["a".to_string(), "b".to_string(), "# if: e".to_string()], 2));
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use error::IndentexError;
+use lint::LintConfig;
+
+
+pub const CONFIG_FILE_NAME: &str = "indentex.toml";
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Project macro files, relative to the config file
+    pub macros: Vec<PathBuf>,
+    // Names for `# if` blocks
+    pub defines: HashMap<String, bool>,
+    // Values for `{{name}}` placeholders
+    pub variables: HashMap<String, String>,
+    // Environments whose rows are terminated like in `tabular`
+    pub row_environments: Vec<String>,
+    pub lint: LintConfig,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Config, IndentexError> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut contents = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut contents)?;
+        let mut config: Config = ::toml::from_str(&contents).map_err(|e| IndentexError::Config(e.to_string()))?;
+
+        // Make relative paths independent from the working directory
+        if let Some(dir) = path.as_ref().parent() {
+            config.macros = config.macros.iter().map(|m| dir.join(m)).collect();
+        }
+        config.path = Some(path.as_ref().to_path_buf());
+
+        Ok(config)
+    }
+}
+
+/// Look for a config file in the directory of `path` and all its ancestors
+pub fn find_config_file<T: AsRef<Path>>(path: T) -> Option<PathBuf> {
+    let start = if path.as_ref().is_dir() { path.as_ref() } else { path.as_ref().parent()? };
+    let start = start.canonicalize().ok()?;
+
+    start.ancestors()
+        .map(|d| d.join(CONFIG_FILE_NAME))
+        .find(|c| c.is_file())
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_config() {
+        use super::Config;
+        use toml;
+
+        let c: Config = toml::from_str("macros = [\"a.tex\"]\nrow_environments = [\"tabularx\"]\n\
+                                        [defines]\ndraft = true\nfinal = false\n\
+                                        [variables]\nversion = \"1.2\"\n").unwrap();
+        assert_eq!(c.macros, [::std::path::PathBuf::from("a.tex")]);
+        assert_eq!(c.defines.get("draft"), Some(&true));
+        assert_eq!(c.defines.get("final"), Some(&false));
+        assert_eq!(c.variables.get("version").map(String::as_str), Some("1.2"));
+        assert_eq!(c.row_environments, ["tabularx"]);
+        assert_eq!(c.path, None);
+        assert_eq!(c.lint.max_depth, 6);
+
+        let d: Config = toml::from_str("").unwrap();
+        assert!(d.macros.is_empty());
+        assert!(d.defines.is_empty());
+        assert!(d.variables.is_empty());
+
+        assert!(toml::from_str::<Config>("foo = 1").is_err());
+    }
+
+    #[test]
+    fn parse_lint_config() {
+        use super::Config;
+        use lint::{Level, Rule};
+        use toml;
+
+        let c: Config = toml::from_str("[lint]\nmax_depth = 3\n\
+                                        [lint.rules]\nempty-environment = \"off\"\nmax-depth = \"error\"\n").unwrap();
+        assert_eq!(c.lint.max_depth, 3);
+        assert_eq!(c.lint.level(Rule::EmptyEnvironment), Level::Off);
+        assert_eq!(c.lint.level(Rule::MaxDepth), Level::Error);
+        assert_eq!(c.lint.level(Rule::InvalidName), Level::Warning);
+
+        assert!(toml::from_str::<Config>("[lint.rules]\nfoo = \"off\"\n").is_err());
+        assert!(toml::from_str::<Config>("[lint.rules]\nmax-depth = \"fatal\"\n").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::vec::Vec;
+use diagnostics::{Diagnostic, Severity};
+use transpile::SourceLine;
+
+
+// Unbalanced braces always break the LaTeX code, brackets and parentheses only in some places
+const DELIMITERS: [(char, char, Severity); 3] =
+    [('{', '}', Severity::Error), ('[', ']', Severity::Warning), ('(', ')', Severity::Warning)];
+
+
+/// Report unbalanced delimiters in the options and arguments of hashlines
+///
+/// Works on the lines as written, hence has to run before any pass changes them.
+pub fn check_delimiters(lines: &[SourceLine], diagnostics: &mut Vec<Diagnostic>) {
+    use parsers::hashline_ranges;
+
+    for line in lines {
+        let (opts, args) = match hashline_ranges(line) {
+            Some(r) => r,
+            None => continue,
+        };
+        for &(ref range, section) in &[(opts, "options"), (args, "arguments")] {
+            for (offset, delimiter) in find_unbalanced(&line.text[range.clone()]) {
+                // `unwrap()` is safe here since only delimiters are returned
+                let &(open, _, severity) = DELIMITERS.iter().find(|d| d.0 == delimiter || d.1 == delimiter).unwrap();
+                let message = if delimiter == open {
+                    format!("unclosed '{}' in the {}", delimiter, section)
+                } else {
+                    format!("unmatched '{}' in the {}", delimiter, section)
+                };
+                let column = line.text[..range.start + offset].chars().count() + 1;
+                diagnostics.push(Diagnostic::new(severity, line, message).at_column(column));
+            }
+        }
+    }
+}
+
+// Byte offsets of the unbalanced delimiters, ignoring escaped ones like `\{`
+fn find_unbalanced(text: &str) -> Vec<(usize, char)> {
+    let mut unbalanced = Vec::new();
+    // Offsets of the open delimiters of each kind
+    let mut open: Vec<Vec<usize>> = vec![Vec::new(); DELIMITERS.len()];
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if c == '\\' {
+            escaped = !escaped;
+            continue;
+        }
+        if !escaped {
+            for (k, &(o, cl, _)) in DELIMITERS.iter().enumerate() {
+                if c == o {
+                    open[k].push(i);
+                } else if c == cl && open[k].pop().is_none() {
+                    unbalanced.push((i, c));
+                }
+            }
+        }
+        escaped = false;
+    }
+
+    for (k, offsets) in open.iter().enumerate() {
+        unbalanced.extend(offsets.iter().map(|&i| (i, DELIMITERS[k].0)));
+    }
+    unbalanced.sort();
+    unbalanced
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn find_unbalanced() {
+        use super::find_unbalanced;
+
+        assert!(find_unbalanced("").is_empty());
+        assert!(find_unbalanced(r"{a [b] (c)} \{ \] \\{}").is_empty());
+        assert_eq!(find_unbalanced("[Intro"), [(0, '[')]);
+        assert_eq!(find_unbalanced("{a{b}"), [(0, '{')]);
+        assert_eq!(find_unbalanced("a} (b"), [(1, '}'), (3, '(')]);
+        assert_eq!(find_unbalanced(r"\\{a"), [(2, '{')]);
+        // Different kinds are checked independently
+        assert!(find_unbalanced("{[}]").is_empty());
+    }
+
+    #[test]
+    fn check_delimiters() {
+        use super::check_delimiters;
+        use transpile::SourceLine;
+
+        let lines = SourceLine::number(&["# section [Intro: x", "  # textbf: {a % }", r"# f (\{: ä) }", "{ text",
+                                         "# ok: {}"]);
+        let mut diagnostics = Vec::new();
+        check_delimiters(&lines, &mut diagnostics);
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages,
+                   ["1:11: warning: unclosed '[' in the options",
+                    "2:13: error: unclosed '{' in the arguments",
+                    "3:5: warning: unclosed '(' in the options",
+                    "3:11: warning: unmatched ')' in the arguments",
+                    "3:13: error: unmatched '}' in the arguments"]);
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use transpile::Transpiled;
+
+
+/// Make rule stating that the output of `source` depends on all files which influenced it
+pub fn dependency_rule<T: AsRef<Path>>(source: T, transpiled: &Transpiled, config_files: &[PathBuf]) -> Option<String> {
+    use file_utils::rename_indentex_file;
+
+    let target = rename_indentex_file(source.as_ref()).ok()?;
+
+    let mut prerequisites: Vec<&Path> = vec![source.as_ref()];
+    for p in transpiled.includes.iter().map(|d| d.path.as_path()).chain(config_files.iter().map(PathBuf::as_path)) {
+        if !prerequisites.contains(&p) {
+            prerequisites.push(p);
+        }
+    }
+
+    let prerequisites: Vec<_> = prerequisites.into_iter().map(escape).collect();
+    Some(format!("{}: {}\n", escape(&target), prerequisites.join(" ")))
+}
+
+// Escape the characters which have a special meaning in Makefiles
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn escape() {
+        use super::escape;
+
+        assert_eq!(escape(Path::new("a/b.inden.tex")), "a/b.inden.tex");
+        assert_eq!(escape(Path::new("my paper/#1 $x.tex")), r"my\ paper/\#1\ $$x.tex");
+    }
+
+    #[test]
+    fn dependency_rule() {
+        use super::dependency_rule;
+        use transpile::{Dependency, Transpiled};
+
+        let dependency = |p: &str| Dependency { from: None, path: PathBuf::from(p) };
+        let transpiled = Transpiled {
+            text: String::new(),
+            diagnostics: Vec::new(),
+            includes: vec![dependency("parts/a.inden.tex"), dependency("parts/a.inden.tex")],
+            references: vec![dependency("ch/b.inden.tex")],
+            line_map: Vec::new(),
+        };
+
+        assert_eq!(dependency_rule("paper.inden.tex", &transpiled, &[PathBuf::from("indentex.toml")]),
+                   Some("paper_indentex.tex: paper.inden.tex parts/a.inden.tex indentex.toml\n".to_string()));
+        assert_eq!(dependency_rule("paper.tex", &transpiled, &[]), None);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::path::PathBuf;
+use transpile::SourceLine;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // `None` for the file being transpiled, otherwise the included file
+    pub file: Option<PathBuf>,
+    // One-based line number in the indentex source
+    pub line: usize,
+    // One-based column in characters, if the problem is not about the whole line
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<T: Into<String>>(severity: Severity, source: &SourceLine, message: T) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: source.file.as_ref().map(|f| f.to_path_buf()),
+            line: source.line,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning<T: Into<String>>(source: &SourceLine, message: T) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, source, message)
+    }
+
+    pub fn error<T: Into<String>>(source: &SourceLine, message: T) -> Diagnostic {
+        Diagnostic::new(Severity::Error, source, message)
+    }
+
+    pub fn at_column(self, column: usize) -> Diagnostic {
+        Diagnostic { column: Some(column), ..self }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(c) => write!(f, "{}:{}: {}: {}", self.line, c, self.severity, self.message),
+            None => write!(f, "{}: {}: {}", self.line, self.severity, self.message),
+        }
+    }
+}
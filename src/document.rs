@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use macros::MacroTable;
+use transpile::{EnvironmentSpan, SourceLine, SourceLocation, TranspileOptions, Transpiled};
+
+
+// A top-level block of lines, no environment is open before or after it
+struct Block {
+    start: usize,
+    len: usize,
+    // Macros defined before the block
+    macros: MacroTable,
+    // Whether the block has to be transpiled again
+    dirty: bool,
+    // Whether the first non-empty preprocessed line is indented, i.e. continues the preceding block
+    continues: bool,
+    // Whether an environment opened by `# begin` is still open at the end, i.e. continues into the next block
+    open: bool,
+    // Adjusted indentation of each line
+    indents: Vec<usize>,
+    // Line numbers in the document are relative to `start` in all of the following
+    environments: Vec<EnvironmentSpan>,
+    output: Transpiled,
+}
+
+impl Block {
+    fn new(start: usize, len: usize) -> Block {
+        Block {
+            start,
+            len,
+            macros: MacroTable::new(),
+            dirty: true,
+            continues: false,
+            open: false,
+            indents: Vec::new(),
+            environments: Vec::new(),
+            output: empty_output(),
+        }
+    }
+}
+
+/// An indentex document which is transpiled incrementally
+///
+/// The lines are split into top-level blocks. An edit transpiles only the blocks it touches,
+/// and the following ones as long as the macros defined before them change.
+/// Filters see the whole syntax tree, hence a document with filters is transpiled in full.
+pub struct Document {
+    path: Option<PathBuf>,
+    // The notice is prepended and the filters are run once instead of for each block
+    options: TranspileOptions,
+    notice: bool,
+    filters: Vec<String>,
+    lines: Vec<String>,
+    // Number of lines of the front matter including the delimiters
+    front_matter: usize,
+    front_matter_indents: Vec<usize>,
+    front_matter_environments: Vec<EnvironmentSpan>,
+    front_matter_diagnostics: Vec<Diagnostic>,
+    variables: HashMap<String, String>,
+    blocks: Vec<Block>,
+}
+
+impl Document {
+    /// `path` is used to resolve includes and references as in `transpile`
+    pub fn new<T: AsRef<str>>(lines: &[T], path: Option<&Path>, options: &TranspileOptions) -> Document {
+        let mut document = Document {
+            path: path.map(Path::to_path_buf),
+            options: TranspileOptions { prepend_do_not_edit_notice: false, filters: Vec::new(), ..options.clone() },
+            notice: options.prepend_do_not_edit_notice,
+            filters: options.filters.clone(),
+            lines: lines.iter().map(|l| l.as_ref().trim_end().to_string()).collect(),
+            front_matter: 0,
+            front_matter_indents: Vec::new(),
+            front_matter_environments: Vec::new(),
+            front_matter_diagnostics: Vec::new(),
+            variables: HashMap::new(),
+            blocks: Vec::new(),
+        };
+        document.rebuild();
+        document
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Replace the lines `start..end` with `lines`
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn edit<T: AsRef<str>>(&mut self, start: usize, end: usize, lines: &[T]) {
+        use variables::FRONT_MATTER_DELIMITER;
+
+        let inserted = lines.len();
+        self.lines.splice(start..end, lines.iter().map(|l| l.as_ref().trim_end().to_string()));
+
+        // The front matter affects all blocks, also when an unterminated one might get terminated
+        let unterminated = self.front_matter == 0 && self.lines.first().is_some_and(|l| l == FRONT_MATTER_DELIMITER);
+        if start == 0 || start < self.front_matter || unterminated {
+            return self.rebuild();
+        }
+
+        // The block before the edit is included, since the first edited line may continue it
+        let first = self.blocks.iter().rposition(|b| b.start < start).unwrap_or(0);
+        let last = self.blocks.iter().rposition(|b| b.start < end).map_or(first, |l| l.max(first));
+        let macros = self.blocks.get(first).map_or_else(|| self.options.macros.clone(), |b| b.macros.clone());
+        let region_start = self.blocks.get(first).map_or(self.front_matter, |b| b.start);
+
+        for b in self.blocks.iter_mut().skip(last + 1) {
+            b.start = b.start + inserted - (end - start);
+        }
+        let region_end = self.blocks.get(last + 1).map_or(self.lines.len(), |b| b.start);
+
+        let blocks = self.split_blocks(region_start, region_end);
+        let replaced = first..(last + 1).min(self.blocks.len());
+        self.blocks.splice(replaced, blocks);
+        self.update(first, macros);
+    }
+
+    /// Transpile the document, which yields the same result as `transpile` on its lines
+    ///
+    /// Only the diagnostics are ordered by block instead of by pass.
+    pub fn transpiled(&self) -> Transpiled {
+        use transpile::{transpile, DO_NOT_EDIT_NOTICE};
+
+        if !self.filters.is_empty() {
+            let options = TranspileOptions {
+                prepend_do_not_edit_notice: self.notice,
+                filters: self.filters.clone(),
+                ..self.options.clone()
+            };
+            return transpile(&self.lines, self.path.as_deref(), &options);
+        }
+
+        let mut transpiled = empty_output();
+        transpiled.diagnostics = self.front_matter_diagnostics.clone();
+        if self.notice {
+            transpiled.text.push_str(DO_NOT_EDIT_NOTICE);
+            transpiled.line_map.extend(DO_NOT_EDIT_NOTICE.lines().map(|_| None));
+        }
+
+        for b in &self.blocks {
+            transpiled.text.push_str(&b.output.text);
+            transpiled.line_map.extend(b.output.line_map.iter().map(|l| {
+                l.as_ref().map(|l| SourceLocation {
+                    file: l.file.clone(),
+                    line: if l.file.is_none() { b.start + l.line } else { l.line },
+                })
+            }));
+            transpiled.diagnostics.extend(b.output.diagnostics.iter().map(|d| Diagnostic {
+                line: if d.file.is_none() { b.start + d.line } else { d.line },
+                ..d.clone()
+            }));
+            transpiled.includes.extend(b.output.includes.iter().cloned());
+            transpiled.references.extend(b.output.references.iter().cloned());
+        }
+
+        transpiled
+    }
+
+    /// Adjusted indentation of all lines, the same as `scan_indents`
+    pub fn indents(&self) -> Vec<usize> {
+        let mut indents = self.front_matter_indents.clone();
+        for b in &self.blocks {
+            indents.extend(&b.indents);
+        }
+        indents.push(0);
+        indents
+    }
+
+    /// Environments opened by hashlines, the same as `scan_environments`
+    pub fn environments(&self) -> Vec<EnvironmentSpan> {
+        let mut environments = self.front_matter_environments.clone();
+        for b in &self.blocks {
+            environments.extend(b.environments.iter().map(|e| EnvironmentSpan {
+                start: b.start + e.start,
+                end: b.start + e.end,
+                ..e.clone()
+            }));
+        }
+        environments
+    }
+
+    // Transpile all blocks again
+    fn rebuild(&mut self) {
+        use variables::extract_front_matter;
+
+        self.variables = self.options.default_variables.clone();
+        self.front_matter_diagnostics.clear();
+        let body = extract_front_matter(SourceLine::number(&self.lines),
+                                        &mut self.variables,
+                                        &mut self.front_matter_diagnostics);
+        self.variables.extend(self.options.variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        self.front_matter = self.lines.len() - body.len();
+        let (indents, environments) = scan(&self.lines[..self.front_matter]);
+        self.front_matter_indents = indents;
+        self.front_matter_environments = environments;
+
+        self.blocks = self.split_blocks(self.front_matter, self.lines.len());
+        let macros = self.options.macros.clone();
+        self.update(0, macros);
+    }
+
+    // Split the lines `start..end` at lines which close all environments
+    fn split_blocks(&self, start: usize, end: usize) -> Vec<Block> {
+        use conditionals::continues_chain;
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, line) in self.lines[start..end].iter().enumerate() {
+            let starts_block = !line.is_empty() && !line.starts_with(char::is_whitespace) && !continues_chain(line);
+            match blocks.last_mut() {
+                Some(b) if !starts_block => b.len += 1,
+                _ => blocks.push(Block::new(start + i, 1)),
+            }
+        }
+        blocks
+    }
+
+    // Transpile the dirty blocks from `i` on, starting with the macros defined before block `i`
+    fn update(&mut self, mut i: usize, mut macros: MacroTable) {
+        while i < self.blocks.len() {
+            if !self.blocks[i].dirty && self.blocks[i].macros == macros {
+                break;
+            }
+
+            let mut defined = macros.clone();
+            self.transpile_block(i, &mut defined);
+            // Preprocessing may yield indented lines at the start, which belong to an environment before
+            if i > 0 && self.blocks[i].continues {
+                let merged = self.blocks.remove(i);
+                i -= 1;
+                self.blocks[i].len += merged.len;
+                self.blocks[i].dirty = true;
+                macros = self.blocks[i].macros.clone();
+                continue;
+            }
+            if self.blocks[i].open && i + 1 < self.blocks.len() {
+                let merged = self.blocks.remove(i + 1);
+                self.blocks[i].len += merged.len;
+                self.blocks[i].dirty = true;
+                continue;
+            }
+
+            self.blocks[i].macros = macros;
+            macros = defined;
+            i += 1;
+        }
+    }
+
+    fn transpile_block(&mut self, i: usize, macros: &mut MacroTable) {
+        use transpile::{emit_mapped, preprocess};
+
+        let (start, len) = (self.blocks[i].start, self.blocks[i].len);
+        let lines = &self.lines[start..start + len];
+
+        // Lines are numbered relative to the start of the block
+        let mut output = empty_output();
+        let preprocessed = preprocess(SourceLine::number(lines),
+                                      self.path.as_deref(),
+                                      &self.variables,
+                                      macros,
+                                      &self.options,
+                                      &mut output);
+        let (text, line_map) = emit_mapped(&preprocessed, &self.options, &mut output.diagnostics);
+        output.text = text;
+        output.line_map = line_map;
+        let (indents, environments) = scan(lines);
+
+        let block = &mut self.blocks[i];
+        block.continues = preprocessed.iter()
+            .find(|l| !l.text.is_empty())
+            .is_some_and(|l| l.text.starts_with(char::is_whitespace));
+        // The lines as written are scanned for environments as well
+        block.open = leaves_open(&preprocessed) || leaves_open(lines);
+        block.indents = indents;
+        block.environments = environments;
+        block.output = output;
+        block.dirty = false;
+    }
+}
+
+fn empty_output() -> Transpiled {
+    Transpiled {
+        text: String::new(),
+        diagnostics: Vec::new(),
+        includes: Vec::new(),
+        references: Vec::new(),
+        line_map: Vec::new(),
+    }
+}
+
+// Whether the lines open more environments by `# begin` than they close by `# end`
+fn leaves_open<T: AsRef<str>>(lines: &[T]) -> bool {
+    use parsers::{parse_closer, process_line};
+    use parsers::Hashline::OpenEnv;
+
+    let mut open = 0usize;
+    for line in lines {
+        if parse_closer(line).is_some() {
+            open = open.saturating_sub(1);
+        } else if let OpenEnv(e) = process_line(line, false) {
+            if e.is_closed_explicitly() {
+                open += 1;
+            }
+        }
+    }
+    open > 0
+}
+
+// Indentation without the final zero and environments of a block
+fn scan(lines: &[String]) -> (Vec<usize>, Vec<EnvironmentSpan>) {
+    use transpile::{scan_body_environments, scan_indents};
+
+    let mut indents = scan_indents(lines);
+    indents.pop();
+    (indents, scan_body_environments(lines))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use transpile::TranspileOptions;
+
+    const LINES: [&str; 33] = ["---", "v: 1", "# itemize:", "  * a {{v}}", "  # enumerate:", "    * b", "", "c", "  d",
+                               "    e", "# if draft:", "# if not draft:", "# elif final:", "# else:", "# define foo:",
+                               "  # emph: #1", "# foo: x", "# define foo: section", "# define foo: bar", "# align:",
+                               "  a & b", "  # cases:", r"\hline", "# center:", "\tf", "# x: {{w}}", "% comment",
+                               "# include: missing.inden.tex", r"\input{missing}", "  ", "# begin quote", "  # begin quote",
+                               "# end quote"];
+
+    fn options() -> TranspileOptions {
+        use std::collections::HashMap;
+
+        let mut defines = HashMap::new();
+        defines.insert("draft".to_string(), true);
+        TranspileOptions {
+            prepend_do_not_edit_notice: true,
+            defines,
+            ..Default::default()
+        }
+    }
+
+    fn lines(indices: &[usize]) -> Vec<&'static str> {
+        indices.iter().map(|&i| LINES[i]).collect()
+    }
+
+    // Compare with a full run, diagnostics are only compared as a set
+    fn assert_equivalent(document: &super::Document) {
+        use transpile::{scan_environments, scan_indents, transpile};
+
+        let expected = transpile(document.lines(), None, &options());
+        let actual = document.transpiled();
+        assert_eq!(actual.text, expected.text);
+        assert_eq!(actual.line_map, expected.line_map);
+        assert_eq!(actual.includes, expected.includes);
+        assert_eq!(actual.references, expected.references);
+
+        let sorted = |mut d: Vec<::diagnostics::Diagnostic>| {
+            d.sort_by(|a, b| (a.line, &a.message).cmp(&(b.line, &b.message)));
+            d
+        };
+        assert_eq!(sorted(actual.diagnostics), sorted(expected.diagnostics));
+
+        assert_eq!(document.indents(), scan_indents(document.lines()));
+        assert_eq!(document.environments(), scan_environments(document.lines()));
+    }
+
+    #[test]
+    fn edit() {
+        use super::Document;
+
+        let mut document = Document::new(&["# itemize:", "  * a", "b", "# foo: c"], None, &options());
+        assert_eq!(document.blocks.len(), 3);
+        assert_equivalent(&document);
+
+        // Editing the last block leaves the others alone
+        document.blocks[0].output.text.clear();
+        document.edit(3, 4, &["# foo: d"]);
+        assert!(document.blocks[0].output.text.is_empty());
+        document.edit(0, 0, &["# itemize:"]);
+        assert_equivalent(&document);
+
+        // A new macro definition transpiles the following blocks again
+        document.edit(3, 3, &["# define foo: section"]);
+        assert!(document.transpiled().text.contains(r"\section{d}"));
+        assert_equivalent(&document);
+
+        // The body of a conditional continues the environment before it
+        document.edit(3, 3, &["# if draft:", "    * e", "  * f"]);
+        assert!(document.transpiled().text.contains("  \\item a\n  \\item e\n\\end{itemize}\n* f\n"));
+        assert_equivalent(&document);
+        document.edit(4, 5, &["    * g"]);
+        assert_equivalent(&document);
+
+        // An environment closed by `# end` continues into the following blocks
+        let mut document = Document::new(&["# begin document", "a", "# center:", "  b", "# end document"], None, &options());
+        assert_eq!(document.blocks.len(), 1);
+        assert_equivalent(&document);
+        document.edit(4, 5, &["c"]);
+        assert_equivalent(&document);
+        document.edit(4, 4, &["# end", "d"]);
+        assert_eq!(document.blocks.len(), 3);
+        assert_equivalent(&document);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filters() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use super::Document;
+        use test_utils::TempDir;
+        use transpile::{transpile, TranspileOptions};
+
+        // The filter counts its runs and turns lists into numbered ones
+        let dir = TempDir::new("document-filters");
+        let filter = dir.join("filter");
+        fs::write(&filter, "#!/bin/sh\necho run >> \"$0.runs\"\nsed 's/\"itemize\"/\"enumerate\"/g'\n").unwrap();
+        fs::set_permissions(&filter, fs::Permissions::from_mode(0o755)).unwrap();
+        let runs = || fs::read_to_string(dir.join("filter.runs")).map_or(0, |r| r.lines().count());
+        let options = TranspileOptions { filters: vec![filter.to_str().unwrap().to_string()], ..options() };
+
+        // Blocks are transpiled without the filters, which run once on the whole document
+        let mut document = Document::new(&["# itemize:", "  * a", "b", "# itemize:", "  * c"], None, &options);
+        document.edit(2, 3, &["d"]);
+        assert_eq!(runs(), 0);
+        let transpiled = document.transpiled();
+        assert_eq!(runs(), 1);
+        assert_eq!(transpiled.text.matches(r"\begin{enumerate}").count(), 2);
+        assert_eq!(transpiled.text, transpile(document.lines(), None, &options).text);
+    }
+
+    proptest! {
+        #[test]
+        fn edits_are_equivalent_to_full_runs(initial in vec(0..LINES.len(), 0..30),
+                                              edits in vec((any::<Index>(), 0..4usize, vec(0..LINES.len(), 0..5)), 1..8)) {
+            use super::Document;
+
+            let mut document = Document::new(&lines(&initial), None, &options());
+            assert_equivalent(&document);
+            for (start, removed, inserted) in edits {
+                let start = start.index(document.lines().len() + 1);
+                let end = (start + removed).min(document.lines().len());
+                document.edit(start, end, &lines(&inserted));
+                assert_equivalent(&document);
+            }
+        }
+    }
+}
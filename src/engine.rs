@@ -0,0 +1,310 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use diagnostics::Severity;
+use error::IndentexError;
+use source_map::SourceMap;
+
+
+pub const ENGINES: [&str; 4] = ["latexmk", "pdflatex", "xelatex", "lualatex"];
+// Stop at the first error instead of waiting for input and report errors as `file:line: message`
+const ENGINE_ARGS: [&str; 2] = ["-interaction=nonstopmode", "-file-line-error"];
+const LOG_EXTENSION: &str = "log";
+// The `l.<n>` marker follows an error within a few lines
+const MAX_CONTEXT_LINES: usize = 10;
+
+
+// A message from the LaTeX log, located in the transpiled files or, after mapping, in the sources
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogMessage {
+    pub severity: Severity,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file.display(), line)?,
+            (Some(file), None) => write!(f, "{}: ", file.display())?,
+            _ => {}
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Run the LaTeX engine on a transpiled file in its directory and return the contents of the log
+///
+/// `command` replaces the executable of the engine, e.g. to use a specific installation.
+pub fn run_engine<T: AsRef<Path>>(engine: &str, command: Option<&str>, output: T, verbose: bool)
+                                  -> Result<(bool, String), IndentexError> {
+    use std::fs::File;
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    let output = output.as_ref();
+    let dir = output.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    // `unwrap()` is safe here since outputs are always named `*_indentex.tex`
+    let file_name = output.file_name().unwrap();
+
+    let mut cmd = Command::new(command.unwrap_or(engine));
+    if engine == "latexmk" {
+        cmd.arg("-pdf");
+    }
+    let status = cmd.args(ENGINE_ARGS)
+        .arg(file_name)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(if verbose { Stdio::inherit() } else { Stdio::null() })
+        .status()?;
+
+    // The log is written in the encoding of the input, which is not necessarily UTF-8
+    let mut log = Vec::new();
+    File::open(output.with_extension(LOG_EXTENSION))?.read_to_end(&mut log)?;
+
+    Ok((status.success(), String::from_utf8_lossy(&log).into_owned()))
+}
+
+/// Extract errors and warnings from a LaTeX log, file names are relative to the directory of the log
+pub fn parse_log(log: &str) -> Vec<LogMessage> {
+    let lines: Vec<&str> = log.lines().collect();
+    let mut messages = Vec::new();
+    // Files opened by TeX, which are shown as `(./file.tex ... )` in the log
+    let mut files: Vec<String> = Vec::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+
+        if let Some((file, line_num, message)) = split_file_line_error(line) {
+            messages.push(LogMessage {
+                severity: Severity::Error,
+                file: Some(PathBuf::from(file)),
+                line: Some(line_num),
+                message: message.to_string(),
+            });
+            i += 1;
+            continue;
+        }
+
+        if let Some(message) = line.strip_prefix("! ") {
+            let line_num = lines[i + 1..].iter()
+                .take(MAX_CONTEXT_LINES)
+                .filter_map(|l| l.strip_prefix("l."))
+                .filter_map(|l| l.split_whitespace().next().and_then(|n| n.parse().ok()))
+                .next();
+            messages.push(LogMessage {
+                severity: Severity::Error,
+                file: files.last().map(PathBuf::from),
+                line: line_num,
+                message: message.to_string(),
+            });
+            i += 1;
+            continue;
+        }
+
+        let is_warning = line.contains("Warning: ");
+        if is_warning || line.starts_with("Overfull ") || line.starts_with("Underfull ") {
+            // Warnings continue until the next blank line, bad boxes are followed by the box contents
+            let mut end = i + 1;
+            while is_warning && end < lines.len() && !lines[end].trim().is_empty() {
+                end += 1;
+            }
+            let text = lines[i..end].iter()
+                .map(|l| strip_package_prefix(l.trim()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let message = match text.find("Warning: ") {
+                Some(p) => text[p + "Warning: ".len()..].to_string(),
+                None => text.clone(),
+            };
+            messages.push(LogMessage {
+                severity: Severity::Warning,
+                file: files.last().map(PathBuf::from),
+                line: warning_line(&text),
+                message,
+            });
+            for l in &lines[i..end] {
+                track_files(l, &mut files);
+            }
+            i = end;
+            continue;
+        }
+
+        track_files(line, &mut files);
+        i += 1;
+    }
+
+    messages
+}
+
+/// Point messages in transpiled files to the indentex sources
+pub fn map_messages(messages: Vec<LogMessage>, dir: &Path, source_map: &SourceMap) -> Vec<LogMessage> {
+    messages.into_iter()
+        .map(|m| {
+            let located = match (&m.file, m.line) {
+                (Some(f), Some(l)) => source_map.locate(dir.join(f), l),
+                _ => None,
+            };
+            match located {
+                Some((file, line)) => LogMessage { file: Some(file), line: Some(line), ..m },
+                None => m,
+            }
+        })
+        .collect()
+}
+
+// Split `./file.tex:12: message` at the first `:<digits>:`, since paths may contain colons, e.g. `C:\doc\a.tex`
+fn split_file_line_error(line: &str) -> Option<(&str, usize, &str)> {
+    let (i, digits) = line.match_indices(':')
+        .map(|(i, _)| (i, line[i + 1..].chars().take_while(char::is_ascii_digit).count()))
+        .find(|&(i, n)| n > 0 && line[i + 1 + n..].starts_with(':'))?;
+    let file = &line[..i];
+    let line_num = line[i + 1..i + 1 + digits].parse().ok()?;
+    let message = line[i + 2 + digits..].trim();
+    if file.is_empty() || file.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((file, line_num, message))
+}
+
+// Continuation lines of package warnings start with `(package)`
+fn strip_package_prefix(line: &str) -> &str {
+    match line.find(')') {
+        Some(i) if line.starts_with('(') && !line[1..i].contains(char::is_whitespace) => line[i + 1..].trim_start(),
+        _ => line,
+    }
+}
+
+// Find `on input line <n>` or `at lines <n>--<m>`
+fn warning_line(text: &str) -> Option<usize> {
+    ["on input line ", "at lines ", "at line "].iter()
+        .filter_map(|p| text.find(p).map(|i| &text[i + p.len()..]))
+        .filter_map(|rest| {
+            let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse().ok()
+        })
+        .next()
+}
+
+// Follow the files opened and closed by TeX
+fn track_files(line: &str, files: &mut Vec<String>) {
+    for (i, c) in line.char_indices() {
+        match c {
+            '(' => {
+                let name = line[i + 1..].split(|c: char| c.is_whitespace() || c == '(' || c == ')').next();
+                files.push(name.unwrap_or("").to_string());
+            }
+            ')' => {
+                files.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use diagnostics::Severity;
+
+    const LOG: &str = "\
+This is pdfTeX, Version 3.14159265-2.6-1.40.21 (preloaded format=pdflatex)
+(./paper_indentex.tex
+LaTeX2e <2020-02-02> patch level 2
+(/usr/share/texlive/texmf-dist/tex/latex/base/article.cls
+Document Class: article 2019/12/20 v1.4l Standard LaTeX document class
+(/usr/share/texlive/texmf-dist/tex/latex/base/size10.clo))
+./paper_indentex.tex:9: Undefined control sequence.
+l.9 \\foo
+
+! Missing $ inserted.
+<inserted text>
+                $
+l.12 a_
+       b
+
+LaTeX Warning: Reference `fig:a' on page 1 undefined on input line 14.
+
+
+Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+(hyperref)                removing `math shift' on input line 15.
+
+Overfull \\hbox (15.0pt too wide) in paragraph at lines 16--18
+[]\\OT1/cmr/m/n/10 a|
+ []
+
+LaTeX Warning: There were undefined references.
+
+ )
+";
+
+    #[test]
+    fn parse_log() {
+        use super::{parse_log, LogMessage};
+
+        let file = Some(PathBuf::from("./paper_indentex.tex"));
+        let message = |severity, line, message: &str| LogMessage {
+            severity,
+            file: file.clone(),
+            line,
+            message: message.to_string(),
+        };
+        assert_eq!(parse_log(LOG),
+                   [message(Severity::Error, Some(9), "Undefined control sequence."),
+                    message(Severity::Error, Some(12), "Missing $ inserted."),
+                    message(Severity::Warning, Some(14), "Reference `fig:a' on page 1 undefined on input line 14."),
+                    message(Severity::Warning,
+                            Some(15),
+                            "Token not allowed in a PDF string (Unicode): removing `math shift' on input line 15."),
+                    message(Severity::Warning, Some(16), "Overfull \\hbox (15.0pt too wide) in paragraph at lines 16--18"),
+                    message(Severity::Warning, None, "There were undefined references.")]);
+    }
+
+    #[test]
+    fn split_file_line_error() {
+        use super::split_file_line_error;
+
+        assert_eq!(split_file_line_error("./a.tex:3: Undefined control sequence."),
+                   Some(("./a.tex", 3, "Undefined control sequence.")));
+        assert_eq!(split_file_line_error(r"C:\doc\a.tex:12: Missing $ inserted."),
+                   Some((r"C:\doc\a.tex", 12, "Missing $ inserted.")));
+        assert_eq!(split_file_line_error("./a.tex:3:4: x"), Some(("./a.tex", 3, "4: x")));
+        assert_eq!(split_file_line_error("LaTeX Font Info: 12: x"), None);
+        assert_eq!(split_file_line_error("Document Class: article"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_engine() {
+        use super::{map_messages, parse_log, run_engine};
+        use source_map::SourceMap;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use test_utils::TempDir;
+        use transpile::transpile_file;
+
+        let dir = TempDir::new("engine");
+        fs::write(dir.join("paper.inden.tex"), "# itemize:\n  * \\foo\n").unwrap();
+        // The fake engine writes a log with an error in the second line of the output
+        let engine = dir.join("fake-engine");
+        fs::write(&engine, "#!/bin/sh\nfor f; do :; done\n\
+                            printf '(./%s\\n./%s:2: Undefined control sequence.\\n)\\n' \"$f\" \"$f\" \
+                            > \"${f%.tex}.log\"\nexit 1\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let options = ::transpile::TranspileOptions::default();
+        let transpiled = transpile_file(dir.join("paper.inden.tex"), &options).unwrap();
+        let mut source_map = SourceMap::new();
+        source_map.add(dir.join("paper.inden.tex"), &transpiled).unwrap();
+
+        let (success, log) = run_engine("pdflatex", engine.to_str(), dir.join("paper_indentex.tex"), false).unwrap();
+        assert!(!success);
+        let messages = map_messages(parse_log(&log), &dir, &source_map);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].file, Some(dir.join("paper.inden.tex")));
+        assert_eq!(messages[0].line, Some(2));
+    }
+}
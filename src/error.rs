@@ -1,14 +1,22 @@
+use diagnostics::Diagnostic;
+#[cfg(feature = "cli")]
 use ignore;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum IndentexError {
     Io(io::Error),
+    Config(String),
     InvalidExtension,
+    OutsideRoot(PathBuf),
+    #[cfg(feature = "cli")]
     WalkError(ignore::Error),
+    Transpilation(Vec<Diagnostic>),
 }
 
+#[cfg(feature = "cli")]
 impl From<ignore::Error> for IndentexError {
     fn from(e: ignore::Error) -> IndentexError {
         IndentexError::WalkError(e)
@@ -23,11 +31,19 @@ impl From<io::Error> for IndentexError {
 
 impl fmt::Display for IndentexError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use std::error::Error;
         match *self {
-            IndentexError::Io(ref e) => write!(f, "{}", e.description()),
+            IndentexError::Io(ref e) => write!(f, "{}", e),
+            IndentexError::Config(ref e) => write!(f, "invalid config file: {}", e),
             IndentexError::InvalidExtension => write!(f, "not a valid indentex file"),
-            IndentexError::WalkError(ref e) => write!(f, "{}", e.description()),
+            IndentexError::OutsideRoot(ref p) => {
+                write!(f, "refusing to use '{}' which is outside of the project root", p.display())
+            }
+            #[cfg(feature = "cli")]
+            IndentexError::WalkError(ref e) => write!(f, "{}", e),
+            IndentexError::Transpilation(ref d) => {
+                let num_errors = d.iter().filter(|d| d.is_error()).count();
+                write!(f, "{} error(s) occurred", num_errors)
+            }
         }
     }
 }
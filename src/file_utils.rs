@@ -3,17 +3,35 @@ use std::vec::Vec;
 use error::IndentexError;
 
 
-const INDENTEX_GLOB: &'static str = "*.inden.tex";
+const INDENTEX_GLOB: &str = "*.inden.tex";
+#[cfg(feature = "cli")]
+const LATEX_GLOBS: [&str; 3] = ["*.tex", "*.sty", "*.cls"];
+pub const INDENTEX_EXTENSION: &str = ".inden.tex";
+pub const OUTPUT_SUFFIX: &str = "_indentex.tex";
 
 
+#[cfg(feature = "cli")]
 pub fn walk_indentex_files<T: AsRef<Path>>(rootdir: T) -> Result<Vec<PathBuf>, IndentexError> {
+    walk_files(rootdir, &[INDENTEX_GLOB])
+}
+
+/// Find all LaTeX sources, packages and classes including the indentex files
+#[cfg(feature = "cli")]
+pub fn walk_latex_files<T: AsRef<Path>>(rootdir: T) -> Result<Vec<PathBuf>, IndentexError> {
+    walk_files(rootdir, &LATEX_GLOBS)
+}
+
+#[cfg(feature = "cli")]
+fn walk_files<T: AsRef<Path>>(rootdir: T, globs: &[&str]) -> Result<Vec<PathBuf>, IndentexError> {
     use ignore::WalkBuilder;
     use ignore::types::TypesBuilder;
 
     // Create a type matcher
     let mut tb = TypesBuilder::new();
-    tb.add("indentex", INDENTEX_GLOB).unwrap();
-    tb.select("indentex");
+    for glob in globs {
+        tb.add("selected", glob).unwrap();
+    }
+    tb.select("selected");
     let matcher = tb.build().unwrap();
 
     // Walk the path
@@ -51,7 +69,7 @@ pub fn rename_indentex_file<T: AsRef<Path>>(old_path: T) -> Result<PathBuf, Inde
     new_pathbuf.set_extension("");
     // Get the full filename (i.e. with all dots etc. if there are any)
     let mut new_name = new_pathbuf.file_name().unwrap().to_os_string();
-    new_name.push("_indentex.tex");
+    new_name.push(OUTPUT_SUFFIX);
     new_pathbuf.pop();
     new_pathbuf.push(new_name);
 
@@ -60,17 +78,73 @@ pub fn rename_indentex_file<T: AsRef<Path>>(old_path: T) -> Result<PathBuf, Inde
 
 /// Read a file line by line, right-trim lines and _copy_ them into a vec of strings
 pub fn read_and_trim_lines<T: AsRef<Path>>(path: T) -> Result<Vec<String>, IndentexError> {
-    use std::fs::File;
-    use std::io::{BufReader, BufRead};
-
     if !is_indentex_file(path.as_ref()) {
         return Err(IndentexError::InvalidExtension);
     }
 
+    read_and_trim_lines_unchecked(path)
+}
+
+/// Same as `read_and_trim_lines`, but accept files with any extension
+pub fn read_and_trim_lines_unchecked<T: AsRef<Path>>(path: T) -> Result<Vec<String>, IndentexError> {
+    use std::fs::File;
+    use std::io::{BufReader, BufRead};
+
     let file = File::open(path.as_ref())?;
     let buf = BufReader::new(file);
 
-    buf.lines().map(|r| Ok(r?.trim_right().to_string())).collect()
+    buf.lines().map(|r| Ok(r?.trim_end().to_string())).collect()
+}
+
+/// Access to the files included and referenced by a source
+///
+/// `NoFileSystem` replaces `FileSystem` where there is none, e.g. in WebAssembly.
+pub trait FileResolver: Send + Sync {
+    /// Canonical path of an existing file, which identifies it in include cycles
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IndentexError>;
+    /// Right-trimmed lines of a file
+    fn read_lines(&self, path: &Path) -> Result<Vec<String>, IndentexError>;
+    /// Whether a file exists, `None` if this cannot be known
+    fn is_file(&self, path: &Path) -> Option<bool>;
+}
+
+pub struct FileSystem;
+
+impl FileResolver for FileSystem {
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IndentexError> {
+        Ok(path.canonicalize()?)
+    }
+
+    fn read_lines(&self, path: &Path) -> Result<Vec<String>, IndentexError> {
+        read_and_trim_lines_unchecked(path)
+    }
+
+    fn is_file(&self, path: &Path) -> Option<bool> {
+        Some(path.is_file())
+    }
+}
+
+/// Files cannot be read and references are not checked
+pub struct NoFileSystem;
+
+impl FileResolver for NoFileSystem {
+    fn canonicalize(&self, _: &Path) -> Result<PathBuf, IndentexError> {
+        Err(no_file_system())
+    }
+
+    fn read_lines(&self, _: &Path) -> Result<Vec<String>, IndentexError> {
+        Err(no_file_system())
+    }
+
+    fn is_file(&self, _: &Path) -> Option<bool> {
+        None
+    }
+}
+
+fn no_file_system() -> IndentexError {
+    use std::io;
+
+    IndentexError::Io(io::Error::other("no file system available"))
 }
 
 pub fn write_to_file<T, U>(path: T, data: U) -> Result<(), IndentexError>
@@ -0,0 +1,112 @@
+use ast::SyntaxTree;
+
+
+/// Pass the syntax tree through external commands in turn
+///
+/// Each filter reads the tree as JSON on stdin and writes the possibly modified tree to stdout.
+pub fn run_filters(tree: SyntaxTree, filters: &[String]) -> Result<SyntaxTree, String> {
+    filters.iter().try_fold(tree, |t, f| run_filter(&t, f))
+}
+
+fn run_filter(tree: &SyntaxTree, filter: &str) -> Result<SyntaxTree, String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    let mut child = Command::new(filter)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run filter '{}': {}", filter, e))?;
+
+    // `unwrap()` is safe here since the tree only contains strings and integers
+    let input = ::serde_json::to_vec(tree).unwrap();
+    // Write from another thread, a filter may start writing before it has read the whole tree
+    // `unwrap()` is safe here since stdin has been piped
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().map_err(|e| format!("filter '{}' failed: {}", filter, e))?;
+    // A filter which exits without reading its input is only an error if it also fails
+    let _ = writer.join();
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.trim();
+        return Err(format!("filter '{}' failed with {}{}{}",
+                           filter,
+                           output.status,
+                           if reason.is_empty() { "" } else { ": " },
+                           reason));
+    }
+    ::serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("filter '{}' returned an invalid syntax tree: {}", filter, e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::path::Path;
+
+    // Write an executable shell script into `dir` and return the filter list running it
+    #[cfg(unix)]
+    fn script(dir: &Path, name: &str, body: &str) -> [String; 1] {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        [path.to_str().unwrap().to_string()]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_filters() {
+        use ast::parse;
+        use super::run_filters;
+        use test_utils::TempDir;
+
+        let dir = TempDir::new("filters");
+        let tree = parse(&["# section: Foo", "text"]);
+        let cat = script(&dir, "cat", "cat");
+        let rename = script(&dir, "rename", "sed 's/\"section\"/\"chapter\"/'");
+        assert_eq!(run_filters(tree.clone(), &[]), Ok(tree.clone()));
+        assert_eq!(run_filters(tree.clone(), &cat), Ok(tree.clone()));
+        assert_eq!(run_filters(tree.clone(), &[cat[0].clone(), rename[0].clone()]).unwrap().to_text(),
+                   "command chapter 1:1-1:15\ntext 2:1-2:5\n");
+
+        let fail = script(&dir, "fail", "echo oops >&2; exit 3");
+        let err = run_filters(tree.clone(), &fail).unwrap_err();
+        assert!(err.starts_with(&format!("filter '{}' failed with exit status: 3", fail[0])), "{}", err);
+        assert!(err.ends_with(": oops"));
+
+        let garbage = script(&dir, "garbage", "cat >/dev/null; echo '{'");
+        let err = run_filters(tree.clone(), &garbage).unwrap_err();
+        assert!(err.starts_with(&format!("filter '{}' returned an invalid syntax tree", garbage[0])));
+
+        assert!(run_filters(tree, &["/nonexistent/filter".to_string()]).unwrap_err()
+            .starts_with("could not run filter '/nonexistent/filter'"));
+    }
+
+    #[test]
+    fn deserialize() {
+        use ast::{Node, SyntaxTree};
+
+        // Nodes added by a filter need neither spans nor empty fields
+        let tree: SyntaxTree = ::serde_json::from_value(json!({
+            "kind": "document",
+            "children": [
+                {"kind": "environment", "name": "center", "children": [{"kind": "text", "text": "  a"}]},
+                {"kind": "command", "name": "section", "args": "b"},
+            ],
+        })).unwrap();
+        assert_eq!(tree.children.len(), 2);
+        match tree.children[1] {
+            Node::Command { ref command, .. } => assert_eq!(command.latex(), r"\section{b}"),
+            _ => panic!("expected a command"),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use ast::Node;
+use diagnostics::Diagnostic;
+use parsers::parse_hashline;
+use std::path::Path;
+use transpile::TranspileOptions;
+
+
+pub const DEFAULT_INDENT_WIDTH: usize = 2;
+
+/// Normalise indentex source, which changes the transpiled output only in the indentation of its
+/// lines and the blank lines at its end
+///
+/// Each nesting level is indented by `indent_width` spaces, which must not be zero. Trailing
+/// whitespace and blank lines at the end are removed, hashlines are written as
+/// `# name [opts]: args % comment` and items as `* text`. The front matter is only right-trimmed.
+/// Layouts whose meaning depends on more than the nesting, like lines indented with tabs or below a
+/// macro invocation, are reported instead. The source is transpiled with `path` and `options` to
+/// make sure of this.
+pub fn format_source(text: &str, indent_width: usize, path: Option<&Path>, options: &TranspileOptions)
+                     -> Result<String, Diagnostic> {
+    use ast::parse;
+    use variables::{front_matter_len, FRONT_MATTER_DELIMITER};
+
+    let mut lines: Vec<String> = text.lines().map(|l| l.trim_end().to_string()).collect();
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    let front_matter = front_matter_len(&lines);
+    let mut items = Vec::new();
+    collect_items(&parse(&lines[front_matter..]).children, front_matter, &mut items);
+
+    let mut indents = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate().skip(front_matter) {
+        let indent = line.len() - line.trim_start().len();
+        if line[..indent].contains(|c| c != ' ') {
+            return Err(refusal(i, "cannot reindent a line indented with tabs or other whitespace than spaces".to_string()));
+        }
+        indents.push(if line.is_empty() { None } else { Some(indent) });
+    }
+    if let Some((i, j)) = find_hidden_environment(&lines[front_matter..], &indents) {
+        return Err(refusal(front_matter + i,
+                           format!("cannot reindent a conditional less indented than the open hashline on line {}",
+                                   front_matter + j + 1)));
+    }
+    let mut new_indents = vec![0; indents.len()];
+    reindent(&indents, 0, indent_width, &mut new_indents);
+    // Only the first line could turn into a front matter delimiter
+    if front_matter == 0 && new_indents.first() == Some(&0) &&
+       lines[0] != FRONT_MATTER_DELIMITER && lines[0].trim_start() == FRONT_MATTER_DELIMITER {
+        return Err(refusal(0, format!("cannot reindent '{}', which would start a front matter", FRONT_MATTER_DELIMITER)));
+    }
+
+    let original = lines.clone();
+    for (i, line) in lines.iter_mut().enumerate().skip(front_matter) {
+        let content = line.trim_start();
+        if content.is_empty() {
+            continue;
+        }
+        let formatted = if items.binary_search(&i).is_ok() { format_item(content) } else { format_line(content) };
+        *line = format!("{:ind$}{}", "", formatted, ind = new_indents[i - front_matter]);
+    }
+
+    // Lines indented below a macro invocation or an include belong to the environments of the inserted
+    // lines, whose levels may differ from the ones here
+    if let Some(i) = find_changed_output(&original, &lines, path, options) {
+        return Err(refusal(i, "cannot reindent without changing the transpiled output".to_string()));
+    }
+
+    let mut formatted = lines.join("\n");
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+// Indent the lines on the lowest level of a block by `indent` and each run of deeper lines between
+// them as a block one width further. Lines before the first one on the lowest level form such a run
+// as well, which keeps them deeper than the lowest level even after a conditional has moved its body
+// to the level of its hashline.
+fn reindent(indents: &[Option<usize>], indent: usize, width: usize, new_indents: &mut [usize]) {
+    let lowest = match indents.iter().filter_map(|&i| i).min() {
+        Some(l) => l,
+        None => return,
+    };
+
+    let mut run_start = 0;
+    for (i, &current) in indents.iter().enumerate() {
+        if current == Some(lowest) {
+            reindent(&indents[run_start..i], indent + width, width, &mut new_indents[run_start..i]);
+            new_indents[i] = indent;
+            run_start = i + 1;
+        }
+    }
+    let end = indents.len();
+    reindent(&indents[run_start..], indent + width, width, &mut new_indents[run_start..end]);
+}
+
+// The line is zero-based
+fn refusal(line: usize, message: String) -> Diagnostic {
+    use diagnostics::Severity;
+
+    Diagnostic { severity: Severity::Error, file: None, line: line + 1, column: None, message }
+}
+
+// Conditionals are removed before the environments are parsed, hence a hashline which is indented
+// deeper than a conditional and still open is continued by its body. Return both lines in this case.
+fn find_hidden_environment(lines: &[String], indents: &[Option<usize>]) -> Option<(usize, usize)> {
+    use conditionals::is_conditional_line;
+
+    for i in (0..lines.len()).filter(|&i| is_conditional_line(&lines[i])) {
+        // `unwrap()` is safe here since hashlines are not blank
+        let indent = indents[i].unwrap();
+        let mut open = None;
+        let mut lowest_between = usize::MAX;
+        for j in (0..i).rev() {
+            let ind = match indents[j] {
+                Some(ind) => ind,
+                None => continue,
+            };
+            if ind <= indent {
+                // A preceding conditional on the same level moves its body to this level as well
+                if ind == indent && is_conditional_line(&lines[j]) {
+                    open = None;
+                }
+                break;
+            }
+            if ind < lowest_between {
+                if open.is_none() && !is_conditional_line(&lines[j]) &&
+                   (parse_hashline(&lines[j]).is_some() || opens_explicitly(&lines[j])) {
+                    open = Some(j);
+                }
+                lowest_between = ind;
+            }
+        }
+        if let Some(j) = open {
+            return Some((i, j));
+        }
+    }
+    None
+}
+
+// Whether the line is `# begin`, which is no hashline to `parse_hashline`
+fn opens_explicitly(line: &str) -> bool {
+    use parsers::process_line;
+    use parsers::Hashline::OpenEnv;
+
+    match process_line(line, false) {
+        OpenEnv(e) => e.is_closed_explicitly(),
+        _ => false,
+    }
+}
+
+// Transpile without indentation, notice and filters, return the first line whose output differs
+fn find_changed_output(before: &[String], after: &[String], path: Option<&Path>, options: &TranspileOptions)
+                       -> Option<usize> {
+    use transpile::transpile;
+
+    let mut options = options.clone();
+    options.flatten_output = true;
+    options.prepend_do_not_edit_notice = false;
+    options.filters.clear();
+
+    let expected = transpile(before, path, &options);
+    let actual = transpile(after, path, &options);
+    if actual.text.trim_end() == expected.text.trim_end() {
+        return None;
+    }
+    let k = expected.text.lines().zip(actual.text.lines()).take_while(|&(e, a)| e == a).count();
+    // Output of included files is reported at the first line
+    let line = expected.line_map.get(k).and_then(|l| l.as_ref()).filter(|l| l.file.is_none()).map_or(1, |l| l.line);
+    Some(line - 1)
+}
+
+// Zero-based line numbers of all items, in ascending order
+fn collect_items(nodes: &[Node], offset: usize, items: &mut Vec<usize>) {
+    for node in nodes {
+        match *node {
+            Node::Item { ref span, .. } => items.push(offset + span.start_line - 1),
+            Node::Environment { ref children, .. } => collect_items(children, offset, items),
+            _ => {}
+        }
+    }
+}
+
+// The text of an item is trimmed anyway
+fn format_item(line: &str) -> String {
+    let text = line.trim_start()[1..].trim();
+    if text.is_empty() { "*".to_string() } else { format!("* {}", text) }
+}
+
+fn format_line(line: &str) -> String {
+    let line = line.trim_end();
+    match parse_hashline(line) {
+        // The parser removes the escapes of colons and percent signs
+        Some(p) => {
+            format!("{:ind$}# {}{}{}:{}{}{}{}",
+                    "",
+                    p.name.replace(':', r"\:"),
+                    if p.opts.is_empty() { "" } else { " " },
+                    p.opts.replace(':', r"\:").replace('%', r"\%"),
+                    if p.args.is_empty() { "" } else { " " },
+                    p.args.replace('%', r"\%"),
+                    if p.comment.is_empty() { "" } else { " " },
+                    p.comment,
+                    ind = p.indent_depth)
+        }
+        None => line.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use diagnostics::Diagnostic;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use transpile::TranspileOptions;
+
+    const LINES: [&str; 26] = ["---", "v: 1", "# itemize:", "*a {{v}}", "*", "#enumerate [x] :b", "", "c", "% d",
+                               "# if draft:", "# elif final:", "# else:", "# define foo:", "# emph: #1", "# box: x",
+                               "# align:", "a & b", "# cases:", r"\hline", "#center:", r"# section: 50\% % e",
+                               r"# label: a\:b", "* # f:", "****", "# begin quote", "# end quote"];
+
+    // The output is compared without its indentation, which follows the source. Invocations of the
+    // macro open nested environments, which may continue below them.
+    fn options() -> TranspileOptions {
+        use macros::MacroTable;
+        use std::collections::HashMap;
+        use transpile::SourceLine;
+
+        let mut defines = HashMap::new();
+        defines.insert("draft".to_string(), true);
+        let mut macros = MacroTable::new();
+        macros.expand(SourceLine::number(&["# define box:", "  # center:", "    # minipage [#1]:"]), &mut Vec::new());
+        TranspileOptions {
+            flatten_output: true,
+            macros,
+            defines,
+            ..Default::default()
+        }
+    }
+
+    fn format(text: &str, width: usize) -> Result<String, Diagnostic> {
+        use super::format_source;
+
+        format_source(text, width, None, &options())
+    }
+
+    fn assert_same_output(text: &str, formatted: &str, path: Option<&::std::path::Path>) {
+        use transpile::transpile;
+
+        let before: Vec<&str> = text.lines().map(str::trim_end).collect();
+        let after: Vec<&str> = formatted.lines().collect();
+        let expected = transpile(&before, path, &options());
+        let actual = transpile(&after, path, &options());
+        assert_eq!(actual.text.trim_end(), expected.text.trim_end(), "{}", formatted);
+        assert_eq!(actual.diagnostics, expected.diagnostics, "{}", formatted);
+    }
+
+    #[test]
+    fn format_line() {
+        use super::format_line;
+
+        assert_eq!(format_line("# section:Foo  "), "# section: Foo");
+        assert_eq!(format_line("  # figure[h] :   % c"), "  # figure [h]: % c");
+        assert_eq!(format_line(r"# section   [Foo\: bar]:  Foo: bar%x"), r"# section [Foo\: bar]: Foo: bar %x");
+        assert_eq!(format_line(r"# foo\:bar [50\%]: 50\% % c"), r"# foo\:bar [50\%]: 50\% % c");
+        assert_eq!(format_line("  * item  "), "  * item");
+        assert_eq!(format_line("#no hashline"), "#no hashline");
+    }
+
+    #[test]
+    fn format_source() {
+        assert_eq!(format("# itemize:\n  * a \n\n\n", 2), Ok("# itemize:\n  * a\n".to_string()));
+        assert_eq!(format("a\n\nb", 2), Ok("a\n\nb\n".to_string()));
+        assert_eq!(format("\n \n", 2), Ok(String::new()));
+
+        // Levels are mapped to multiples of the width, items are only recognised in lists
+        let text = "# itemize:\n   *a\n       continued\n   # enumerate [x] :\n         *   b\n\n         c\n*d\n";
+        assert_eq!(format(text, 4),
+                   Ok("# itemize:\n    * a\n        continued\n    # enumerate [x]:\n        * b\n\n        c\n*d\n"
+                       .to_string()));
+        assert_same_output(text, &format(text, 4).unwrap(), None);
+
+        // Lines before a less indented one in the same block stay deeper
+        assert_eq!(format("# if draft:\n      a\n   b\n", 2), Ok("# if draft:\n    a\n  b\n".to_string()));
+        assert_eq!(format("# center:\n      # center:\n          a\n   b\n", 2),
+                   Ok("# center:\n    # center:\n      a\n  b\n".to_string()));
+
+        // The front matter is left alone
+        assert_eq!(format("---\n  title: a \n---\n   b\n", 2), Ok("---\n  title: a\n---\nb\n".to_string()));
+    }
+
+    #[test]
+    fn refusals() {
+        assert_eq!(format("# center:\n  a\n\t# b:\n", 2).unwrap_err().line, 3);
+        assert_eq!(format("  ---\n", 2).unwrap_err().line, 1);
+        // The body of the conditional would continue the environment
+        assert_eq!(format("  # center:\n# if draft:\n    a\n", 2).unwrap_err().line, 2);
+        assert_eq!(format("   # begin quote\n# if draft:\n   # end quote\n", 1).unwrap_err().line, 2);
+        // The line below the invocation belongs to the inner environment of the body
+        let text = "# define box:\n  # center:\n    # minipage [t]:\n# box:\n      a\n";
+        assert_eq!(format(text, 2).unwrap_err().line, 5);
+        let text = text.replace("      a", "  a");
+        assert_eq!(format(&text, 2), Ok(text.clone()));
+    }
+
+    #[test]
+    fn test_cases() {
+        use std::fs;
+        use super::format_source;
+
+        for entry in fs::read_dir("tests/test_cases").unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_file() {
+                let text = fs::read_to_string(&path).unwrap();
+                for width in 1..5 {
+                    let formatted = format_source(&text, width, Some(&path), &options()).unwrap();
+                    assert_same_output(&text, &formatted, Some(&path));
+                    assert_eq!(format_source(&formatted, width, Some(&path), &options()), Ok(formatted.clone()));
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn formatting_keeps_the_output(lines in vec((0..LINES.len(), 0..7usize), 0..30), width in 1..5usize) {
+            let text: String = lines.iter().map(|&(i, ind)| format!("{:ind$}{}\n", "", LINES[i], ind = ind)).collect();
+            let formatted = match format(&text, width) {
+                Ok(f) => f,
+                // Layouts which cannot be reindented are refused
+                Err(_) => return Ok(()),
+            };
+            assert_same_output(&text, &formatted, None);
+            assert_eq!(format(&formatted, width), Ok(formatted.clone()));
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use error::IndentexError;
+use transpile::{Dependency, Transpiled, TranspileOptions};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    // `# include:` of an indentex file, which is inlined
+    Include,
+    // `\input`, `\include` or `\subfile`, which is left to LaTeX
+    Reference,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+// What has been done with a reachable file
+pub enum Visited {
+    Indentex(Result<Transpiled, IndentexError>),
+    // Plain LaTeX files are only scanned for further references
+    Latex(Result<Vec<Diagnostic>, IndentexError>),
+}
+
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    // Paths as they have been found, the first one is the main file
+    pub nodes: Vec<PathBuf>,
+    pub edges: Vec<Edge>,
+    // Used to identify files referenced by different relative paths
+    canonical: Vec<PathBuf>,
+}
+
+impl DependencyGraph {
+    // Return the index of the node, which is added if it does not exist yet
+    fn add_node(&mut self, path: PathBuf) -> usize {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        match self.canonical.iter().position(|c| *c == canonical) {
+            Some(i) => i,
+            None => {
+                self.nodes.push(path);
+                self.canonical.push(canonical);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn add_edge(&mut self, edge: Edge) {
+        if !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+
+    /// Return the nodes of a cycle (the first one repeated at the end), if there is any
+    pub fn find_cycle(&self) -> Option<Vec<usize>> {
+        // Nodes on the current path and nodes without cycles below them
+        let mut path = Vec::new();
+        let mut done = vec![false; self.nodes.len()];
+
+        for start in 0..self.nodes.len() {
+            if let Some(c) = self.find_cycle_from(start, &mut path, &mut done) {
+                return Some(c);
+            }
+        }
+        None
+    }
+
+    fn find_cycle_from(&self, node: usize, path: &mut Vec<usize>, done: &mut [bool]) -> Option<Vec<usize>> {
+        if done[node] {
+            return None;
+        }
+        if let Some(i) = path.iter().position(|&n| n == node) {
+            let mut cycle = path[i..].to_vec();
+            cycle.push(node);
+            return Some(cycle);
+        }
+
+        path.push(node);
+        for e in self.edges.iter().filter(|e| e.from == node) {
+            if let Some(c) = self.find_cycle_from(e.to, path, done) {
+                return Some(c);
+            }
+        }
+        path.pop();
+        done[node] = true;
+
+        None
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            writeln!(text, "{}", node.display()).unwrap();
+            for e in self.edges.iter().filter(|e| e.from == i) {
+                let verb = match e.kind {
+                    EdgeKind::Include => "includes",
+                    EdgeKind::Reference => "references",
+                };
+                writeln!(text, "    {} {}", verb, self.nodes[e.to].display()).unwrap();
+            }
+        }
+        text
+    }
+
+    pub fn to_dot(&self) -> String {
+        let quote = |p: &PathBuf| format!("\"{}\"", p.display().to_string().replace('\\', r"\\").replace('"', "\\\""));
+
+        let mut dot = String::from("digraph indentex {\n");
+        for node in &self.nodes {
+            writeln!(dot, "    {};", quote(node)).unwrap();
+        }
+        for e in &self.edges {
+            let style = match e.kind {
+                EdgeKind::Include => " [style=dashed]",
+                EdgeKind::Reference => "",
+            };
+            writeln!(dot, "    {} -> {}{};", quote(&self.nodes[e.from]), quote(&self.nodes[e.to]), style).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Transpile the main file and all indentex files reachable from it
+///
+/// Files which are only inlined with `# include:` are not transpiled on their own. References in all files are
+/// relative to the directory of the main file.
+pub fn build_graph<T: AsRef<Path>>(main: T, options: &TranspileOptions) -> (DependencyGraph, Vec<(PathBuf, Visited)>) {
+    use file_utils::INDENTEX_EXTENSION;
+    use transpile::transpile_path;
+
+    let base_dir = main.as_ref().parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    let options = TranspileOptions { base_dir: Some(base_dir.clone()), ..options.clone() };
+    let mut graph = DependencyGraph::default();
+    let mut visited = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(graph.add_node(main.as_ref().to_path_buf()));
+    // Only referenced files are visited, inlined ones just become nodes
+    let mut queued = vec![0];
+
+    while let Some(i) = queue.pop_front() {
+        let path = graph.nodes[i].clone();
+        let (result, dependencies) = if path.to_string_lossy().ends_with(INDENTEX_EXTENSION) {
+            let result = transpile_path(&path, &options);
+            let dependencies = match result {
+                Ok(ref t) => t.includes.iter()
+                    .map(|d| (d.clone(), EdgeKind::Include))
+                    .chain(t.references.iter().map(|d| (d.clone(), EdgeKind::Reference)))
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            (Visited::Indentex(result), dependencies)
+        } else {
+            match scan_latex_file(&path, &base_dir) {
+                Ok((r, d)) => (Visited::Latex(Ok(d)), r.into_iter().map(|r| (r, EdgeKind::Reference)).collect()),
+                Err(e) => (Visited::Latex(Err(e)), Vec::new()),
+            }
+        };
+        visited.push((path, result));
+
+        for (d, kind) in dependencies {
+            let from = match d.from {
+                Some(ref f) => graph.add_node(f.as_ref().clone()),
+                None => i,
+            };
+            let to = graph.add_node(d.path);
+            graph.add_edge(Edge { from, to, kind });
+            if kind == EdgeKind::Reference && !queued.contains(&to) {
+                queued.push(to);
+                queue.push_back(to);
+            }
+        }
+    }
+
+    (graph, visited)
+}
+
+// Collect the references of a plain LaTeX file, which are relative to `base_dir`
+fn scan_latex_file(path: &Path, base_dir: &Path) -> Result<(Vec<Dependency>, Vec<Diagnostic>), IndentexError> {
+    use file_utils::{read_and_trim_lines_unchecked, FileSystem};
+    use references::rewrite_references;
+    use transpile::SourceLine;
+
+    let lines = read_and_trim_lines_unchecked(path)?;
+    let mut references = Vec::new();
+    let mut diagnostics = Vec::new();
+    rewrite_references(SourceLine::number(&lines), base_dir, &FileSystem, &mut references, &mut diagnostics);
+
+    Ok((references, diagnostics))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use test_utils::TempDir;
+    use transpile::TranspileOptions;
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("graph_{}", name));
+        fs::create_dir_all(dir.join("ch")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reachable_files() {
+        use super::{build_graph, EdgeKind, Visited};
+
+        let dir = temp_dir("reachable");
+        fs::write(dir.join("main.inden.tex"), "# include: ch/part.inden.tex\n\\input{ch/plain}\n\\input{ch/a}\n")
+            .unwrap();
+        // References are relative to the main file, also in included and referenced files
+        fs::write(dir.join("ch/part.inden.tex"), "\\include{a}\n").unwrap();
+        fs::write(dir.join("ch/a.inden.tex"), "\\input{ch/b}\n").unwrap();
+        fs::write(dir.join("ch/b.inden.tex"), "b\n").unwrap();
+        fs::write(dir.join("ch/plain.tex"), "\\input{ch/a_indentex.tex}\n").unwrap();
+        fs::write(dir.join("ch/stale.inden.tex"), "stale\n").unwrap();
+
+        let (graph, visited) = build_graph(dir.join("main.inden.tex"), &TranspileOptions::default());
+        let names: Vec<_> = graph.nodes.iter().map(|n| n.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["main.inden.tex", "part.inden.tex", "plain.tex", "a.inden.tex", "b.inden.tex"]);
+        let edges: Vec<_> = graph.edges.iter().map(|e| (e.from, e.to, e.kind)).collect();
+        assert_eq!(edges, [(0, 1, EdgeKind::Include), (0, 2, EdgeKind::Reference), (0, 3, EdgeKind::Reference),
+                           (2, 3, EdgeKind::Reference), (3, 4, EdgeKind::Reference)]);
+        assert_eq!(graph.find_cycle(), None);
+        match visited[0].1 {
+            Visited::Indentex(Ok(ref t)) => {
+                assert_eq!(t.diagnostics.len(), 1);
+                assert_eq!(t.diagnostics[0].file, Some(dir.join("ch/part.inden.tex")));
+            }
+            _ => panic!("the main file has not been transpiled"),
+        }
+
+        // The inlined part is not transpiled on its own
+        let visited: Vec<_> = visited.iter()
+            .map(|(p, v)| (p.file_name().unwrap().to_str().unwrap(), match *v {
+                Visited::Indentex(ref r) => r.is_ok(),
+                Visited::Latex(ref r) => r.is_ok(),
+            }))
+            .collect();
+        assert_eq!(visited,
+                   [("main.inden.tex", true), ("plain.tex", true), ("a.inden.tex", true), ("b.inden.tex", true)]);
+    }
+
+    #[test]
+    fn cycles() {
+        use super::build_graph;
+
+        let dir = temp_dir("cycles");
+        fs::write(dir.join("main.inden.tex"), "\\input{ch/a}\n").unwrap();
+        fs::write(dir.join("ch/a.inden.tex"), "\\input{ch/b.tex}\n").unwrap();
+        fs::write(dir.join("ch/b.tex"), "\\input{main_indentex.tex}\n").unwrap();
+
+        let (graph, _) = build_graph(dir.join("main.inden.tex"), &TranspileOptions::default());
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.find_cycle(), Some(vec![0, 1, 2, 0]));
+    }
+
+    #[test]
+    fn render() {
+        use super::{DependencyGraph, Edge, EdgeKind};
+
+        let graph = DependencyGraph {
+            nodes: vec![PathBuf::from("main.inden.tex"), PathBuf::from("a.inden.tex"), PathBuf::from("b.tex")],
+            edges: vec![Edge { from: 0, to: 1, kind: EdgeKind::Include },
+                        Edge { from: 1, to: 2, kind: EdgeKind::Reference }],
+            canonical: Vec::new(),
+        };
+        assert_eq!(graph.to_text(),
+                   "main.inden.tex\n    includes a.inden.tex\na.inden.tex\n    references b.tex\nb.tex\n");
+        assert_eq!(graph.to_dot(),
+                   "digraph indentex {\n    \"main.inden.tex\";\n    \"a.inden.tex\";\n    \"b.tex\";\n    \
+                    \"main.inden.tex\" -> \"a.inden.tex\" [style=dashed];\n    \"a.inden.tex\" -> \"b.tex\";\n}\n");
+    }
+}
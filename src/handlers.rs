@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use parsers::{Environment, HashlineParts};
+
+
+/// Project-specific hashlines, consulted before the built-in rules
+///
+/// The parts are passed as written, only with the escapes of colons and percent signs removed.
+pub trait HashlineHandler: Send + Sync {
+    fn handle(&self, hashline: &HashlineParts) -> Handled;
+}
+
+pub enum Handled {
+    /// Replace the hashline with these lines, indented like the hashline
+    Lines(Vec<String>),
+    /// Open an environment with the lines of the body nested like for built-in environments
+    Environment { begin: String, end: String },
+    /// Let the built-in rules transpile the hashline
+    Default,
+}
+
+// What the first handler which does not defer made of a hashline
+pub enum Custom {
+    Lines(Vec<String>),
+    Environment(Environment),
+}
+
+pub fn apply_handlers<T: AsRef<str>>(line: T, handlers: &[Arc<dyn HashlineHandler>]) -> Option<(String, Custom)> {
+    use parsers::parse_hashline;
+
+    if handlers.is_empty() {
+        return None;
+    }
+    let parts = parse_hashline(line)?;
+
+    for handler in handlers {
+        match handler.handle(&parts) {
+            Handled::Lines(lines) => {
+                let indented = lines.iter()
+                    .map(|l| format!("{:ind$}{}", "", l, ind = parts.indent_depth))
+                    .collect();
+                return Some((parts.name, Custom::Lines(indented)));
+            }
+            Handled::Environment { begin, end } => {
+                let name = parts.name.clone();
+                return Some((name, Custom::Environment(Environment::custom(&parts, begin, end))));
+            }
+            Handled::Default => {}
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use parsers::HashlineParts;
+    use super::{Handled, HashlineHandler};
+
+    pub struct Todo;
+
+    impl HashlineHandler for Todo {
+        fn handle(&self, hashline: &HashlineParts) -> Handled {
+            match hashline.name.as_str() {
+                "todo" if hashline.args.is_empty() => {
+                    Handled::Environment { begin: r"\begin{todobox}".to_string(), end: r"\end{todobox}".to_string() }
+                }
+                "todo" => {
+                    let colour = if hashline.opts == "[ann]" { "red" } else { "blue" };
+                    Handled::Lines(vec![format!(r"\todonote[color={}]{{{}}}", colour, hashline.args),
+                                        r"\par".to_string()])
+                }
+                _ => Handled::Default,
+            }
+        }
+    }
+
+    #[test]
+    fn apply_handlers() {
+        use std::sync::Arc;
+        use super::{apply_handlers, Custom, HashlineHandler};
+
+        let handlers: Vec<Arc<dyn HashlineHandler>> = vec![Arc::new(Todo)];
+        match apply_handlers(r"  # todo [ann]: 50\% done", &handlers) {
+            Some((ref name, Custom::Lines(ref lines))) => {
+                assert_eq!(name, "todo");
+                assert_eq!(*lines, [r"  \todonote[color=red]{50% done}", r"  \par"]);
+            }
+            _ => panic!("expected replacement lines"),
+        }
+        match apply_handlers("# todo:", &handlers) {
+            Some((_, Custom::Environment(ref e))) => {
+                assert_eq!(e.latex_begin(), r"\begin{todobox}");
+                assert_eq!(e.latex_end(), r"\end{todobox}");
+            }
+            _ => panic!("expected an environment"),
+        }
+        assert!(apply_handlers("# section: Foo", &handlers).is_none());
+        assert!(apply_handlers("todo", &handlers).is_none());
+        assert!(apply_handlers("# todo: x", &[]).is_none());
+    }
+
+    #[test]
+    fn transpile() {
+        use transpile::{transpile, TranspileOptions};
+
+        let mut options = TranspileOptions::default();
+        options.add_handler(Todo);
+        let t = transpile(&["# todo:", "  # todo: x", "  # section: y", "z"], None, &options);
+        assert_eq!(t.text,
+                   "\\begin{todobox}\n  \\todonote[color=blue]{x}\n  \\par\n  \\section{y}\n\\end{todobox}\nz\n");
+        let origins: Vec<_> = t.line_map.iter().map(|l| l.as_ref().map(|l| l.line)).collect();
+        assert_eq!(origins, [Some(1), Some(2), Some(2), Some(3), Some(3), Some(4)]);
+    }
+}
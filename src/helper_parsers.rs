@@ -6,13 +6,19 @@
 macro_rules! specific_byte (
   ($i:expr, $byte: expr) => (
     {
+      use nom::{AtEof, Slice};
+
       if $i.is_empty() {
-        nom::IResult::Incomplete::<_, _>(nom::Needed::Size(1))
+        if $i.at_eof() {
+          Err(nom::Err::Error(error_position!($i, nom::ErrorKind::Char)))
+        } else {
+          Err(nom::Err::Incomplete(nom::Needed::Size(1)))
+        }
       } else {
         if $byte == $i[0] {
-          nom::IResult::Done(&$i[1..], $i[0])
+          Ok(($i.slice(1..), $i[0]))
         } else {
-          nom::IResult::Error(error_position!(nom::ErrorKind::Char, $i))
+          Err(nom::Err::Error(error_position!($i, nom::ErrorKind::Char)))
         }
       }
     }
@@ -23,8 +29,14 @@ macro_rules! specific_byte (
 macro_rules! none_of_bytes_as_bytes (
   ($i:expr, $bytes: expr) => (
     {
+      use nom::{AtEof, Slice};
+
       if $i.is_empty() {
-        nom::IResult::Incomplete::<_, _>(nom::Needed::Size(1))
+        if $i.at_eof() {
+          Err(nom::Err::Error(error_position!($i, nom::ErrorKind::NoneOf)))
+        } else {
+          Err(nom::Err::Incomplete(nom::Needed::Size(1)))
+        }
       } else {
         let mut found = false;
 
@@ -36,9 +48,9 @@ macro_rules! none_of_bytes_as_bytes (
         }
 
         if !found {
-          nom::IResult::Done(&$i[1..], $i[0])
+          Ok(($i.slice(1..), $i[0]))
         } else {
-          nom::IResult::Error(error_position!(nom::ErrorKind::NoneOf, $i))
+          Err(nom::Err::Error(error_position!($i, nom::ErrorKind::NoneOf)))
         }
       }
     }
@@ -49,28 +61,39 @@ macro_rules! none_of_bytes_as_bytes (
 #[cfg(test)]
 mod tests {
     use nom;
-    use nom::IResult::{Done, Error};
-    use nom::ErrorKind;
+    use nom::{Err, ErrorKind, Needed};
+    use nom::types::CompleteByteSlice;
 
     #[test]
     fn none_of_bytes_as_bytes() {
         named!(f<u8>, none_of_bytes_as_bytes!("ab".as_bytes()));
+        named!(g<CompleteByteSlice, u8>, none_of_bytes_as_bytes!("ab".as_bytes()));
 
         let a = &b"abcd"[..];
-        assert_eq!(f(a), Error(error_position!(ErrorKind::NoneOf, a)));
+        assert_eq!(f(a), Err(Err::Error(error_position!(a, ErrorKind::NoneOf))));
 
         let b = &b"cde"[..];
-        assert_eq!(f(b), Done(&b"de"[..], 'c' as u8));
+        assert_eq!(f(b), Ok((&b"de"[..], b'c')));
+
+        // Only complete input is known to end
+        assert_eq!(f(&b""[..]), Err(Err::Incomplete(Needed::Size(1))));
+        let c = CompleteByteSlice(b"");
+        assert_eq!(g(c), Err(Err::Error(error_position!(c, ErrorKind::NoneOf))));
     }
 
     #[test]
     fn specific_byte() {
-        named!(f<u8>, specific_byte!('c' as u8));
+        named!(f<u8>, specific_byte!(b'c'));
+        named!(g<CompleteByteSlice, u8>, specific_byte!(b'c'));
 
         let a = &b"abcd"[..];
-        assert_eq!(f(a), Error(error_position!(ErrorKind::Char, a)));
+        assert_eq!(f(a), Err(Err::Error(error_position!(a, ErrorKind::Char))));
 
         let b = &b"cde"[..];
-        assert_eq!(f(b), Done(&b"de"[..], 'c' as u8));
+        assert_eq!(f(b), Ok((&b"de"[..], b'c')));
+
+        assert_eq!(f(&b""[..]), Err(Err::Incomplete(Needed::Size(1))));
+        let c = CompleteByteSlice(b"");
+        assert_eq!(g(c), Err(Err::Error(error_position!(c, ErrorKind::Char))));
     }
 }
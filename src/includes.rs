@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use file_utils::{FileResolver, INDENTEX_EXTENSION};
+use parsers::parse_hashline;
+use transpile::{Dependency, SourceLine};
+
+
+const INCLUDE_KEYWORD: &str = "include";
+
+
+/// Inline the lines of `# include: foo.inden.tex`, indented to the column of the hashline
+///
+/// `stack` holds the canonical paths of all files being included at the moment,
+/// starting with the file being transpiled (if known). All inlined files are added to `included`.
+pub fn expand_includes(lines: Vec<SourceLine>,
+                       base_dir: &Path,
+                       files: &dyn FileResolver,
+                       defines: &HashMap<String, bool>,
+                       stack: &mut Vec<PathBuf>,
+                       included: &mut Vec<Dependency>,
+                       diagnostics: &mut Vec<Diagnostic>)
+                       -> Vec<SourceLine> {
+    use conditionals::evaluate_conditionals;
+    use delimiters::check_delimiters;
+    use variables::extract_front_matter;
+
+    let mut expanded = Vec::with_capacity(lines.len());
+
+    for line in lines {
+        // Other targets of `# include:` are left to LaTeX's `\include`
+        let parts = match parse_hashline(&line) {
+            Some(p) if p.name == INCLUDE_KEYWORD && p.opts.is_empty() &&
+                        p.args.ends_with(INDENTEX_EXTENSION) => p,
+            _ => {
+                expanded.push(line);
+                continue;
+            }
+        };
+
+        // Paths are relative to the file containing the hashline
+        let dir = match line.file {
+            Some(ref f) => f.parent().unwrap_or(base_dir).to_path_buf(),
+            None => base_dir.to_path_buf(),
+        };
+        let path = dir.join(&parts.args);
+        let canonical = match files.canonicalize(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                let msg = format!("could not include '{}': {}", path.display(), e);
+                diagnostics.push(Diagnostic::error(&line, msg));
+                continue;
+            }
+        };
+
+        if stack.contains(&canonical) {
+            let cycle = stack.iter()
+                .skip_while(|p| **p != canonical)
+                .chain(Some(&canonical))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            diagnostics.push(Diagnostic::error(&line, format!("include cycle: {}", cycle)));
+            continue;
+        }
+
+        let lines_included = match files.read_lines(&canonical) {
+            Ok(l) => l,
+            Err(e) => {
+                let msg = format!("could not include '{}': {}", path.display(), e);
+                diagnostics.push(Diagnostic::error(&line, msg));
+                continue;
+            }
+        };
+
+        included.push(Dependency { from: line.file.clone(), path: path.clone() });
+        // Diagnostics in the included lines point to the included file
+        let file = Some(Arc::new(path));
+        let lines_included = SourceLine::number_in_file(&lines_included, file);
+        // Variables are the same in the whole document, hence only the main file defines them
+        let front_matter = lines_included.first().cloned();
+        let mut variables = HashMap::new();
+        let lines_included = extract_front_matter(lines_included, &mut variables, diagnostics);
+        if let Some(ref l) = front_matter.filter(|_| !variables.is_empty()) {
+            let msg = "variables in the front matter of an included file are ignored, define them in the main file";
+            diagnostics.push(Diagnostic::warning(l, msg));
+        }
+        check_delimiters(&lines_included, diagnostics);
+        let lines_included = evaluate_conditionals(lines_included, defines, diagnostics);
+        stack.push(canonical);
+        let lines_included = expand_includes(lines_included, base_dir, files, defines, stack, included, diagnostics);
+        stack.pop();
+
+        expanded.extend(lines_included.into_iter().map(|l| {
+            let text = if l.text.is_empty() {
+                String::new()
+            } else {
+                format!("{:ind$}{}", "", l.text, ind = parts.indent_depth)
+            };
+            l.with_text(text)
+        }));
+    }
+
+    expanded
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use file_utils::FileSystem;
+    use test_utils::TempDir;
+    use transpile::SourceLine;
+
+    fn temp_dir(name: &str) -> TempDir {
+        TempDir::new(&format!("includes_{}", name))
+    }
+
+    #[test]
+    fn indentation_and_origin() {
+        use super::expand_includes;
+
+        let dir = temp_dir("indentation");
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("parts/a.inden.tex"), "* a\n\n# include: b.inden.tex\n").unwrap();
+        fs::write(dir.join("parts/b.inden.tex"), "* b\n  c\n").unwrap();
+
+        let lines = SourceLine::number(&["# itemize:", "  # include: parts/a.inden.tex", "# include: ch1"]);
+        let mut included = Vec::new();
+        let mut diagnostics = Vec::new();
+        let expanded = expand_includes(lines,
+                                       &dir,
+                                       &FileSystem,
+                                       &HashMap::new(),
+                                       &mut Vec::new(),
+                                       &mut included,
+                                       &mut diagnostics);
+
+        let texts: Vec<_> = expanded.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["# itemize:", "  * a", "", "  * b", "    c", "# include: ch1"]);
+        assert_eq!(included.len(), 2);
+        assert!(included[0].from.is_none());
+        assert!(included[1].from.as_ref().unwrap().ends_with("parts/a.inden.tex"));
+        assert!(included[1].path.ends_with("parts/b.inden.tex"));
+        assert_eq!(expanded[4].line, 2);
+        assert_eq!(expanded[4].file.as_ref().map(|f| f.ends_with("parts/b.inden.tex")), Some(true));
+        assert!(expanded[5].file.is_none());
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn cycles_and_missing_files() {
+        use super::expand_includes;
+
+        let dir = temp_dir("cycles");
+        fs::write(dir.join("a.inden.tex"), "a\n# include: b.inden.tex\n").unwrap();
+        fs::write(dir.join("b.inden.tex"), "b\n# include: a.inden.tex\n").unwrap();
+
+        let lines = SourceLine::number(&["# include: a.inden.tex", "# include: missing.inden.tex"]);
+        let mut diagnostics = Vec::new();
+        let expanded = expand_includes(lines,
+                                       &dir,
+                                       &FileSystem,
+                                       &HashMap::new(),
+                                       &mut Vec::new(),
+                                       &mut Vec::new(),
+                                       &mut diagnostics);
+
+        let texts: Vec<_> = expanded.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["a", "b"]);
+        assert_eq!(diagnostics.len(), 2);
+        // The cycle is reported in the file that closes it
+        assert!(diagnostics[0].message.starts_with("include cycle"));
+        assert_eq!(diagnostics[0].line, 2);
+        assert!(diagnostics[0].file.as_ref().unwrap().ends_with("b.inden.tex"));
+        assert_eq!(diagnostics[1].line, 2);
+        assert!(diagnostics[1].file.is_none());
+    }
+
+    #[test]
+    fn front_matter() {
+        use super::expand_includes;
+
+        let dir = temp_dir("front_matter");
+        fs::write(dir.join("a.inden.tex"), "---\nfruit: apples\n---\n* {{fruit}}\n").unwrap();
+
+        let lines = SourceLine::number(&["# include: a.inden.tex"]);
+        let mut diagnostics = Vec::new();
+        let expanded = expand_includes(lines,
+                                       &dir,
+                                       &FileSystem,
+                                       &HashMap::new(),
+                                       &mut Vec::new(),
+                                       &mut Vec::new(),
+                                       &mut diagnostics);
+
+        // The front matter is not copied into the output
+        let texts: Vec<_> = expanded.iter().map(|l| (l.text.as_str(), l.line)).collect();
+        assert_eq!(texts, [("* {{fruit}}", 4)]);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.starts_with("variables in the front matter of an included file are ignored"));
+        assert_eq!(diagnostics[0].line, 1);
+        assert!(diagnostics[0].file.as_ref().unwrap().ends_with("a.inden.tex"));
+    }
+}
@@ -0,0 +1,61 @@
+#[cfg(feature = "cli")]
+extern crate flate2;
+extern crate globset;
+#[cfg(feature = "cli")]
+extern crate ignore;
+#[macro_use]
+extern crate nom;
+#[cfg(test)]
+extern crate proptest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "cli")]
+extern crate tar;
+extern crate toml;
+#[cfg(target_arch = "wasm32")]
+extern crate wasm_bindgen;
+
+// Import helper macros before `parsers`
+#[macro_use]
+mod helper_parsers;
+
+pub mod ast;
+pub mod bundle;
+#[cfg(feature = "cli")]
+pub mod capi;
+pub mod conditionals;
+pub mod config;
+pub mod delimiters;
+pub mod depfile;
+pub mod diagnostics;
+pub mod document;
+#[cfg(feature = "cli")]
+pub mod engine;
+pub mod error;
+pub mod file_utils;
+pub mod filters;
+pub mod format;
+pub mod graph;
+pub mod handlers;
+pub mod includes;
+pub mod lint;
+#[cfg(feature = "cli")]
+pub mod lsp;
+pub mod macros;
+pub mod names;
+#[cfg(feature = "cli")]
+pub mod pack;
+pub mod parsers;
+pub mod references;
+pub mod source_map;
+#[cfg(feature = "cli")]
+pub mod synctex;
+pub mod transpile;
+pub mod variables;
+pub mod wasm;
+
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec::Vec;
+use ast::Node;
+use diagnostics::{Diagnostic, Severity};
+use serde::{Deserialize, Deserializer};
+use parsers::Environment;
+use transpile::{SourceLine, TranspileOptions};
+
+
+pub const DISABLE_COMMENT: &str = "indentex-disable";
+const DEFAULT_MAX_DEPTH: usize = 6;
+// Characters which break `\begin{...}` and `\end{...}`
+const INVALID_ENVIRONMENT_CHARS: &str = "\\{}#~";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    // Itemlines are copied verbatim outside of list-like environments
+    ItemOutsideList,
+    EmptyEnvironment,
+    // Hashline names LaTeX cannot use as a command or environment
+    InvalidName,
+    // `\begin` and `\end` within the body of a hashline environment
+    ExplicitEnvironment,
+    // Environments nested deeper than `max_depth`
+    MaxDepth,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] =
+        [Rule::ItemOutsideList, Rule::EmptyEnvironment, Rule::InvalidName, Rule::ExplicitEnvironment, Rule::MaxDepth];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Rule::ItemOutsideList => "item-outside-list",
+            Rule::EmptyEnvironment => "empty-environment",
+            Rule::InvalidName => "invalid-name",
+            Rule::ExplicitEnvironment => "explicit-environment",
+            Rule::MaxDepth => "max-depth",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Rule> {
+        Rule::ALL.iter().cloned().find(|r| r.id() == id)
+    }
+}
+
+// Map keys in TOML cannot be deserialized as enums, hence go through the ID
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rule, D::Error> {
+        use serde::de::Error;
+
+        let id = String::deserialize(deserializer)?;
+        Rule::from_id(&id).ok_or_else(|| D::Error::custom(format!("unknown lint rule '{}'", id)))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Off,
+    Warning,
+    Error,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "off" => Some(Level::Off),
+            "warning" => Some(Level::Warning),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    fn severity(self) -> Option<Severity> {
+        match self {
+            Level::Off => None,
+            Level::Warning => Some(Severity::Warning),
+            Level::Error => Some(Severity::Error),
+        }
+    }
+}
+
+// The `[lint]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    // Rules which are not listed are warnings
+    pub rules: HashMap<Rule, Level>,
+    // Number of nested environments which is still fine
+    pub max_depth: usize,
+}
+
+impl Default for LintConfig {
+    fn default() -> LintConfig {
+        LintConfig { rules: HashMap::new(), max_depth: DEFAULT_MAX_DEPTH }
+    }
+}
+
+impl LintConfig {
+    pub fn level(&self, rule: Rule) -> Level {
+        self.rules.get(&rule).cloned().unwrap_or(Level::Warning)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: Rule,
+    pub diagnostic: Diagnostic,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.diagnostic, self.rule)
+    }
+}
+
+pub struct Linted {
+    pub findings: Vec<Finding>,
+    // Problems found by the passes before the LaTeX emission
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Lint right-trimmed lines as `transpile` would see them, `path` is used to resolve includes
+pub fn lint<T: AsRef<str>>(lines: &[T], path: Option<&Path>, options: &TranspileOptions, config: &LintConfig)
+                           -> Linted {
+    use ast::parse_with;
+    use transpile::{preprocess_source, Transpiled};
+
+    let mut transpiled = Transpiled {
+        text: String::new(),
+        diagnostics: Vec::new(),
+        includes: Vec::new(),
+        references: Vec::new(),
+        line_map: Vec::new(),
+    };
+    let preprocessed = preprocess_source(lines, path, options, &mut transpiled);
+    let suppressions = collect_suppressions(&preprocessed, &mut transpiled.diagnostics);
+
+    let mut linter = Linter { lines: &preprocessed, config, suppressions, findings: Vec::new() };
+    linter.check(&parse_with(&preprocessed, &options.handlers).children, None, 0);
+
+    Linted { findings: linter.findings, diagnostics: transpiled.diagnostics }
+}
+
+type Origin = (Option<Arc<PathBuf>>, usize);
+
+// Rules disabled for each source line
+//
+// A comment after the code disables the rules on its own line, a line with only the comment
+// on the next line with code. Without any rule IDs all rules are disabled.
+fn collect_suppressions(lines: &[SourceLine], diagnostics: &mut Vec<Diagnostic>) -> HashMap<Origin, Vec<Rule>> {
+    use transpile::strip_comment;
+
+    let mut suppressions: HashMap<Origin, Vec<Rule>> = HashMap::new();
+    let mut pending = Vec::new();
+
+    for line in lines {
+        let code = strip_comment(&line.text);
+        let comment = line.text[code.len()..].trim_start_matches('%').trim();
+        let mut disabled = Vec::new();
+        if let Some(ids) = comment.strip_prefix(DISABLE_COMMENT) {
+            if ids.is_empty() || ids.starts_with(char::is_whitespace) {
+                let ids: Vec<_> = ids.split(|c: char| c.is_whitespace() || c == ',').filter(|i| !i.is_empty()).collect();
+                for id in &ids {
+                    match Rule::from_id(id) {
+                        Some(r) => disabled.push(r),
+                        None => diagnostics.push(Diagnostic::warning(line, format!("unknown lint rule '{}'", id))),
+                    }
+                }
+                if ids.is_empty() {
+                    disabled.extend_from_slice(&Rule::ALL);
+                }
+            }
+        }
+
+        if code.trim().is_empty() {
+            pending.extend(disabled);
+        } else {
+            disabled.append(&mut pending);
+            if !disabled.is_empty() {
+                suppressions.entry((line.file.clone(), line.line)).or_default().extend(disabled);
+            }
+        }
+    }
+
+    suppressions
+}
+
+struct Linter<'a> {
+    lines: &'a [SourceLine],
+    config: &'a LintConfig,
+    suppressions: HashMap<Origin, Vec<Rule>>,
+    findings: Vec<Finding>,
+}
+
+impl<'a> Linter<'a> {
+    // `depth` is the number of enclosing environments
+    fn check(&mut self, nodes: &[Node], parent: Option<&Environment>, depth: usize) {
+        use parsers::process_line;
+        use parsers::Hashline::Item;
+        use transpile::strip_comment;
+
+        for node in nodes {
+            let line = node.span().start_line;
+            match *node {
+                Node::Environment { ref environment, ref children, .. } => {
+                    let name = environment.name();
+                    if !environment.is_custom() && !is_valid_environment_name(name) {
+                        self.report(Rule::InvalidName, line, format!("'{}' is not a valid environment name", name));
+                    }
+                    if children.iter().all(|c| matches!(*c, Node::Text { ref text, .. } if text.trim().is_empty())) {
+                        self.report(Rule::EmptyEnvironment, line, format!("environment '{}' is empty", name));
+                    }
+                    // Only report the outermost environment which is too deep
+                    if depth == self.config.max_depth {
+                        self.report(Rule::MaxDepth,
+                                    line,
+                                    format!("environment '{}' is nested deeper than {} levels", name, depth));
+                    }
+                    self.check(children, Some(environment), depth + 1);
+                }
+                Node::Command { ref command, .. } => {
+                    if !is_valid_command_name(&command.name) {
+                        self.report(Rule::InvalidName,
+                                    line,
+                                    format!("'{}' is not a valid command name", command.name));
+                    }
+                }
+                Node::Text { ref text, .. } => {
+                    if let Item(_) = process_line(text, true) {
+                        self.report(Rule::ItemOutsideList,
+                                    line,
+                                    "itemline outside of a list-like environment is copied verbatim".to_string());
+                    }
+                    let code = strip_comment(text);
+                    if let Some(e) = parent.filter(|_| code.contains(r"\begin{") || code.contains(r"\end{")) {
+                        self.report(Rule::ExplicitEnvironment,
+                                    line,
+                                    format!("explicit '\\begin' or '\\end' within environment '{}'", e.name()));
+                    }
+                }
+                Node::Item { .. } | Node::Comment { .. } | Node::Custom { .. } => {}
+            }
+        }
+    }
+
+    // `line` is the one-based line in the preprocessed lines
+    fn report(&mut self, rule: Rule, line: usize, message: String) {
+        let severity = match self.config.level(rule).severity() {
+            Some(s) => s,
+            None => return,
+        };
+        let source = &self.lines[line - 1];
+        let suppressed = self.suppressions
+            .get(&(source.file.clone(), source.line))
+            .is_some_and(|s| s.contains(&rule));
+        if !suppressed {
+            self.findings.push(Finding { rule, diagnostic: Diagnostic::new(severity, source, message) });
+        }
+    }
+}
+
+// A control sequence is either a word of letters, here optionally starred, or a single other character
+fn is_valid_command_name(name: &str) -> bool {
+    let word = name.strip_suffix('*').unwrap_or(name);
+    (!word.is_empty() && word.chars().all(|c| c.is_ascii_alphabetic())) ||
+    (name.chars().count() == 1 && !name.starts_with(|c: char| c.is_ascii_alphabetic() || c.is_whitespace()))
+}
+
+fn is_valid_environment_name(name: &str) -> bool {
+    !name.contains(|c| INVALID_ENVIRONMENT_CHARS.contains(c))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{LintConfig, Rule};
+    use transpile::TranspileOptions;
+
+    // Rule and line of each finding
+    fn lint(lines: &[&str], config: &LintConfig) -> Vec<(Rule, usize)> {
+        use super::lint;
+
+        let linted = lint(lines, None, &TranspileOptions::default(), config);
+        assert!(linted.diagnostics.is_empty());
+        linted.findings.iter().map(|f| (f.rule, f.diagnostic.line)).collect()
+    }
+
+    #[test]
+    fn names() {
+        use super::{is_valid_command_name, is_valid_environment_name};
+
+        assert!(is_valid_command_name("section"));
+        assert!(is_valid_command_name("section*"));
+        assert!(is_valid_command_name(","));
+        assert!(!is_valid_command_name("sub-section"));
+        assert!(!is_valid_command_name("x2"));
+        assert!(!is_valid_command_name(" "));
+        assert!(is_valid_environment_name("align*"));
+        assert!(is_valid_environment_name("my-env"));
+        assert!(!is_valid_environment_name(r"a\:b"));
+        assert!(!is_valid_environment_name("a}"));
+    }
+
+    #[test]
+    fn rules() {
+        use super::Rule::*;
+
+        let config = LintConfig::default();
+        assert_eq!(lint(&["# itemize:", "  * a", "  # center:", "    * b", "* c", "d"], &config),
+                   [(ItemOutsideList, 4), (ItemOutsideList, 5)]);
+        assert_eq!(lint(&["# center:", "", "# figure:", "  # center:", "  ", "  % x", "e"], &config),
+                   [(EmptyEnvironment, 1), (EmptyEnvironment, 4)]);
+        assert_eq!(lint(&["# sub-section: a", "# x}y:", "  z", "# textbf*: w"], &config),
+                   [(InvalidName, 1), (InvalidName, 2)]);
+        assert_eq!(lint(&[r"\begin{center}", "# center:", r"  \begin{tabular}{c} % \end{x}", "  a"], &config),
+                   [(ExplicitEnvironment, 3)]);
+
+        let nested = ["# a:", "  # b:", "    # c:", "      # d:", "        e"];
+        assert!(lint(&nested, &config).is_empty());
+        assert_eq!(lint(&nested, &LintConfig { max_depth: 2, ..LintConfig::default() }), [(MaxDepth, 3)]);
+    }
+
+    #[test]
+    fn levels() {
+        use super::lint as lint_lines;
+        use super::Level;
+        use diagnostics::Severity;
+
+        let mut config = LintConfig::default();
+        config.rules.insert(Rule::EmptyEnvironment, Level::Error);
+        config.rules.insert(Rule::InvalidName, Level::Off);
+        let linted = lint_lines(&["# center:", "# a-b: c"], None, &TranspileOptions::default(), &config);
+        assert_eq!(linted.findings.len(), 1);
+        assert_eq!(linted.findings[0].diagnostic.severity, Severity::Error);
+        assert_eq!(linted.findings[0].to_string(), "1: error: environment 'center' is empty [empty-environment]");
+    }
+
+    #[test]
+    fn suppressions() {
+        use super::lint as lint_lines;
+        use super::Rule::*;
+
+        let config = LintConfig::default();
+        assert_eq!(lint(&["# center: % indentex-disable empty-environment", "# figure: % indentex-disable max-depth"],
+                        &config),
+                   [(EmptyEnvironment, 2)]);
+        assert_eq!(lint(&["% indentex-disable", "", "# center:", "* a %indentex-disable", "* b"], &config),
+                   [(ItemOutsideList, 5)]);
+        assert_eq!(lint(&["% indentex-disable item-outside-list, empty-environment", "# center:", "  * a", "# f:"],
+                        &config),
+                   [(ItemOutsideList, 3), (EmptyEnvironment, 4)]);
+        // Only a comment starting with the marker counts
+        assert_eq!(lint(&["# center: % no indentex-disable"], &config), [(EmptyEnvironment, 1)]);
+
+        let linted = lint_lines(&["% indentex-disable foo", "* a"], None, &TranspileOptions::default(), &config);
+        assert_eq!(linted.diagnostics.len(), 1);
+        assert_eq!(linted.diagnostics[0].to_string(), "1: warning: unknown lint rule 'foo'");
+        assert_eq!(linted.findings.len(), 1);
+    }
+
+    #[test]
+    fn preprocessed() {
+        use super::Rule::*;
+        use macros::MacroTable;
+        use super::lint as lint_lines;
+        use transpile::SourceLine;
+
+        let mut options = TranspileOptions::default();
+        options.defines.insert("draft".to_string(), true);
+        let mut macros = MacroTable::new();
+        macros.expand(SourceLine::number(&["# define box:", "  # center:"]), &mut Vec::new());
+        options.macros = macros;
+
+        // Conditionals are evaluated, macros expanded and the front matter skipped
+        let lines = ["---", "title: x", "---", "# if draft:", "  * a", "# else:", "  * b", "# box: % indentex-disable",
+                     "# box:"];
+        let linted = lint_lines(&lines, None, &options, &LintConfig::default());
+        let findings: Vec<_> = linted.findings.iter().map(|f| (f.rule, f.diagnostic.line)).collect();
+        assert_eq!(findings, [(ItemOutsideList, 5), (EmptyEnvironment, 9)]);
+    }
+}
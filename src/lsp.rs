@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::vec::Vec;
+use serde_json::{self, Value};
+use diagnostics::Diagnostic;
+use error::IndentexError;
+use transpile::{EnvironmentSpan, SourceLine, TranspileOptions, Transpiled};
+
+
+pub const SHOW_GENERATED_LATEX: &str = "indentex.showGeneratedLatex";
+const REF_COMMANDS: [&str; 8] = ["ref", "eqref", "pageref", "autoref", "cref", "Cref", "nameref", "vref"];
+const LABEL_COMMAND: &str = "label";
+// Hashlines of the preprocessor, which are not environments
+const DIRECTIVES: [&str; 5] = ["if", "elif", "else", "define", "include"];
+const FILE_SCHEME: &str = "file://";
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+// LSP enumerations
+const FULL_SYNC: u8 = 1;
+const SEVERITY_ERROR: u8 = 1;
+const SEVERITY_WARNING: u8 = 2;
+const SYMBOL_NAMESPACE: u8 = 3;
+
+
+type RequestError = (i64, String);
+
+/// Serve the Language Server Protocol until the client sends `exit` or closes the input
+pub fn run<R: BufRead, W: Write>(input: &mut R, output: &mut W, options: &TranspileOptions) -> Result<(), IndentexError> {
+    let mut server = Server { options, documents: HashMap::new() };
+
+    while let Some(body) = read_message(input)? {
+        let message: Value = match serde_json::from_str(&body) {
+            Ok(m) => m,
+            Err(e) => {
+                write_message(output, &error_response(Value::Null, (PARSE_ERROR, e.to_string())))?;
+                continue;
+            }
+        };
+        // Responses to requests of the server are not expected
+        let method = match message["method"].as_str() {
+            Some(m) => m,
+            None => continue,
+        };
+        if method == "exit" {
+            break;
+        }
+
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, &message["params"]) {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Err(e) => error_response(id.clone(), e),
+                };
+                write_message(output, &response)?;
+            }
+            None => {
+                for notification in server.notify(method, &message["params"]) {
+                    write_message(output, &notification)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct Server<'a> {
+    options: &'a TranspileOptions,
+    // Text of the open documents by URI
+    documents: HashMap<String, String>,
+}
+
+impl<'a> Server<'a> {
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, RequestError> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": FULL_SYNC,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "foldingRangeProvider": true,
+                    "definitionProvider": true,
+                    "documentFormattingProvider": true,
+                    "executeCommandProvider": {"commands": [SHOW_GENERATED_LATEX]},
+                },
+                "serverInfo": {"name": "indentex", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/documentSymbol" => {
+                let (_, text) = self.document(params)?;
+                let lines = trimmed_lines(text);
+                Ok(Value::Array(document_symbols(&scan_environments(&lines), &lines)))
+            }
+            "textDocument/foldingRange" => {
+                let (_, text) = self.document(params)?;
+                let ranges: Vec<Value> = scan_environments(&trimmed_lines(text)).iter()
+                    .filter(|s| s.end > s.start)
+                    .map(|s| json!({"startLine": s.start, "endLine": s.end}))
+                    .collect();
+                Ok(Value::Array(ranges))
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/formatting" => {
+                use format::{format_source, DEFAULT_INDENT_WIDTH};
+
+                let (uri, text) = self.document(params)?;
+                let width = params["options"]["tabSize"].as_u64().filter(|&w| w > 0);
+                let width = width.map_or(DEFAULT_INDENT_WIDTH, |w| w as usize);
+                let formatted = format_source(text, width, uri_to_path(uri).as_deref(), self.options)
+                    .map_err(|d| (REQUEST_FAILED, d.to_string()))?;
+                if formatted == text {
+                    return Ok(json!([]));
+                }
+                Ok(json!([{"range": {"start": position(0, 0), "end": end_of_text(text)}, "newText": formatted}]))
+            }
+            "workspace/executeCommand" => {
+                if params["command"] != SHOW_GENERATED_LATEX {
+                    return Err((INVALID_PARAMS, format!("unknown command {}", params["command"])));
+                }
+                let uri = params["arguments"][0].as_str().ok_or_else(|| (INVALID_PARAMS, "expected a URI".to_string()))?;
+                let text = self.documents.get(uri).ok_or_else(|| not_open(uri))?;
+                Ok(Value::String(self.transpile(uri, text).text))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+        }
+    }
+
+    // Update the open documents, return the notifications to send
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(u) => u.to_string(),
+            None => return Vec::new(),
+        };
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri.clone(), text);
+            }
+            // Only full synchronisation has been announced, hence the last change is the whole text
+            "textDocument/didChange" => match params["contentChanges"].as_array().and_then(|c| c.last()) {
+                Some(change) => {
+                    let text = change["text"].as_str().unwrap_or("").to_string();
+                    self.documents.insert(uri.clone(), text);
+                }
+                None => return Vec::new(),
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, Vec::new())];
+            }
+            _ => return Vec::new(),
+        }
+
+        let text = &self.documents[&uri];
+        let lines = trimmed_lines(text);
+        let mut diagnostics = self.transpile(&uri, text).diagnostics;
+        diagnostics.extend(check_indentation(&lines));
+        diagnostics.sort_by_key(|d| d.line);
+        // Diagnostics in included files are reported when those files are opened
+        let diagnostics = diagnostics.iter()
+            .filter(|d| d.file.is_none())
+            .map(|d| {
+                json!({
+                    "range": match d.column {
+                        Some(c) => character_range(&lines, d.line - 1, c - 1),
+                        None => line_range(&lines, d.line - 1),
+                    },
+                    "severity": if d.is_error() { SEVERITY_ERROR } else { SEVERITY_WARNING },
+                    "source": "indentex",
+                    "message": d.message,
+                })
+            })
+            .collect();
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    fn document<'b>(&'b self, params: &'b Value) -> Result<(&'b str, &'b str), RequestError> {
+        let uri = params["textDocument"]["uri"].as_str()
+            .ok_or_else(|| (INVALID_PARAMS, "expected a text document".to_string()))?;
+        match self.documents.get(uri) {
+            Some(text) => Ok((uri, text)),
+            None => Err(not_open(uri)),
+        }
+    }
+
+    fn transpile(&self, uri: &str, text: &str) -> Transpiled {
+        use transpile::transpile;
+
+        let path = uri_to_path(uri);
+        transpile(&trimmed_lines(text), path.as_deref(), self.options)
+    }
+
+    // Show the LaTeX produced by a hashline
+    fn hover(&self, params: &Value) -> Result<Value, RequestError> {
+        use parsers::{parse_hashline, process_line};
+        use parsers::Hashline::OpenEnv;
+
+        let (uri, text) = self.document(params)?;
+        let lines = trimmed_lines(text);
+        let line_num = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        match lines.get(line_num) {
+            Some(l) if parse_hashline(l).is_some() => {}
+            _ => return Ok(Value::Null),
+        }
+
+        let transpiled = self.transpile(uri, text);
+        let mut latex: Vec<&str> = transpiled.text
+            .lines()
+            .zip(&transpiled.line_map)
+            .filter(|(_, l)| l.as_ref().is_some_and(|l| l.file.is_none() && l.line == line_num + 1))
+            .map(|(t, _)| t)
+            .collect();
+        // The body of an environment is closed on a later line
+        let end = match process_line(&lines[line_num], false) {
+            OpenEnv(e) => Some(e.latex_end()),
+            _ => None,
+        };
+        if let Some(ref end) = end {
+            if !latex.contains(&end.as_str()) {
+                latex.push("...");
+                latex.push(end);
+            }
+        }
+
+        let indent = latex.iter().filter(|l| **l != "...").map(|l| l.len() - l.trim_start().len()).min().unwrap_or(0);
+        let latex: Vec<&str> = latex.iter().map(|l| if *l == "..." { "  ..." } else { &l[indent..] }).collect();
+        Ok(json!({
+            "contents": {"kind": "markdown", "value": format!("```latex\n{}\n```", latex.join("\n"))},
+            "range": line_range(&lines, line_num),
+        }))
+    }
+
+    // Jump from a reference to the labels with the same key in the open documents
+    fn definition(&self, params: &Value) -> Result<Value, RequestError> {
+        let (uri, text) = self.document(params)?;
+        let lines = trimmed_lines(text);
+        let line_num = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let line = match lines.get(line_num) {
+            Some(l) => l,
+            None => return Ok(Value::Null),
+        };
+        let column = byte_offset(line, params["position"]["character"].as_u64().unwrap_or(0) as usize);
+        let key = match reference_at(line, column) {
+            Some(k) => k,
+            None => return Ok(Value::Null),
+        };
+
+        // Search the current document first
+        let mut uris: Vec<&String> = self.documents.keys().filter(|u| *u != uri).collect();
+        uris.sort();
+        let mut locations = Vec::new();
+        for u in Some(uri).into_iter().chain(uris.into_iter().map(String::as_str)) {
+            let lines = trimmed_lines(&self.documents[u]);
+            for (i, l) in lines.iter().enumerate() {
+                if command_arguments(l, &[LABEL_COMMAND]).iter().any(|&(_, _, k)| k == key) {
+                    locations.push(json!({"uri": u, "range": line_range(&lines, i)}));
+                }
+            }
+        }
+
+        Ok(if locations.is_empty() { Value::Null } else { Value::Array(locations) })
+    }
+}
+
+/// Convert a `file://` URI into a path, `None` for other schemes
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix(FILE_SCHEME)?;
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let decoded = match path.as_bytes()[i] {
+            b'%' => path.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(b) => {
+                bytes.push(b);
+                i += 3;
+            }
+            None => {
+                bytes.push(path.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn trimmed_lines(text: &str) -> Vec<String> {
+    text.lines().map(|l| l.trim_end().to_string()).collect()
+}
+
+// Preprocessor directives are parsed like environments, but do not open any
+fn scan_environments(lines: &[String]) -> Vec<EnvironmentSpan> {
+    use transpile::scan_environments;
+
+    let (directives, spans): (Vec<_>, Vec<_>) =
+        scan_environments(lines).into_iter().partition(|s| DIRECTIVES.contains(&s.name.as_str()));
+    spans.into_iter()
+        .map(|s| {
+            let enclosing = directives.iter().filter(|d| d.start < s.start && s.start <= d.end).count();
+            EnvironmentSpan { depth: s.depth - enclosing, ..s }
+        })
+        .collect()
+}
+
+// Warn about indentation which is probably not what the author meant
+fn check_indentation(lines: &[String]) -> Vec<Diagnostic> {
+    let source = SourceLine::number(lines);
+    let mut diagnostics: Vec<Diagnostic> = source.iter()
+        .filter(|l| l.text[..l.text.len() - l.text.trim_start().len()].contains('\t'))
+        .map(|l| Diagnostic::warning(l, "tab in indentation, each tab counts as a single space"))
+        .collect();
+    for s in scan_environments(lines).iter().filter(|s| s.end == s.start) {
+        let msg = format!("environment '{}' is empty, its body must be indented deeper than the hashline", s.name);
+        diagnostics.push(Diagnostic::warning(&source[s.start], msg));
+    }
+    diagnostics
+}
+
+// Nest the environments as in `env_stack`
+fn document_symbols(spans: &[EnvironmentSpan], lines: &[String]) -> Vec<Value> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < spans.len() {
+        let depth = spans[i].depth;
+        let children_end = spans[i + 1..].iter().position(|s| s.depth <= depth).map_or(spans.len(), |p| i + 1 + p);
+        symbols.push(json!({
+            "name": spans[i].name,
+            "kind": SYMBOL_NAMESPACE,
+            "range": {"start": position(spans[i].start, 0), "end": line_range(lines, spans[i].end)["end"]},
+            "selectionRange": line_range(lines, spans[i].start),
+            "children": document_symbols(&spans[i + 1..children_end], lines),
+        }));
+        i = children_end;
+    }
+    symbols
+}
+
+// Key of the reference at a byte offset, either in `\ref{key}` or in a hashline `# ref: key`
+fn reference_at(line: &str, column: usize) -> Option<&str> {
+    command_arguments(line, &REF_COMMANDS)
+        .into_iter()
+        .find(|&(command, start, arg)| command <= column && column <= start + arg.len())
+        .and_then(|(_, start, arg)| key_at(arg, column.saturating_sub(start)))
+}
+
+// `\cref` and friends accept comma-separated lists of keys
+fn key_at(keys: &str, offset: usize) -> Option<&str> {
+    let mut start = 0;
+    for key in keys.split(',') {
+        if offset <= start + key.len() {
+            return Some(key.trim()).filter(|k| !k.is_empty());
+        }
+        start += key.len() + 1;
+    }
+    keys.rsplit(',').next().map(str::trim).filter(|k| !k.is_empty())
+}
+
+// Arguments of `\command{...}` and of `# command: ...` for the given commands, together with the
+// byte offsets of the command and of the argument
+fn command_arguments<'a>(line: &'a str, commands: &[&str]) -> Vec<(usize, usize, &'a str)> {
+    use parsers::parse_hashline;
+
+    let mut arguments = Vec::new();
+    if let Some(p) = parse_hashline(line) {
+        if commands.contains(&p.name.as_str()) && p.opts.is_empty() && !p.args.is_empty() {
+            // The names of these commands contain no colons, hence the first one ends the name
+            let after_colon = line.find(':').map_or(0, |i| i + 1);
+            if let Some(i) = line[after_colon..].find(&p.args) {
+                let start = after_colon + i;
+                arguments.push((p.indent_depth, start, &line[start..start + p.args.len()]));
+            }
+        }
+    }
+
+    for c in commands {
+        let pattern = format!(r"\{}{{", c);
+        let mut from = 0;
+        while let Some(i) = line[from..].find(&pattern) {
+            let start = from + i + pattern.len();
+            match line[start..].find('}') {
+                Some(len) => arguments.push((from + i, start, &line[start..start + len])),
+                None => break,
+            }
+            from = start;
+        }
+    }
+
+    arguments
+}
+
+fn position(line: usize, character: usize) -> Value {
+    json!({"line": line, "character": character})
+}
+
+// Range of a whole line, characters are counted in UTF-16 code units
+fn line_range(lines: &[String], line: usize) -> Value {
+    let len = lines.get(line).map_or(0, |l| l.encode_utf16().count());
+    json!({"start": position(line, 0), "end": position(line, len)})
+}
+
+// Range of the character at a zero-based column counted in characters
+fn character_range(lines: &[String], line: usize, column: usize) -> Value {
+    let text = lines.get(line).map_or("", String::as_str);
+    let start = text.chars().take(column).map(char::len_utf16).sum();
+    let len = text.chars().nth(column).map_or(0, char::len_utf16);
+    json!({"start": position(line, start), "end": position(line, start + len)})
+}
+
+fn end_of_text(text: &str) -> Value {
+    let last = text.rsplit('\n').next().unwrap_or("");
+    position(text.matches('\n').count(), last.encode_utf16().count())
+}
+
+// Convert a position in UTF-16 code units into a byte offset
+fn byte_offset(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn not_open(uri: &str) -> RequestError {
+    (INVALID_PARAMS, format!("document '{}' is not open", uri))
+}
+
+fn error_response(id: Value, (code, message): RequestError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+// Read the body of the next message, `None` at the end of the input
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<String>, IndentexError> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(i) = header.find(':') {
+            if header[..i].eq_ignore_ascii_case("Content-Length") {
+                length = header[i + 1..].trim().parse().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(body))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<(), IndentexError> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    const URI: &str = "file:///tmp/indentex%20lsp/paper.inden.tex";
+    const TEXT: &str = "# figure [h]:\n  # center:\n    # includegraphics: a.pdf\n  # label: fig:a\n\
+                        # itemize:\n\t* see \\ref{fig:a}\n# if draft:\n  # center:\n    draft\n# else:\n";
+
+    // Send the messages to a server and return everything it has written
+    fn session(messages: &[Value]) -> Vec<Value> {
+        use super::{read_message, run};
+        use std::io::Cursor;
+        use transpile::TranspileOptions;
+
+        let options = TranspileOptions::default();
+        let input: String = messages.iter()
+            .map(|m| format!("Content-Length: {}\r\n\r\n{}", m.to_string().len(), m))
+            .collect();
+
+        let mut output = Vec::new();
+        run(&mut Cursor::new(input), &mut output, &options).unwrap();
+
+        let mut output = Cursor::new(output);
+        let mut responses = Vec::new();
+        while let Some(body) = read_message(&mut output).unwrap() {
+            responses.push(::serde_json::from_str(&body).unwrap());
+        }
+        responses
+    }
+
+    fn open() -> Value {
+        json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+               "params": {"textDocument": {"uri": URI, "languageId": "latex", "version": 1, "text": TEXT}}})
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+    }
+
+    fn position(line: u64, character: u64) -> Value {
+        json!({"textDocument": {"uri": URI}, "position": {"line": line, "character": character}})
+    }
+
+    #[test]
+    fn lifecycle() {
+        let responses = session(&[request(1, "initialize", json!({"capabilities": {}})),
+                                  json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+                                  request(2, "textDocument/rename", json!({})),
+                                  request(3, "shutdown", Value::Null),
+                                  json!({"jsonrpc": "2.0", "method": "exit"}),
+                                  request(4, "shutdown", Value::Null)]);
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["capabilities"]["textDocumentSync"], 1);
+        assert_eq!(responses[0]["result"]["capabilities"]["executeCommandProvider"]["commands"][0],
+                   "indentex.showGeneratedLatex");
+        assert_eq!(responses[1]["error"]["code"], -32601);
+        assert_eq!(responses[2], json!({"jsonrpc": "2.0", "id": 3, "result": null}));
+    }
+
+    #[test]
+    fn diagnostics() {
+        let change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange",
+                            "params": {"textDocument": {"uri": URI, "version": 2},
+                                       "contentChanges": [{"text": "# section: {{title}}\n# emph: 😀}\n"}]}});
+        let close = json!({"jsonrpc": "2.0", "method": "textDocument/didClose",
+                           "params": {"textDocument": {"uri": URI}}});
+        let responses = session(&[open(), change, close, request(1, "textDocument/hover", position(0, 0))]);
+
+        assert_eq!(responses[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(responses[0]["params"]["uri"], URI);
+        let diagnostics: Vec<(u64, u64)> = responses[0]["params"]["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| (d["range"]["start"]["line"].as_u64().unwrap(), d["severity"].as_u64().unwrap()))
+            .collect();
+        // The tab and the undefined name, the empty `# else:` in line 9 is no empty environment
+        assert_eq!(diagnostics, [(5, 2), (6, 2)]);
+
+        let diagnostics = &responses[1]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+        assert_eq!(diagnostics[0]["message"], "undefined variable 'title'");
+        assert_eq!(diagnostics[0]["range"]["end"], json!({"line": 0, "character": 20}));
+        // Only the delimiter is marked, the emoji counts as two UTF-16 code units
+        assert_eq!(diagnostics[1]["message"], "unmatched '}' in the arguments");
+        assert_eq!(diagnostics[1]["range"],
+                   json!({"start": {"line": 1, "character": 10}, "end": {"line": 1, "character": 11}}));
+
+        assert_eq!(responses[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(responses[3]["error"]["code"], -32602);
+    }
+
+    #[test]
+    fn structure() {
+        let document = json!({"textDocument": {"uri": URI}});
+        let responses = session(&[open(),
+                                  request(1, "textDocument/documentSymbol", document.clone()),
+                                  request(2, "textDocument/foldingRange", document)]);
+
+        let symbols = &responses[1]["result"];
+        assert_eq!(symbols.as_array().unwrap().len(), 3);
+        assert_eq!(symbols[0]["name"], "figure");
+        assert_eq!(symbols[0]["range"], json!({"start": {"line": 0, "character": 0}, "end": {"line": 3, "character": 16}}));
+        assert_eq!(symbols[0]["children"][0]["name"], "center");
+        assert_eq!(symbols[0]["children"][0]["children"], json!([]));
+        assert_eq!(symbols[1]["name"], "itemize");
+        // Environments within an `# if` block are not nested in it
+        assert_eq!(symbols[2]["name"], "center");
+        assert_eq!(symbols[2]["range"]["start"], json!({"line": 7, "character": 0}));
+
+        assert_eq!(responses[2]["result"],
+                   json!([{"startLine": 0, "endLine": 3}, {"startLine": 1, "endLine": 2}, {"startLine": 4, "endLine": 5},
+                          {"startLine": 7, "endLine": 8}]));
+    }
+
+    #[test]
+    fn hover() {
+        let responses = session(&[open(),
+                                  request(1, "textDocument/hover", position(0, 3)),
+                                  request(2, "textDocument/hover", position(2, 6)),
+                                  request(3, "textDocument/hover", position(5, 2))]);
+
+        assert_eq!(responses[1]["result"]["contents"]["value"],
+                   "```latex\n\\begin{figure}[h]\n  ...\n\\end{figure}\n```");
+        assert_eq!(responses[2]["result"]["contents"]["value"],
+                   "```latex\n  \\includegraphics{a.pdf}\n\\end{center}\n```");
+        assert_eq!(responses[3]["result"], Value::Null);
+    }
+
+    #[test]
+    fn definition() {
+        let other = "file:///tmp/other.inden.tex";
+        let open_other = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+                                "params": {"textDocument": {"uri": other, "languageId": "latex", "version": 1,
+                                                            "text": "\\cref{x,fig:a}\n\\label{fig:a}\n"}}});
+        let at = |line, character| json!({"textDocument": {"uri": other}, "position": {"line": line, "character": character}});
+        let responses = session(&[open(),
+                                  open_other,
+                                  request(1, "textDocument/definition", position(5, 10)),
+                                  request(2, "textDocument/definition", at(0, 9)),
+                                  request(3, "textDocument/definition", at(0, 6)),
+                                  request(4, "textDocument/definition", position(0, 0))]);
+
+        let location = |uri: &str, line, end| {
+            json!({"uri": uri, "range": {"start": {"line": line, "character": 0}, "end": {"line": line, "character": end}}})
+        };
+        assert_eq!(responses[2]["result"], json!([location(URI, 3, 16), location(other, 1, 13)]));
+        assert_eq!(responses[3]["result"], json!([location(other, 1, 13), location(URI, 3, 16)]));
+        assert_eq!(responses[4]["result"], Value::Null);
+        assert_eq!(responses[5]["result"], Value::Null);
+    }
+
+    #[test]
+    fn formatting_and_commands() {
+        let other = "file:///tmp/other.inden.tex";
+        let open_other = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+                                "params": {"textDocument": {"uri": other, "languageId": "latex", "version": 1,
+                                                            "text": "# center:\n a\n"}}});
+        let document = json!({"textDocument": {"uri": URI}, "options": {"tabSize": 2, "insertSpaces": true}});
+        let command = json!({"command": "indentex.showGeneratedLatex", "arguments": [URI]});
+        let responses = session(&[open(),
+                                  open_other,
+                                  request(1, "textDocument/formatting", document),
+                                  request(2, "workspace/executeCommand", command),
+                                  request(3, "workspace/executeCommand", json!({"command": "foo"})),
+                                  request(4, "textDocument/formatting",
+                                          json!({"textDocument": {"uri": other}, "options": {"tabSize": 4}}))]);
+
+        // The item is indented with a tab, which would make it an item
+        assert_eq!(responses[2]["error"]["code"], -32803);
+        assert_eq!(responses[2]["error"]["message"].as_str().unwrap().split(':').next(), Some("6"));
+        let latex = responses[3]["result"].as_str().unwrap();
+        assert!(latex.starts_with("\\begin{figure}[h]\n  \\begin{center}\n"), "{}", latex);
+        assert_eq!(responses[4]["error"]["code"], -32602);
+        assert_eq!(responses[5]["result"][0]["newText"], "# center:\n    a\n");
+    }
+
+    #[test]
+    fn uri_to_path() {
+        use super::uri_to_path;
+        use std::path::PathBuf;
+
+        assert_eq!(uri_to_path(URI), Some(PathBuf::from("/tmp/indentex lsp/paper.inden.tex")));
+        assert_eq!(uri_to_path("file:///a%2"), Some(PathBuf::from("/a%2")));
+        assert_eq!(uri_to_path("untitled:Untitled-1"), None);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use error::IndentexError;
+use parsers::{parse_hashline, HashlineParts};
+use transpile::SourceLine;
+
+
+const DEFINE_KEYWORD: &str = "define";
+const ARGS_SEPARATOR: char = '|';
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Macro {
+    // Replaces the hashline name only, e.g. `# eq:` becomes `# equation:`
+    Alias(String),
+    // Replaces the whole hashline with indentex lines containing placeholders
+    Template { body: Vec<String>, arity: usize },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+}
+
+impl MacroTable {
+    pub fn new() -> MacroTable {
+        MacroTable { macros: HashMap::new() }
+    }
+
+    pub fn define_alias<T, U>(&mut self, name: T, target: U)
+        where T: Into<String>,
+              U: Into<String>
+    {
+        self.macros.insert(name.into(), Macro::Alias(target.into()));
+    }
+
+    pub fn define_template<T: Into<String>>(&mut self, name: T, body: Vec<String>) {
+        let arity = body.iter().map(|l| max_placeholder(l)).max().unwrap_or(0);
+        self.macros.insert(name.into(), Macro::Template { body, arity });
+    }
+
+    /// Load all `# define` blocks from a project macro file
+    pub fn load_file<T: AsRef<Path>>(&mut self, path: T) -> Result<Vec<Diagnostic>, IndentexError> {
+        use file_utils::read_and_trim_lines_unchecked;
+
+        let lines = read_and_trim_lines_unchecked(path)?;
+        let source = SourceLine::number(&lines);
+
+        let mut diagnostics = Vec::new();
+        for l in self.expand(source, &mut diagnostics) {
+            if !l.text.trim().is_empty() && !l.text.trim_start().starts_with('%') {
+                diagnostics.push(Diagnostic::warning(&l, "ignoring line outside of a macro definition"));
+            }
+        }
+
+        Ok(diagnostics)
+    }
+
+    /// Collect and strip macro definitions, and expand macro invocations
+    pub fn expand(&mut self, lines: Vec<SourceLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<SourceLine> {
+        use transpile::scan_indents;
+
+        let adjusted_indents = scan_indents(&lines);
+        let mut expanded = Vec::with_capacity(lines.len());
+
+        let mut i = 0;
+        while i < lines.len() {
+            match parse_hashline(&lines[i]) {
+                Some(ref parts) if parts.name == DEFINE_KEYWORD => {
+                    // The definition body consists of all lines indented deeper than the hashline
+                    let mut j = i + 1;
+                    while j < lines.len() && adjusted_indents[j] > parts.indent_depth {
+                        j += 1;
+                    }
+                    self.define(parts, &lines[i + 1..j], &lines[i], diagnostics);
+                    i = j;
+                }
+                _ => {
+                    self.expand_line(lines[i].clone(), 0, &mut expanded, diagnostics);
+                    i += 1;
+                }
+            }
+        }
+
+        expanded
+    }
+
+    fn define(&mut self, parts: &HashlineParts, body: &[SourceLine], line: &SourceLine, diagnostics: &mut Vec<Diagnostic>) {
+        let name = &parts.opts;
+        if !is_valid_macro_name(name) {
+            diagnostics.push(Diagnostic::error(line, format!("invalid macro name '{}'", name)));
+            return;
+        }
+
+        if !parts.args.is_empty() {
+            if !body.is_empty() {
+                diagnostics.push(Diagnostic::error(line, format!("macro '{}' has both an alias target and a body", name)));
+            } else if !is_valid_macro_name(&parts.args) {
+                diagnostics.push(Diagnostic::error(line, format!("invalid alias target '{}'", parts.args)));
+            } else {
+                self.define_alias(name.as_str(), parts.args.as_str());
+            }
+            return;
+        }
+
+        if body.is_empty() {
+            diagnostics.push(Diagnostic::error(line, format!("macro '{}' has an empty body", name)));
+            return;
+        }
+
+        // `unwrap()` is safe here since the body is indented deeper than the definition
+        let body_indent = body.iter().filter_map(|l| count_indent(&l.text)).min().unwrap();
+        let dedented = body.iter()
+            .map(|l| if l.text.is_empty() { String::new() } else { l.text[body_indent..].to_string() })
+            .collect();
+        self.define_template(name.as_str(), dedented);
+    }
+
+    // Starred names fall back to the alias of the unstarred one, e.g. `# eq*:` to `# equation*:`
+    fn lookup<'a>(&'a self, name: &'a str) -> Option<(&'a str, &'a Macro)> {
+        match self.macros.get(name) {
+            Some(m) => Some((name, m)),
+            None => {
+                let base = name.strip_suffix('*')?;
+                match self.macros.get(base) {
+                    Some(m @ &Macro::Alias(_)) => Some((base, m)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn expand_line(&self, line: SourceLine, depth: usize, expanded: &mut Vec<SourceLine>, diagnostics: &mut Vec<Diagnostic>) {
+        let parts = match parse_hashline(&line) {
+            Some(p) => p,
+            None => return expanded.push(line),
+        };
+        let (name, m) = match self.lookup(&parts.name) {
+            Some(r) => r,
+            None => return expanded.push(line),
+        };
+        if depth >= MAX_EXPANSION_DEPTH {
+            let msg = format!("macro '{}' is nested too deeply (is it recursive?)", parts.name);
+            return diagnostics.push(Diagnostic::error(&line, msg));
+        }
+
+        match *m {
+            Macro::Alias(ref target) => {
+                // Replace the raw name, so that options, arguments and escapes are left untouched
+                let name_start = parts.indent_depth + "# ".len();
+                let text = match line.text[name_start..].strip_prefix(name) {
+                    Some(rest) => format!("{}# {}{}", &line.text[..parts.indent_depth], target, rest),
+                    None => return expanded.push(line),
+                };
+                self.expand_line(line.with_text(text), depth + 1, expanded, diagnostics);
+            }
+            Macro::Template { ref body, arity } => {
+                let params = macro_params(&parts);
+                if params.len() <= arity {
+                    let msg = format!("macro '{}' expects {} argument(s), but {} given",
+                                      parts.name, arity, params.len() - 1);
+                    diagnostics.push(Diagnostic::error(&line, msg));
+                } else if params.len() > arity + 1 {
+                    let msg = format!("macro '{}' expects {} argument(s), ignoring the remaining ones",
+                                      parts.name, arity);
+                    diagnostics.push(Diagnostic::warning(&line, msg));
+                }
+
+                for (i, body_line) in body.iter().enumerate() {
+                    let mut text = if body_line.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{:ind$}{}", "", substitute(body_line, &params), ind = parts.indent_depth)
+                    };
+                    if i == 0 && !parts.comment.is_empty() {
+                        text.push(' ');
+                        text.push_str(&parts.comment);
+                    }
+                    self.expand_line(line.with_text(text), depth + 1, expanded, diagnostics);
+                }
+            }
+        }
+    }
+}
+
+
+#[inline]
+fn is_valid_macro_name(name: &str) -> bool {
+    !name.is_empty() && name != DEFINE_KEYWORD &&
+    !name.chars().any(|c| c.is_whitespace() || "[]{}()%:".contains(c))
+}
+
+// Only spaces are removed when dedenting, since other whitespace may be wider than one byte
+#[inline]
+fn count_indent(line: &str) -> Option<usize> {
+    if line.is_empty() {
+        None
+    } else {
+        Some(line.len() - line.trim_start_matches(' ').len())
+    }
+}
+
+// Placeholder `#0` holds the options without brackets, `#1` to `#9` hold the arguments separated by `|`.
+// LaTeX's `\|` and `||` do not separate arguments.
+fn macro_params(parts: &HashlineParts) -> Vec<String> {
+    let opts = if parts.opts.starts_with('[') && parts.opts.ends_with(']') {
+        &parts.opts[1..parts.opts.len() - 1]
+    } else {
+        &parts.opts[..]
+    };
+
+    let mut params = vec![opts.trim().replace('%', r"\%")];
+    if !parts.args.is_empty() {
+        params.extend(split_args(&parts.args).into_iter().map(|a| a.trim().replace('%', r"\%")));
+    }
+    params
+}
+
+fn split_args(args: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut start = 0;
+    let mut chars = args.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c != ARGS_SEPARATOR {
+            continue;
+        }
+        // An odd number of backslashes in front means that the separator is escaped
+        let before = &args[..i];
+        if (before.len() - before.trim_end_matches('\\').len()) % 2 == 1 {
+            continue;
+        }
+        if chars.peek().map(|&(_, d)| d) == Some(ARGS_SEPARATOR) {
+            chars.next();
+            continue;
+        }
+        split.push(&args[start..i]);
+        start = i + 1;
+    }
+    split.push(&args[start..]);
+    split
+}
+
+fn max_placeholder(template: &str) -> usize {
+    let mut max = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c == '#' {
+            match chars.next().and_then(|d| d.to_digit(10)) {
+                Some(d) => max = max.max(d as usize),
+                None => continue,
+            }
+        }
+    }
+    max
+}
+
+// Replace `#0` to `#9` with the respective parameter and `##` with a literal `#`
+fn substitute<T: AsRef<str>>(template: &str, params: &[T]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '#' {
+            result.push(c);
+            continue;
+        }
+        match chars.peek().cloned() {
+            Some('#') => {
+                chars.next();
+                result.push('#');
+            }
+            Some(d) if d.is_ascii_digit() => {
+                chars.next();
+                // Missing parameters have already been reported, replace them with nothing
+                if let Some(p) = params.get(d.to_digit(10).unwrap() as usize) {
+                    result.push_str(p.as_ref());
+                }
+            }
+            _ => result.push('#'),
+        }
+    }
+
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use transpile::SourceLine;
+
+    fn expand_str(lines: &[&str]) -> (Vec<String>, usize) {
+        use super::MacroTable;
+
+        let mut diagnostics = Vec::new();
+        let expanded = MacroTable::new().expand(SourceLine::number(lines), &mut diagnostics);
+        (expanded.into_iter().map(|l| l.text).collect(), diagnostics.len())
+    }
+
+    #[test]
+    fn max_placeholder() {
+        use super::max_placeholder;
+
+        assert_eq!(max_placeholder("foo"), 0);
+        assert_eq!(max_placeholder("# foo: #1"), 1);
+        assert_eq!(max_placeholder("#3 #1 ##4"), 3);
+    }
+
+    #[test]
+    fn split_args() {
+        use super::split_args;
+
+        assert_eq!(split_args("a | b|c"), ["a ", " b", "c"]);
+        assert_eq!(split_args(r"$\|x\|$ | a || b | c\\| d"), [r"$\|x\|$ ", " a || b ", r" c\\", " d"]);
+        assert_eq!(split_args(""), [""]);
+    }
+
+    #[test]
+    fn substitute() {
+        use super::substitute;
+
+        let params = ["a", "b", "c"];
+        assert_eq!(substitute("# foo [#0]: #1 and #2", &params), "# foo [a]: b and c");
+        assert_eq!(substitute("##1 # #", &params), "#1 # #");
+        assert_eq!(substitute("#1#2#3", &params), "bc");
+    }
+
+    #[test]
+    fn aliases() {
+        let (l, n) = expand_str(&["# define eq: equation", "# eq [x]:", "  a % b", "# eq*: foo"]);
+        assert_eq!(l, ["# equation [x]:", "  a % b", "# equation*: foo"]);
+        assert_eq!(n, 0);
+
+        // Recursive definitions are reported instead of looping forever
+        let (l, n) = expand_str(&["# define a: b", "# define b: a", "# a: foo"]);
+        assert!(l.is_empty());
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn templates() {
+        let (l, n) = expand_str(&[
+            "# define fig:",
+            "  # figure:",
+            "    \\includegraphics[width=#0]{#1}",
+            "",
+            "    # caption: #2",
+            "",
+            "# itemize:",
+            "  # fig [0.8]: a.pdf | 50\\% | unused % comment",
+        ]);
+        assert_eq!(l, [
+            "",
+            "# itemize:",
+            "  # figure: % comment",
+            "    \\includegraphics[width=0.8]{a.pdf}",
+            "",
+            "    # caption: 50\\%",
+        ]);
+        // Warning for the superfluous argument
+        assert_eq!(n, 1);
+
+        let (l, n) = expand_str(&["# define foo:", "  #1 #2", "# foo: a"]);
+        assert_eq!(l, ["a "]);
+        assert_eq!(n, 1);
+
+        // A no-break space in the indentation of the body is kept
+        let (l, n) = expand_str(&["# define foo:", "  # center:", " \u{a0}  #1", "# foo: a"]);
+        assert_eq!(l, [" # center:", "\u{a0}  a"]);
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let (l, n) = expand_str(&["# define:", "  foo", "# define a b: c", "# define c:", "bar"]);
+        assert_eq!(l, ["bar"]);
+        assert_eq!(n, 3);
+    }
+}
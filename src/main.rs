@@ -1,40 +1,52 @@
 #[macro_use]
 extern crate clap;
-extern crate globset;
-extern crate ignore;
-#[macro_use]
-extern crate nom;
+extern crate indentex;
 extern crate rayon;
+extern crate serde_json;
 
-// Import helper macros before `parsers`
-#[macro_use]
-mod helper_parsers;
-
-mod error;
-mod file_utils;
-mod parsers;
-mod transpile;
+use indentex::{ast, bundle, config, depfile, diagnostics, engine, error, file_utils, format, graph, lint, lsp, macros,
+               names, pack, references, source_map, synctex, transpile, variables};
 
+// Codes of indentex errors are powers of two. If several problems occur, the highest code is returned, hence later
+// codes take precedence over earlier ones. Unix only keeps the lowest 8 bits of the exit status, so the failures of
+// the checks run by some subcommands follow the powers of two with values above 159, which shells use for processes
+// killed by a signal.
 enum ReturnCode {
     Ok = 0,
     WalkError = 2,
     FileTypeError = 4,
     TranspilationError = 8,
+    MacroFileError = 16,
+    ConfigError = 32,
+    DependencyCycle = 64,
+    DepfileError = 128,
+    // The LaTeX engine reported errors
+    EngineError = 192,
+    // Only returned by 'fmt --check'
+    Unformatted = 224,
+    // Only returned by 'lint' for findings which are errors
+    LintError = 240,
 }
 
-fn main() {
-    use clap::{App, Arg};
-    use file_utils::walk_indentex_files;
-    use rayon::prelude::*;
-    use std::cmp;
-    use std::path::{Path, PathBuf};
-    use std::process;
-    use transpile::{transpile_file, TranspileOptions};
+fn print_diagnostics<W, T>(out: &mut W, path: T, diagnostics: &[diagnostics::Diagnostic])
+    where W: std::io::Write,
+          T: AsRef<std::path::Path>
+{
+    for d in diagnostics {
+        // Diagnostics from included files carry their own path
+        let file = d.file.as_ref().map_or(path.as_ref(), |f| f.as_path());
+        writeln!(out, "{}:{}", file.display(), d).unwrap();
+    }
+}
+
+fn build_cli() -> clap::App<'static, 'static> {
+    use clap::{App, AppSettings, Arg, SubCommand};
 
-    let m = App::new("indentex")
+    App::new("indentex")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Transpiler for an indentation-based superset of LaTeX")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("path")
             .help("Path to a single indentex file or a directory (recursively transpile all \
                    indentex files)")
@@ -43,59 +55,850 @@ fn main() {
         .arg(Arg::with_name("verbose")
             .help("Show transpilation progress")
             .short("v")
-            .long("verbose"))
+            .long("verbose")
+            .global(true))
         .arg(Arg::with_name("flatten-output")
             .help("Remove all indentation from the output")
-            .long("flatten-output"))
+            .long("flatten-output")
+            .global(true))
         .arg(Arg::with_name("disable-do-not-edit")
             .help("Disable prepending the 'DO NOT EDIT' notice")
-            .long("disable-do-not-edit"))
-        .get_matches();
+            .long("disable-do-not-edit")
+            .global(true))
+        .arg(Arg::with_name("strict-hashlines")
+            .help("Report lines which almost are hashlines, e.g. '#section: Foo', as errors instead of warnings")
+            .long("strict-hashlines")
+            .global(true))
+        .arg(Arg::with_name("check-names")
+            .help("Warn about unknown environments and misspelt commands, including the ones defined in the LaTeX \
+                   files of the project")
+            .long("check-names")
+            .global(true))
+        .arg(Arg::with_name("macros")
+            .help("Load hashline macros from a file with '# define' blocks (can be repeated)")
+            .long("macros")
+            .value_name("FILE")
+            .multiple(true)
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("define")
+            .help("Set a name for '# if' blocks (can be repeated)")
+            .short("D")
+            .long("define")
+            .value_name("NAME")
+            .multiple(true)
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("set")
+            .help("Set a variable for '{{name}}' placeholders, overriding the front matter (can be repeated)")
+            .long("set")
+            .value_name("NAME=VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|s| match s.find('=') {
+                Some(i) if variables::is_valid_variable_name(&s[..i]) => Ok(()),
+                _ => Err("expected 'name=value'".to_string()),
+            })
+            .global(true))
+        .arg(Arg::with_name("filter")
+            .help("Pass the syntax tree as JSON through this command before emitting LaTeX (can be repeated)")
+            .long("filter")
+            .value_name("COMMAND")
+            .multiple(true)
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("config")
+            .help("Use this config file instead of looking for 'indentex.toml' next to the path \
+                   and in its parent directories")
+            .long("config")
+            .value_name("FILE")
+            .global(true))
+        .arg(Arg::with_name("depfile")
+            .help("Write a Makefile rule for each output, listing the files it depends on")
+            .long("depfile")
+            .value_name("FILE")
+            .global(true))
+        .subcommand(SubCommand::with_name("build-graph")
+            .about("Transpile only the files reachable from a main file via '\\input', \
+                    '\\include' and '# include:'")
+            .arg(Arg::with_name("main")
+                .help("Path to the main indentex file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("graph")
+                .help("Print the dependency graph to stdout")
+                .long("graph")
+                .value_name("FORMAT")
+                .possible_values(&["text", "dot"]))
+            .arg(Arg::with_name("dry-run")
+                .help("Do not write any files")
+                .long("dry-run")))
+        .subcommand(SubCommand::with_name("build")
+            .about("Transpile the files reachable from a main file and run the LaTeX engine, reporting its \
+                    messages in the indentex sources")
+            .arg(Arg::with_name("main")
+                .help("Path to the main indentex file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("engine")
+                .help("LaTeX engine to run")
+                .long("engine")
+                .value_name("ENGINE")
+                .possible_values(&engine::ENGINES)
+                .default_value("latexmk"))
+            .arg(Arg::with_name("engine-command")
+                .help("Run this executable instead of the engine")
+                .long("engine-command")
+                .value_name("COMMAND")))
+        .subcommand(SubCommand::with_name("synctex-rewrite")
+            .about("Point a SyncTeX file to the indentex sources instead of the transpiled files")
+            .arg(Arg::with_name("synctex")
+                .help("Path to the SyncTeX file (optionally gzipped)")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Write the rewritten file here instead of replacing the original")
+                .short("o")
+                .long("output")
+                .value_name("FILE")))
+        .subcommand(SubCommand::with_name("bundle")
+            .about("Transpile a main file and inline all referenced files into a single LaTeX file")
+            .arg(Arg::with_name("main")
+                .help("Path to the main indentex file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Path of the bundled LaTeX file")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .required(true))
+            .arg(Arg::with_name("strip-comments")
+                .help("Remove all LaTeX comments")
+                .long("strip-comments")))
+        .subcommand(SubCommand::with_name("pack")
+            .about("Write a tarball with the transpiled files reachable from a main file and the local \
+                    files they use")
+            .arg(Arg::with_name("main")
+                .help("Path to the main indentex file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Path of the gzipped tarball")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .required(true)))
+        .subcommand(SubCommand::with_name("parse")
+            .about("Print the syntax tree of an indentex file")
+            .arg(Arg::with_name("file")
+                .help("Path to the indentex file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("json")
+                .help("Print the tree as JSON")
+                .long("json")))
+        .subcommand(SubCommand::with_name("fmt")
+            .about("Reindent indentex files and normalise the spacing of hashlines and items")
+            .arg(Arg::with_name("path")
+                .help("Path to a single indentex file or a directory (recursively format all indentex files)")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("check")
+                .help("Only report the files which are not formatted")
+                .long("check"))
+            .arg(Arg::with_name("indent-width")
+                .help("Number of spaces per nesting level")
+                .long("indent-width")
+                .value_name("WIDTH")
+                .default_value("2")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(w) if w > 0 => Ok(()),
+                    _ => Err("expected a positive number".to_string()),
+                })))
+        .subcommand(SubCommand::with_name("lint")
+            .about("Report questionable constructs in indentex files")
+            .arg(Arg::with_name("path")
+                .help("Path to a single indentex file or a directory (recursively lint all indentex files)")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("rule")
+                .help("Set the level of a rule, overriding the config file (can be repeated)")
+                .long("rule")
+                .value_name("RULE=LEVEL")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    let mut parts = s.splitn(2, '=');
+                    match (parts.next().and_then(lint::Rule::from_id), parts.next().and_then(lint::Level::from_name)) {
+                        (Some(_), Some(_)) => Ok(()),
+                        _ => {
+                            let rules: Vec<_> = lint::Rule::ALL.iter().map(|r| r.id()).collect();
+                            Err(format!("expected 'rule=level' with a rule out of {} and a level out of off, \
+                                         warning and error",
+                                        rules.join(", ")))
+                        }
+                    }
+                }))
+            .arg(Arg::with_name("max-depth")
+                .help("Number of nested environments allowed by the 'max-depth' rule, overriding the config file")
+                .long("max-depth")
+                .value_name("DEPTH")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|_| "expected a number".to_string()))))
+        .subcommand(SubCommand::with_name("lsp")
+            .about("Run a language server speaking the Language Server Protocol over stdin and stdout"))
+}
+
+// Collect the transpilation options from the config file and the command line, report problems to `out`
+fn load_options<W>(m: &clap::ArgMatches, path: &std::path::Path, out: &mut W) -> (transpile::TranspileOptions, i32)
+    where W: std::io::Write
+{
+    let (config, ret_val) = load_config(m, path, out);
+    let (options, ret_val_options) = build_options(m, path, config, out);
+    (options, std::cmp::max(ret_val, ret_val_options))
+}
+
+// Load the given config file or look for one, the default config is used if there is none
+fn load_config<W>(m: &clap::ArgMatches, path: &std::path::Path, out: &mut W) -> (config::Config, i32)
+    where W: std::io::Write
+{
+    use config::{find_config_file, Config};
+    use std::path::PathBuf;
+
+    let config_path = m.value_of("config").map(PathBuf::from).or_else(|| find_config_file(path));
+    match config_path {
+        Some(ref c) => match Config::load(c) {
+            Ok(config) => {
+                if m.is_present("verbose") {
+                    writeln!(out, "Using config file '{}'", c.display()).unwrap();
+                }
+                (config, ReturnCode::Ok as i32)
+            }
+            Err(e) => {
+                writeln!(out, "Could not load config file '{}': {}", c.display(), e).unwrap();
+                (Config::default(), ReturnCode::ConfigError as i32)
+            }
+        },
+        None => (Config::default(), ReturnCode::Ok as i32),
+    }
+}
+
+fn build_options<W>(m: &clap::ArgMatches,
+                    path: &std::path::Path,
+                    config: config::Config,
+                    out: &mut W)
+                    -> (transpile::TranspileOptions, i32)
+    where W: std::io::Write
+{
+    use macros::MacroTable;
+    use names::KnownNames;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use transpile::TranspileOptions;
+
+    let mut ret_val = ReturnCode::Ok as i32;
+
+    let known_names = if m.is_present("check-names") {
+        // The project is the directory of the config file, otherwise the one of the path
+        let dir = match config.path {
+            Some(ref c) => c.parent(),
+            None if path.is_dir() => Some(path),
+            None => path.parent(),
+        };
+        let dir = dir.filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        match KnownNames::from_project(dir) {
+            Ok(k) => Some(Arc::new(k)),
+            Err(e) => {
+                ret_val = ReturnCode::WalkError as i32;
+                writeln!(out, "Could not collect the names defined in '{}': {}", dir.display(), e).unwrap();
+                Some(Arc::new(KnownNames::builtin()))
+            }
+        }
+    } else {
+        None
+    };
+    let mut config_files: Vec<PathBuf> = config.path.into_iter().collect();
+
+    let macro_files = config.macros.iter()
+        .cloned()
+        .chain(m.values_of("macros").into_iter().flatten().map(PathBuf::from));
+    let mut macros = MacroTable::new();
+    for macro_file in macro_files {
+        match macros.load_file(&macro_file) {
+            Ok(d) => {
+                print_diagnostics(out, &macro_file, &d);
+                if d.iter().any(|d| d.is_error()) {
+                    ret_val = ReturnCode::MacroFileError as i32;
+                }
+                config_files.push(macro_file);
+            }
+            Err(e) => {
+                ret_val = ReturnCode::MacroFileError as i32;
+                writeln!(out, "Could not load macros from '{}': {}", macro_file.display(), e).unwrap();
+            }
+        }
+    }
+
+    let mut defines = config.defines;
+    for name in m.values_of("define").into_iter().flatten() {
+        defines.insert(name.to_string(), true);
+    }
+
+    let mut variables = HashMap::new();
+    for assignment in m.values_of("set").into_iter().flatten() {
+        // `unwrap()` is safe here since the value has already been validated
+        let i = assignment.find('=').unwrap();
+        variables.insert(assignment[..i].to_string(), assignment[i + 1..].to_string());
+    }
 
-    let path = Path::new(m.value_of("path").unwrap());
-    let verbose = m.is_present("verbose");
     let options = TranspileOptions {
         flatten_output: m.is_present("flatten-output"),
         prepend_do_not_edit_notice: ! m.is_present("disable-do-not-edit"),
+        strict_hashlines: m.is_present("strict-hashlines"),
+        known_names,
+        macros,
+        defines,
+        default_variables: config.variables,
+        variables,
+        config_files,
+        row_environments: config.row_environments,
+        filters: m.values_of("filter").into_iter().flatten().map(String::from).collect(),
+        ..Default::default()
     };
 
-    let mut ret_val = ReturnCode::Ok as i32;
+    (options, ret_val)
+}
+
+// Write the Makefile rules of all outputs if requested
+fn write_depfile(m: &clap::ArgMatches, rules: &[String]) -> i32 {
+    use file_utils::write_to_file;
+
+    match m.value_of("depfile") {
+        Some(depfile) => match write_to_file(depfile, rules.concat()) {
+            Ok(()) => ReturnCode::Ok as i32,
+            Err(e) => {
+                eprintln!("Could not write depfile '{}': {}", depfile, e);
+                ReturnCode::DepfileError as i32
+            }
+        },
+        None => ReturnCode::Ok as i32,
+    }
+}
 
-    let batch: Vec<PathBuf> = if path.is_file() {
-        vec![path.to_path_buf()]
+// A single file or all indentex files in a directory
+fn collect_batch(path: &std::path::Path) -> (Vec<std::path::PathBuf>, i32) {
+    use file_utils::walk_indentex_files;
+
+    if path.is_file() {
+        (vec![path.to_path_buf()], ReturnCode::Ok as i32)
     } else if path.is_dir() {
-        match walk_indentex_files(&path) {
-            Ok(b) => b,
+        match walk_indentex_files(path) {
+            Ok(b) => (b, ReturnCode::Ok as i32),
             Err(e) => {
-                ret_val = ReturnCode::WalkError as i32;
                 println!("{}", e);
-                Vec::new()
+                (Vec::new(), ReturnCode::WalkError as i32)
             }
         }
     } else {
-        ret_val = ReturnCode::FileTypeError as i32;
         println!("Error: path '{}' is neither a file nor a directory", path.display());
-        Vec::new()
-    };
+        (Vec::new(), ReturnCode::FileTypeError as i32)
+    }
+}
+
+// Transpile a single file or all indentex files in a directory
+fn transpile_all(m: &clap::ArgMatches, path: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use depfile::dependency_rule;
+    use error::IndentexError;
+    use rayon::prelude::*;
+    use std::cmp;
+    use std::io;
+    use transpile::transpile_file;
+
+    let verbose = m.is_present("verbose");
+    let (batch, ret_val) = collect_batch(path);
 
-    let ret_val_transpilation = batch.par_iter()
-        .map(|p| match transpile_file(&p, &options) {
-            Ok(_) => {
+    let results: Vec<(i32, Option<String>)> = batch.par_iter()
+        .map(|p| match transpile_file(p, options) {
+            Ok(t) => {
+                print_diagnostics(&mut io::stdout(), p, &t.diagnostics);
                 if verbose {
                     println!("Transpiling file '{}'... ok", p.display());
                 }
-                ReturnCode::Ok
+                (ReturnCode::Ok as i32, dependency_rule(p, &t, &options.config_files))
             }
             Err(e) => {
+                if let IndentexError::Transpilation(ref d) = e {
+                    print_diagnostics(&mut io::stdout(), p, d);
+                }
                 if verbose {
                     println!("Transpiling file '{}'... failed", p.display());
                 }
                 println!("Could not transpile '{}': {}", p.display(), e);
+                (ReturnCode::TranspilationError as i32, None)
+            }
+        })
+        .collect();
+
+    let rules: Vec<String> = results.iter().filter_map(|r| r.1.clone()).collect();
+    let ret_val_transpilation = results.iter().map(|r| r.0).max().unwrap_or(ReturnCode::Ok as i32);
+
+    cmp::max(cmp::max(ret_val, ret_val_transpilation), write_depfile(m, &rules))
+}
+
+// Transpile all indentex files reachable from `main`, messages go to stderr to keep the graph clean
+fn transpile_graph(m: &clap::ArgMatches,
+                   main: &std::path::Path,
+                   options: &transpile::TranspileOptions,
+                   source_map: &mut source_map::SourceMap)
+                   -> i32 {
+    use depfile::dependency_rule;
+    use error::IndentexError;
+    use graph::{build_graph, Visited};
+    use std::cmp;
+    use std::io;
+    use transpile::write_output;
+
+    let verbose = m.is_present("verbose");
+
+    if !main.is_file() {
+        eprintln!("Error: path '{}' is not a file", main.display());
+        return ReturnCode::FileTypeError as i32;
+    }
+
+    let (graph, visited) = build_graph(main, options);
+    let mut ret_val = ReturnCode::Ok as i32;
+    let mut rules = Vec::new();
+
+    for (p, v) in visited {
+        let ret_val_file = match v {
+            Visited::Indentex(Ok(t)) => {
+                print_diagnostics(&mut io::stderr(), &p, &t.diagnostics);
+                let written = if m.is_present("dry-run") { Ok(()) } else { write_output(&p, &t) };
+                match written {
+                    Ok(()) => {
+                        if verbose {
+                            eprintln!("Transpiling file '{}'... ok", p.display());
+                        }
+                        rules.extend(dependency_rule(&p, &t, &options.config_files));
+                        // `unwrap()` is safe here since the output has just been named the same way
+                        source_map.add(&p, &t).unwrap();
+                        ReturnCode::Ok
+                    }
+                    Err(e) => {
+                        eprintln!("Could not write the output of '{}': {}", p.display(), e);
+                        ReturnCode::TranspilationError
+                    }
+                }
+            }
+            Visited::Indentex(Err(e)) => {
+                if let IndentexError::Transpilation(ref d) = e {
+                    print_diagnostics(&mut io::stderr(), &p, d);
+                }
+                if verbose {
+                    eprintln!("Transpiling file '{}'... failed", p.display());
+                }
+                eprintln!("Could not transpile '{}': {}", p.display(), e);
+                ReturnCode::TranspilationError
+            }
+            Visited::Latex(Ok(d)) => {
+                print_diagnostics(&mut io::stderr(), &p, &d);
+                ReturnCode::Ok
+            }
+            Visited::Latex(Err(e)) => {
+                eprintln!("Could not scan '{}': {}", p.display(), e);
                 ReturnCode::TranspilationError
             }
-        } as i32)
+        };
+        ret_val = cmp::max(ret_val, ret_val_file as i32);
+    }
+
+    if let Some(cycle) = graph.find_cycle() {
+        let cycle: Vec<_> = cycle.iter().map(|&i| graph.nodes[i].display().to_string()).collect();
+        eprintln!("Error: reference cycle: {}", cycle.join(" -> "));
+        ret_val = cmp::max(ret_val, ReturnCode::DependencyCycle as i32);
+    }
+
+    match m.value_of("graph") {
+        Some("dot") => print!("{}", graph.to_dot()),
+        Some(_) => print!("{}", graph.to_text()),
+        None => {}
+    }
+
+    if !m.is_present("dry-run") {
+        ret_val = cmp::max(ret_val, write_depfile(m, &rules));
+    }
+
+    ret_val
+}
+
+// Transpile the reachable files, run the LaTeX engine and report its messages in the sources
+fn build_document(m: &clap::ArgMatches, main: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use engine::{map_messages, parse_log, run_engine};
+    use file_utils::rename_indentex_file;
+    use source_map::SourceMap;
+    use std::path::Path;
+
+    let mut source_map = SourceMap::new();
+    let ret_val = transpile_graph(m, main, options, &mut source_map);
+    if ret_val != ReturnCode::Ok as i32 {
+        return ret_val;
+    }
+
+    // `unwrap()` is safe here since the main file has just been transpiled
+    let output = rename_indentex_file(main).unwrap();
+    let engine = m.value_of("engine").unwrap();
+    if m.is_present("verbose") {
+        eprintln!("Running '{}' on '{}'", engine, output.display());
+    }
+    let (success, log) = match run_engine(engine, m.value_of("engine-command"), &output, m.is_present("verbose")) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Could not run '{}': {}", engine, e);
+            return ReturnCode::EngineError as i32;
+        }
+    };
+
+    let dir = output.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let messages = map_messages(parse_log(&log), dir, &source_map);
+    for msg in &messages {
+        eprintln!("{}", msg);
+    }
+
+    if success && !messages.iter().any(|msg| msg.severity == diagnostics::Severity::Error) {
+        ReturnCode::Ok as i32
+    } else {
+        ReturnCode::EngineError as i32
+    }
+}
+
+fn rewrite_synctex_file(m: &clap::ArgMatches, path: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use error::IndentexError;
+    use references::indentex_source;
+    use source_map::SourceMap;
+    use synctex::{read_synctex, rewrite_synctex, synctex_inputs, write_synctex};
+    use transpile::transpile_path;
+
+    let text = match read_synctex(path) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Could not read '{}': {}", path.display(), e);
+            return ReturnCode::FileTypeError as i32;
+        }
+    };
+
+    // Transpile the sources again to recover the line correspondence
+    let mut ret_val = ReturnCode::Ok as i32;
+    let mut source_map = SourceMap::new();
+    for source in synctex_inputs(&text).iter().filter_map(|p| indentex_source(p)).filter(|p| p.is_file()) {
+        match transpile_path(&source, options) {
+            // `unwrap()` is safe here since the source has been derived from the output name
+            Ok(t) => source_map.add(&source, &t).unwrap(),
+            Err(e) => {
+                if let IndentexError::Transpilation(ref d) = e {
+                    print_diagnostics(&mut std::io::stdout(), &source, d);
+                }
+                println!("Could not transpile '{}': {}", source.display(), e);
+                ret_val = ReturnCode::TranspilationError as i32;
+            }
+        }
+    }
+
+    let output = m.value_of("output").map_or(path, std::path::Path::new);
+    if let Err(e) = write_synctex(output, &rewrite_synctex(&text, &source_map)) {
+        println!("Could not write '{}': {}", output.display(), e);
+        ret_val = std::cmp::max(ret_val, ReturnCode::TranspilationError as i32);
+    }
+
+    ret_val
+}
+
+fn bundle_file(m: &clap::ArgMatches, main: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use bundle::bundle;
+    use error::IndentexError;
+    use file_utils::write_to_file;
+    use std::io;
+
+    let output = m.value_of("output").unwrap();
+
+    match bundle(main, options, m.is_present("strip-comments")) {
+        Ok(b) => {
+            print_diagnostics(&mut io::stdout(), main, &b.diagnostics);
+            match write_to_file(output, &b.text) {
+                Ok(()) => {
+                    if m.is_present("verbose") {
+                        println!("Bundling file '{}' into '{}'... ok", main.display(), output);
+                    }
+                    ReturnCode::Ok as i32
+                }
+                Err(e) => {
+                    println!("Could not write '{}': {}", output, e);
+                    ReturnCode::TranspilationError as i32
+                }
+            }
+        }
+        Err(e) => {
+            if let IndentexError::Transpilation(ref d) = e {
+                print_diagnostics(&mut io::stdout(), main, d);
+            }
+            println!("Could not bundle '{}': {}", main.display(), e);
+            ReturnCode::TranspilationError as i32
+        }
+    }
+}
+
+fn pack_file(m: &clap::ArgMatches, main: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use error::IndentexError;
+    use pack::{collect_entries, write_archive};
+    use std::io;
+
+    let output = m.value_of("output").unwrap();
+
+    let packed = match collect_entries(main, options) {
+        Ok(p) => p,
+        Err(e) => {
+            if let IndentexError::Transpilation(ref d) = e {
+                print_diagnostics(&mut io::stdout(), main, d);
+            }
+            println!("Could not pack '{}': {}", main.display(), e);
+            return ReturnCode::TranspilationError as i32;
+        }
+    };
+    print_diagnostics(&mut io::stdout(), main, &packed.diagnostics);
+
+    match write_archive(&packed.entries, output) {
+        Ok(()) => {
+            if m.is_present("verbose") {
+                for e in &packed.entries {
+                    println!("Packing '{}'", e.name());
+                }
+            }
+            ReturnCode::Ok as i32
+        }
+        Err(e) => {
+            println!("Could not write '{}': {}", output, e);
+            ReturnCode::TranspilationError as i32
+        }
+    }
+}
+
+// Print the syntax tree of a single file
+fn print_syntax_tree(m: &clap::ArgMatches, path: &std::path::Path) -> i32 {
+    use file_utils::read_and_trim_lines;
+
+    let lines = match read_and_trim_lines(path) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Could not read '{}': {}", path.display(), e);
+            return ReturnCode::FileTypeError as i32;
+        }
+    };
+
+    let tree = ast::parse(&lines);
+    if m.is_present("json") {
+        // `unwrap()` is safe here since the tree only contains strings and integers
+        println!("{}", serde_json::to_string_pretty(&tree).unwrap());
+    } else {
+        print!("{}", tree.to_text());
+    }
+    ReturnCode::Ok as i32
+}
+
+// Format a single file or all indentex files in a directory, or only check them
+fn format_files(m: &clap::ArgMatches, path: &std::path::Path, options: &transpile::TranspileOptions) -> i32 {
+    use file_utils::write_to_file;
+    use format::format_source;
+    use rayon::prelude::*;
+    use std::cmp;
+    use std::fs;
+    use std::io;
+
+    let verbose = m.is_present("verbose");
+    let check = m.is_present("check");
+    // `unwrap()` is safe here since the value has already been validated
+    let width = value_t!(m, "indent-width", usize).unwrap();
+    let (batch, ret_val) = collect_batch(path);
+
+    let ret_val_formatting = batch.par_iter()
+        .map(|p| {
+            let text = match fs::read_to_string(p) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("Could not read '{}': {}", p.display(), e);
+                    return ReturnCode::FileTypeError as i32;
+                }
+            };
+            let formatted = match format_source(&text, width, Some(p), options) {
+                Ok(f) => f,
+                Err(d) => {
+                    print_diagnostics(&mut io::stdout(), p, &[d]);
+                    println!("Could not format '{}'", p.display());
+                    return ReturnCode::TranspilationError as i32;
+                }
+            };
+
+            if formatted == text {
+                if verbose {
+                    println!("Formatting file '{}'... unchanged", p.display());
+                }
+                ReturnCode::Ok as i32
+            } else if check {
+                println!("File '{}' is not formatted", p.display());
+                ReturnCode::Unformatted as i32
+            } else {
+                match write_to_file(p, &formatted) {
+                    Ok(()) => {
+                        if verbose {
+                            println!("Formatting file '{}'... ok", p.display());
+                        }
+                        ReturnCode::Ok as i32
+                    }
+                    Err(e) => {
+                        println!("Could not write '{}': {}", p.display(), e);
+                        ReturnCode::TranspilationError as i32
+                    }
+                }
+            }
+        })
         .max()
         .unwrap_or(ReturnCode::Ok as i32);
 
-    process::exit(cmp::max(ret_val, ret_val_transpilation));
+    cmp::max(ret_val, ret_val_formatting)
+}
+
+// Lint a single file or all indentex files in a directory
+fn lint_files(m: &clap::ArgMatches,
+              path: &std::path::Path,
+              options: &transpile::TranspileOptions,
+              mut config: lint::LintConfig)
+              -> i32 {
+    use file_utils::read_and_trim_lines;
+    use lint::{lint, Level, Rule};
+    use rayon::prelude::*;
+    use std::cmp;
+    use std::io;
+
+    for setting in m.values_of("rule").into_iter().flatten() {
+        // `unwrap()` is safe here since the values have already been validated
+        let i = setting.find('=').unwrap();
+        config.rules.insert(Rule::from_id(&setting[..i]).unwrap(), Level::from_name(&setting[i + 1..]).unwrap());
+    }
+    if m.is_present("max-depth") {
+        config.max_depth = value_t!(m, "max-depth", usize).unwrap();
+    }
+
+    let verbose = m.is_present("verbose");
+    let (batch, ret_val) = collect_batch(path);
+
+    let ret_val_lint = batch.par_iter()
+        .map(|p| {
+            let lines = match read_and_trim_lines(p) {
+                Ok(l) => l,
+                Err(e) => {
+                    println!("Could not read '{}': {}", p.display(), e);
+                    return ReturnCode::FileTypeError as i32;
+                }
+            };
+            let linted = lint(&lines, Some(p), options, &config);
+            print_diagnostics(&mut io::stdout(), p, &linted.diagnostics);
+            for f in &linted.findings {
+                let file = f.diagnostic.file.as_ref().map_or(p.as_path(), |f| f.as_path());
+                println!("{}:{}", file.display(), f);
+            }
+
+            let ret_val_file = if linted.diagnostics.iter().any(diagnostics::Diagnostic::is_error) {
+                ReturnCode::TranspilationError
+            } else if linted.findings.iter().any(|f| f.diagnostic.is_error()) {
+                ReturnCode::LintError
+            } else {
+                ReturnCode::Ok
+            };
+            if verbose {
+                let result = if let ReturnCode::Ok = ret_val_file { "ok" } else { "failed" };
+                println!("Linting file '{}'... {}", p.display(), result);
+            }
+            ret_val_file as i32
+        })
+        .max()
+        .unwrap_or(ReturnCode::Ok as i32);
+
+    cmp::max(ret_val, ret_val_lint)
+}
+
+// Serve the Language Server Protocol on stdin and stdout
+fn serve_lsp(options: &transpile::TranspileOptions) -> i32 {
+    use std::io;
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match lsp::run(&mut stdin.lock(), &mut stdout.lock(), options) {
+        Ok(()) => ReturnCode::Ok as i32,
+        Err(e) => {
+            eprintln!("Language server failed: {}", e);
+            ReturnCode::TranspilationError as i32
+        }
+    }
+}
+
+fn main() {
+    use source_map::SourceMap;
+    use std::cmp;
+    use std::io;
+    use std::path::Path;
+    use std::process;
+
+    let m = build_cli().get_matches();
+
+    let ret_val = match m.subcommand() {
+        ("build-graph", Some(sub_m)) => {
+            let main = Path::new(sub_m.value_of("main").unwrap());
+            let (options, ret_val) = load_options(sub_m, main, &mut io::stdout());
+            cmp::max(ret_val, transpile_graph(sub_m, main, &options, &mut SourceMap::new()))
+        }
+        ("build", Some(sub_m)) => {
+            let main = Path::new(sub_m.value_of("main").unwrap());
+            let (options, ret_val) = load_options(sub_m, main, &mut io::stdout());
+            cmp::max(ret_val, build_document(sub_m, main, &options))
+        }
+        ("synctex-rewrite", Some(sub_m)) => {
+            let path = Path::new(sub_m.value_of("synctex").unwrap());
+            let (options, ret_val) = load_options(sub_m, path, &mut io::stdout());
+            cmp::max(ret_val, rewrite_synctex_file(sub_m, path, &options))
+        }
+        ("bundle", Some(sub_m)) => {
+            let main = Path::new(sub_m.value_of("main").unwrap());
+            let (options, ret_val) = load_options(sub_m, main, &mut io::stdout());
+            cmp::max(ret_val, bundle_file(sub_m, main, &options))
+        }
+        ("pack", Some(sub_m)) => {
+            let main = Path::new(sub_m.value_of("main").unwrap());
+            let (options, ret_val) = load_options(sub_m, main, &mut io::stdout());
+            cmp::max(ret_val, pack_file(sub_m, main, &options))
+        }
+        ("fmt", Some(sub_m)) => {
+            let path = Path::new(sub_m.value_of("path").unwrap());
+            let (options, ret_val) = load_options(sub_m, path, &mut io::stdout());
+            cmp::max(ret_val, format_files(sub_m, path, &options))
+        }
+        ("lint", Some(sub_m)) => {
+            let path = Path::new(sub_m.value_of("path").unwrap());
+            let (config, ret_val) = load_config(sub_m, path, &mut io::stdout());
+            let lint_config = config.lint.clone();
+            let (options, ret_val_options) = build_options(sub_m, path, config, &mut io::stdout());
+            cmp::max(cmp::max(ret_val, ret_val_options), lint_files(sub_m, path, &options, lint_config))
+        }
+        ("parse", Some(sub_m)) => print_syntax_tree(sub_m, Path::new(sub_m.value_of("file").unwrap())),
+        ("lsp", Some(sub_m)) => {
+            // Messages go to stderr since stdout carries the protocol
+            let (options, ret_val) = load_options(sub_m, Path::new("."), &mut io::stderr());
+            cmp::max(ret_val, serve_lsp(&options))
+        }
+        _ => {
+            let path = Path::new(m.value_of("path").unwrap());
+            let (options, ret_val) = load_options(&m, path, &mut io::stdout());
+            cmp::max(ret_val, transpile_all(&m, path, &options))
+        }
+    };
+
+    process::exit(ret_val);
 }
@@ -0,0 +1,189 @@
+use std::collections::BTreeSet;
+#[cfg(feature = "cli")]
+use std::path::Path;
+use std::vec::Vec;
+#[cfg(feature = "cli")]
+use error::IndentexError;
+
+
+// Environments of LaTeX, common packages and the list-like and row-based ones known to the parsers
+const BUILTIN_ENVIRONMENTS: [&str; 67] = [
+    "abstract", "algorithm", "algorithmic", "align", "alignat", "aligned", "alignedat", "alertblock", "appendices",
+    "array", "axis", "block", "bmatrix", "Bmatrix", "cases", "center", "column", "columns", "comment", "description",
+    "displaymath", "document", "enumerate", "eqnarray", "equation", "exampleblock", "figure", "filecontents",
+    "flalign", "flushleft", "flushright", "frame", "gather", "gathered", "itemize", "landscape", "letter", "list",
+    "longtable", "lrbox", "lstlisting", "math", "matrix", "minipage", "minted", "multicols", "multline", "picture",
+    "pmatrix", "proof", "quotation", "quote", "smallmatrix", "split", "subequations", "subfigure", "tabbing",
+    "table", "tabular", "tabularx", "thebibliography", "theindex", "tikzpicture", "titlepage", "trivlist",
+    "verbatim", "verse",
+];
+// Commands usually written as hashlines, the list cannot be complete
+const BUILTIN_COMMANDS: [&str; 76] = [
+    "addbibresource", "alert", "author", "autoref", "bibliography", "bibliographystyle", "caption", "centering",
+    "chapter", "cite", "citep", "citet", "clearpage", "color", "colorbox", "cref", "Cref", "date", "documentclass",
+    "emph", "eqref", "fbox", "footnote", "footnotetext", "frac", "framebox", "framesubtitle", "frametitle", "hbox",
+    "href", "hspace", "include", "includegraphics", "includeonly", "input", "item", "label", "lstinputlisting",
+    "makebox", "maketitle", "marginpar", "mathbb", "mathbf", "mathcal", "mathit", "mathrm", "mbox", "multicolumn",
+    "multirow", "nameref", "newpage", "only", "operatorname", "pageref", "pagestyle", "paragraph", "parbox", "part",
+    "raisebox", "ref", "section", "setcounter", "setlength", "subfile", "subparagraph", "subsection",
+    "subsubsection", "textbf", "textcolor", "textit", "textsc", "textsf", "texttt", "title", "underline",
+    "usepackage",
+];
+// Definitions followed by the name of the environment in braces
+const ENVIRONMENT_DEFINITIONS: [&str; 7] = ["newenvironment", "renewenvironment", "newtheorem",
+                                            "NewDocumentEnvironment", "RenewDocumentEnvironment",
+                                            "ProvideDocumentEnvironment", "DeclareDocumentEnvironment"];
+// Definitions followed by the control sequence, optionally in braces
+const COMMAND_DEFINITIONS: [&str; 11] = ["newcommand", "renewcommand", "providecommand", "DeclareRobustCommand",
+                                         "NewDocumentCommand", "RenewDocumentCommand", "ProvideDocumentCommand",
+                                         "DeclareDocumentCommand", "DeclareMathOperator", "def", "let"];
+const MAX_DISTANCE: usize = 2;
+
+
+/// Environments and commands which exist, to spot typos in hashline names
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KnownNames {
+    environments: BTreeSet<String>,
+    commands: BTreeSet<String>,
+}
+
+impl KnownNames {
+    pub fn builtin() -> KnownNames {
+        KnownNames {
+            environments: BUILTIN_ENVIRONMENTS.iter().map(|e| e.to_string()).collect(),
+            commands: BUILTIN_COMMANDS.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Built-in names and the ones defined in the LaTeX and indentex files below `dir`
+    #[cfg(feature = "cli")]
+    pub fn from_project<T: AsRef<Path>>(dir: T) -> Result<KnownNames, IndentexError> {
+        use file_utils::walk_latex_files;
+        use std::fs;
+
+        let mut names = KnownNames::builtin();
+        for path in walk_latex_files(dir)? {
+            // Definitions are ASCII, hence other encodings do not matter
+            names.harvest(&String::from_utf8_lossy(&fs::read(path)?));
+        }
+        Ok(names)
+    }
+
+    /// Add the environments and commands defined in LaTeX code
+    pub fn harvest(&mut self, text: &str) {
+        let mut rest = text;
+        while let Some(i) = rest.find('\\') {
+            rest = &rest[i + 1..];
+            let len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+            let (command, after) = rest.split_at(len);
+            // Skip the star of `\newtheorem*` and the like
+            let after = after.strip_prefix('*').unwrap_or(after).trim_start();
+
+            if ENVIRONMENT_DEFINITIONS.contains(&command) {
+                if let Some(name) = after.strip_prefix('{').and_then(|a| a.find('}').map(|e| &a[..e])) {
+                    self.environments.insert(name.trim().to_string());
+                }
+            } else if COMMAND_DEFINITIONS.contains(&command) {
+                let defined = after.strip_prefix('{').unwrap_or(after).trim_start();
+                if let Some(name) = defined.strip_prefix('\\') {
+                    let len = name.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(name.len());
+                    if len > 0 {
+                        self.commands.insert(name[..len].to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_environment(&self, name: &str) -> bool {
+        self.environments.contains(without_star(name))
+    }
+
+    pub fn is_command(&self, name: &str) -> bool {
+        self.commands.contains(without_star(name))
+    }
+
+    pub fn suggest_environment(&self, name: &str) -> Option<&str> {
+        suggest(without_star(name), &self.environments)
+    }
+
+    pub fn suggest_command(&self, name: &str) -> Option<&str> {
+        suggest(without_star(name), &self.commands)
+    }
+}
+
+// Starred variants exist for most environments and commands
+fn without_star(name: &str) -> &str {
+    name.strip_suffix('*').unwrap_or(name)
+}
+
+// The closest name, unless it is too different to be a typo
+fn suggest<'a>(name: &str, known: &'a BTreeSet<String>) -> Option<&'a str> {
+    let len = name.chars().count();
+    known.iter()
+        .map(|k| (edit_distance(name, k), k))
+        .filter(|&(d, _)| d <= MAX_DISTANCE && 2 * d < len)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, k)| k.as_str())
+}
+
+// Levenshtein distance counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn edit_distance() {
+        use super::edit_distance;
+
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("equaton", "equation"), 1);
+        assert_eq!(edit_distance("cneter", "center"), 2);
+        assert_eq!(edit_distance("ä", "a"), 1);
+        assert_eq!(edit_distance("figure", ""), 6);
+    }
+
+    #[test]
+    fn builtin() {
+        use super::KnownNames;
+
+        let names = KnownNames::builtin();
+        assert!(names.is_environment("itemize"));
+        assert!(names.is_environment("align*"));
+        assert!(!names.is_environment("equaton"));
+        assert!(names.is_command("section*"));
+        assert_eq!(names.suggest_environment("equaton"), Some("equation"));
+        assert_eq!(names.suggest_environment("figur*"), Some("figure"));
+        assert_eq!(names.suggest_command("sectoin"), Some("section"));
+        // Too short or too different
+        assert_eq!(names.suggest_command("xy"), None);
+        assert_eq!(names.suggest_environment("mytheorem"), None);
+    }
+
+    #[test]
+    fn harvest() {
+        use super::KnownNames;
+
+        let mut names = KnownNames::default();
+        names.harvest(r"\newenvironment{note}{}{} \newtheorem*{lemma}{Lemma} \NewDocumentEnvironment {proofsketch}{}
+                        \newcommand{\R}{\mathbb{R}} \newcommand*\abs[1]{|#1|} \def\eps{\varepsilon}
+                        \DeclareMathOperator{\argmax}{arg\,max} \newcommandx{\no}{} \newcommand{\1}{}");
+        assert_eq!(names.environments.iter().collect::<Vec<_>>(), ["lemma", "note", "proofsketch"]);
+        assert_eq!(names.commands.iter().collect::<Vec<_>>(), ["R", "abs", "argmax", "eps"]);
+    }
+}
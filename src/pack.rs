@@ -0,0 +1,356 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use error::IndentexError;
+use transpile::{SourceLine, TranspileOptions};
+
+
+// Tried in this order by graphicx if the extension is omitted (pdfTeX defaults), a given one is used as is
+const GRAPHICS_EXTENSIONS: [&str; 14] = [".pdf", ".png", ".jpg", ".mps", ".jpeg", ".jbig2", ".jb2", ".PDF", ".PNG",
+                                         ".JPG", ".JPEG", ".JBIG2", ".JB2", ".eps"];
+const GRAPHICS_PATH_COMMAND: &str = "graphicspath";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Asset {
+    Graphics,
+    // `\bibliography` takes a list of `.bib` files without extension
+    Bibliography,
+    // `\addbibresource` takes a file name with extension
+    Resource,
+    // Classes and packages are only packed if they are found in the project
+    Class,
+    Package,
+}
+
+const ASSET_COMMANDS: [(&str, Asset); 5] = [("includegraphics", Asset::Graphics),
+                                            ("bibliography", Asset::Bibliography),
+                                            ("addbibresource", Asset::Resource),
+                                            ("documentclass", Asset::Class),
+                                            ("usepackage", Asset::Package)];
+
+// A file in the archive, named relative to the project root
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    File { name: String, path: PathBuf },
+    // Transpiled LaTeX, which need not exist on disk
+    Generated { name: String, text: String },
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        match *self {
+            Entry::File { ref name, .. } | Entry::Generated { ref name, .. } => name,
+        }
+    }
+}
+
+pub struct Packed {
+    pub entries: Vec<Entry>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Collect the transpiled sources reachable from the main file and all local files they use
+///
+/// The project root is the directory of the main file, files outside of it are refused.
+pub fn collect_entries<T: AsRef<Path>>(main: T, options: &TranspileOptions) -> Result<Packed, IndentexError> {
+    use file_utils::{read_and_trim_lines_unchecked, rename_indentex_file};
+    use graph::{build_graph, Visited};
+
+    let main = main.as_ref();
+    let root = main.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    let canonical_root = if root.as_os_str().is_empty() { Path::new(".") } else { root.as_path() }.canonicalize()?;
+
+    let (_, visited) = build_graph(main, options);
+    let mut diagnostics = Vec::new();
+    let mut entries = Vec::new();
+    let mut latex = Vec::new();
+
+    for (path, v) in visited {
+        let with_file = |d: &Diagnostic| Diagnostic { file: d.file.clone().or_else(|| Some(path.clone())), ..d.clone() };
+        match v {
+            Visited::Indentex(Ok(t)) => {
+                diagnostics.extend(t.diagnostics.iter().map(with_file));
+                // Generated lines are located in the sources, the outputs need not exist
+                latex.push(t.source_lines(&path));
+                entries.push((rename_indentex_file(&path)?, Some(t.text)));
+            }
+            Visited::Indentex(Err(IndentexError::Transpilation(d))) => {
+                diagnostics.extend(d.iter().map(with_file));
+            }
+            Visited::Latex(Ok(d)) => {
+                diagnostics.extend(d.iter().map(with_file));
+                let lines = read_and_trim_lines_unchecked(&path)?;
+                latex.push(SourceLine::number_in_file(&lines, Some(Arc::new(path.clone()))));
+                entries.push((path, None));
+            }
+            Visited::Indentex(Err(e)) | Visited::Latex(Err(e)) => return Err(e),
+        }
+    }
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(IndentexError::Transpilation(diagnostics));
+    }
+
+    // The bibliography processed by BibTeX belongs to the main file
+    if let Some((output, _)) = entries.first() {
+        let bbl = output.with_extension("bbl");
+        if bbl.is_file() {
+            entries.push((bbl, None));
+        }
+    }
+    entries.extend(scan_assets(&latex, &root, &mut diagnostics).into_iter().map(|p| (p, None)));
+
+    let mut packed = Packed { entries: Vec::new(), diagnostics };
+    for (path, text) in entries {
+        let name = archive_name(&path, &canonical_root).ok_or_else(|| IndentexError::OutsideRoot(path.clone()))?;
+        if packed.entries.iter().any(|e| e.name() == name) {
+            continue;
+        }
+        packed.entries.push(match text {
+            Some(text) => Entry::Generated { name, text },
+            None => Entry::File { name, path },
+        });
+    }
+
+    Ok(packed)
+}
+
+/// Write the entries into a gzipped tarball
+pub fn write_archive<T: AsRef<Path>>(entries: &[Entry], path: T) -> Result<(), IndentexError> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs::File;
+    use tar::{Builder, Header, HeaderMode};
+
+    let encoder = GzEncoder::new(File::create(path.as_ref())?, Compression::default());
+    let mut builder = Builder::new(encoder);
+    // Packing the same files twice should yield the same archive
+    builder.mode(HeaderMode::Deterministic);
+
+    for e in entries {
+        match *e {
+            Entry::File { ref name, ref path } => builder.append_path_with_name(path, name)?,
+            Entry::Generated { ref name, ref text } => {
+                let mut header = Header::new_gnu();
+                header.set_size(text.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, text.as_bytes())?;
+            }
+        }
+    }
+    builder.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+// Path relative to the root with forward slashes, `None` if the file is outside of the root
+fn archive_name(path: &Path, canonical_root: &Path) -> Option<String> {
+    // Transpiled files need not exist yet
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let canonical = dir.canonicalize().ok()?.join(path.file_name()?);
+    let relative = canonical.strip_prefix(canonical_root).ok()?;
+    let components: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+    Some(components.join("/"))
+}
+
+// Find the local files used by the LaTeX files, paths are relative to the project root like in LaTeX
+fn scan_assets(latex: &[Vec<SourceLine>], root: &Path, diagnostics: &mut Vec<Diagnostic>) -> Vec<PathBuf> {
+    use transpile::strip_comment;
+
+    let mut references = Vec::new();
+    let mut graphics_dirs = vec![String::new()];
+
+    for line in latex.iter().flatten() {
+        let code = strip_comment(&line.text);
+        for arg in command_arguments(code, GRAPHICS_PATH_COMMAND) {
+            graphics_dirs.extend(arg.split(['{', '}'])
+                .filter(|d| !d.trim().is_empty())
+                .map(|d| d.trim().to_string()));
+        }
+        for &(command, asset) in &ASSET_COMMANDS {
+            for arg in command_arguments(code, command) {
+                let targets: Vec<&str> = match asset {
+                    Asset::Bibliography | Asset::Package => arg.split(',').map(str::trim).collect(),
+                    _ => vec![arg.trim()],
+                };
+                references.extend(targets.into_iter()
+                    .filter(|t| !t.is_empty())
+                    .map(|t| (t.to_string(), asset, line.clone())));
+            }
+        }
+    }
+
+    let mut assets = Vec::new();
+    for (target, asset, line) in references {
+        let candidates: Vec<PathBuf> = match asset {
+            Asset::Graphics => {
+                let extensions: &[&str] = match Path::new(&target).extension() {
+                    Some(_) => &[""],
+                    None => &GRAPHICS_EXTENSIONS,
+                };
+                let target = &target;
+                graphics_dirs.iter()
+                    .flat_map(|d| extensions.iter().map(move |e| root.join(format!("{}{}{}", d, target, e))))
+                    .collect()
+            }
+            Asset::Bibliography => vec![root.join(with_extension(&target, ".bib"))],
+            Asset::Resource => vec![root.join(&target)],
+            Asset::Class => vec![root.join(with_extension(&target, ".cls"))],
+            Asset::Package => vec![root.join(with_extension(&target, ".sty"))],
+        };
+
+        match candidates.into_iter().find(|c| c.is_file()) {
+            Some(path) => assets.push(path),
+            None => match asset {
+                // Most classes and packages come with the TeX distribution
+                Asset::Class | Asset::Package => {}
+                _ => diagnostics.push(Diagnostic::warning(&line, format!("could not find file '{}'", target))),
+            },
+        }
+    }
+
+    assets
+}
+
+#[inline]
+fn with_extension(target: &str, extension: &str) -> String {
+    if target.ends_with(extension) {
+        target.to_string()
+    } else {
+        format!("{}{}", target, extension)
+    }
+}
+
+// Mandatory arguments of all occurrences of `\command[opts]{arg}` (also the starred form)
+fn command_arguments<'a>(code: &'a str, command: &str) -> Vec<&'a str> {
+    let pattern = format!(r"\{}", command);
+    let mut arguments = Vec::new();
+    let mut from = 0;
+
+    while let Some(i) = code[from..].find(&pattern) {
+        let mut rest = &code[from + i + pattern.len()..];
+        from += i + pattern.len();
+        // Skip longer commands like `\bibliographystyle`
+        if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+        rest = rest.strip_prefix('*').unwrap_or(rest).trim_start();
+        if rest.starts_with('[') {
+            match rest.find(']') {
+                Some(e) => rest = rest[e + 1..].trim_start(),
+                None => continue,
+            }
+        }
+        if !rest.starts_with('{') {
+            continue;
+        }
+        // Arguments may contain groups, e.g. `\graphicspath{{figures/}}`
+        let mut depth = 0;
+        for (j, c) in rest.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        arguments.push(&rest[1..j]);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    arguments
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use test_utils::TempDir;
+    use transpile::TranspileOptions;
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("pack_{}", name));
+        fs::create_dir_all(dir.join("project/figs")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn command_arguments() {
+        use super::command_arguments;
+
+        assert_eq!(command_arguments(r"\includegraphics[width=2cm]{a} \includegraphics*{b}", "includegraphics"),
+                   ["a", "b"]);
+        assert_eq!(command_arguments(r"\graphicspath{{figs/}{img/}}", "graphicspath"), ["{figs/}{img/}"]);
+        assert!(command_arguments(r"\bibliographystyle{plain}", "bibliography").is_empty());
+    }
+
+    #[test]
+    fn collect_entries() {
+        use super::{collect_entries, Entry};
+
+        let tmp = temp_dir("collect");
+        let dir = tmp.join("project");
+        fs::write(dir.join("main.inden.tex"), "\\documentclass{local}\n\\usepackage{amsmath, mine}\n\
+                                               \\graphicspath{{figs/}}\n\\input{plain}\n\
+                                               \\bibliography{refs,missing}\n").unwrap();
+        fs::write(dir.join("plain.tex"),
+                  "\\includegraphics[width=1cm]{plot}\n% \\includegraphics{x}\n\\includegraphics{logo.eps}\n").unwrap();
+        // The bare name is only used if it has an extension, otherwise the extensions are tried in order
+        for f in &["local.cls", "mine.sty", "figs/plot", "figs/plot.eps", "figs/plot.png", "figs/logo.eps",
+                    "figs/logo.eps.pdf", "refs.bib", "main_indentex.bbl"] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+
+        let options = TranspileOptions { prepend_do_not_edit_notice: true, ..Default::default() };
+        let packed = collect_entries(dir.join("main.inden.tex"), &options).unwrap();
+        let names: Vec<_> = packed.entries.iter().map(Entry::name).collect();
+        assert_eq!(names, ["main_indentex.tex", "plain.tex", "main_indentex.bbl", "local.cls", "mine.sty",
+                           "refs.bib", "figs/plot.png", "figs/logo.eps"]);
+        // Missing files are reported in the source instead of the output with its notice
+        assert_eq!(packed.diagnostics.len(), 1);
+        let d = &packed.diagnostics[0];
+        assert!(d.message.contains("'missing'"));
+        assert_eq!((d.file.clone(), d.line), (Some(dir.join("main.inden.tex")), 5));
+    }
+
+    #[test]
+    fn refuse_outside_root() {
+        use super::collect_entries;
+        use error::IndentexError;
+
+        let dir = temp_dir("outside");
+        fs::write(dir.join("secret.tex"), "").unwrap();
+        fs::write(dir.join("project/main.inden.tex"), "\\input{../secret}\n").unwrap();
+
+        match collect_entries(dir.join("project/main.inden.tex"), &TranspileOptions::default()) {
+            Err(IndentexError::OutsideRoot(p)) => assert!(p.ends_with("secret.tex")),
+            _ => panic!("files outside of the project root must be refused"),
+        }
+    }
+
+    #[test]
+    fn write_archive() {
+        use super::{write_archive, Entry};
+        use flate2::read::GzDecoder;
+        use std::fs::File;
+        use tar::Archive;
+
+        let dir = temp_dir("archive");
+        fs::write(dir.join("project/figs/a.pdf"), "pdf").unwrap();
+        let entries = [Entry::Generated { name: "main_indentex.tex".to_string(), text: "main".to_string() },
+                       Entry::File { name: "figs/a.pdf".to_string(), path: dir.join("project/figs/a.pdf") }];
+        write_archive(&entries, dir.join("out.tar.gz")).unwrap();
+
+        let mut archive = Archive::new(GzDecoder::new(File::open(dir.join("out.tar.gz")).unwrap()));
+        let names: Vec<_> = archive.entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["main_indentex.tex", "figs/a.pdf"]);
+    }
+}
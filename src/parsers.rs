@@ -1,23 +1,82 @@
+use std::ops::Range;
 use nom;
+use nom::types::CompleteByteSlice;
+
+
+/// Environments whose rows are terminated with `\\` automatically, more are registered in `TranspileOptions`
+pub const ROW_ENVIRONMENTS: [&str; 15] = ["align", "align*", "alignat", "alignat*", "aligned", "array", "eqnarray",
+                                          "eqnarray*", "flalign", "flalign*", "gather", "gather*", "gathered",
+                                          "tabular", "tabular*"];
 
 
 #[derive(Debug, PartialEq)]
 pub enum Hashline {
     OpenEnv(Environment),
+    Command(Command),
+    Item(Item),
     PlainLine(String),
 }
 
-#[derive(Debug, PartialEq)]
+impl Hashline {
+    // LaTeX code of the line, the opening tag for environments
+    pub fn latex(&self) -> String {
+        match *self {
+            Hashline::OpenEnv(ref e) => e.latex_begin(),
+            Hashline::Command(ref c) => c.latex(),
+            Hashline::Item(ref i) => i.latex(),
+            Hashline::PlainLine(ref l) => l.clone(),
+        }
+    }
+}
+
+// Hashline split into its components, without any LaTeX-specific processing
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashlineParts {
+    pub indent_depth: usize,
+    pub name: String,
+    pub opts: String,
+    pub args: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Environment {
     indent_depth: usize,
     name: String,
     opts: String,
     comment: String,
     is_list_like: bool,
+    is_row_based: bool,
+    // Opened by `# begin` and closed by `# end` instead of by indentation
+    is_closed_explicitly: bool,
+    // Replace the tags of custom environments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    begin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
 }
 
 impl Environment {
+    // Environment opened by a hashline handler, rendered with the given tags
+    pub fn custom(parts: &HashlineParts, begin: String, end: String) -> Environment {
+        Environment {
+            indent_depth: parts.indent_depth,
+            name: parts.name.clone(),
+            opts: parts.opts.clone(),
+            comment: parts.comment.clone(),
+            is_list_like: false,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: Some(begin),
+            end: Some(end),
+        }
+    }
+
     pub fn latex_begin(&self) -> String {
+        if let Some(ref b) = self.begin {
+            return format!("{:ind$}{}", "", b, ind = self.indent_depth);
+        }
         format!(r"{dummy:ind$}\begin{{{name}}}{opts}{comment_sep}{comment}",
                 name = self.name,
                 opts = self.opts,
@@ -28,12 +87,19 @@ impl Environment {
     }
 
     pub fn latex_end(&self) -> String {
+        if let Some(ref e) = self.end {
+            return format!("{:ind$}{}", "", e, ind = self.indent_depth);
+        }
         format!(r"{dummy:ind$}\end{{{name}}}",
                 name = self.name,
                 dummy = "",
                 ind = self.indent_depth)
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn indent_depth(&self) -> usize {
         self.indent_depth
     }
@@ -41,21 +107,77 @@ impl Environment {
     pub fn is_list_like(&self) -> bool {
         self.is_list_like
     }
+
+    pub fn is_row_based(&self) -> bool {
+        self.is_row_based
+    }
+
+    pub fn is_closed_explicitly(&self) -> bool {
+        self.is_closed_explicitly
+    }
+
+    // Whether a hashline handler replaced the tags
+    pub fn is_custom(&self) -> bool {
+        self.begin.is_some()
+    }
+}
+
+
+// Single-line command, options and arguments are escaped for LaTeX
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Command {
+    pub indent_depth: usize,
+    pub name: String,
+    pub opts: String,
+    pub args: String,
+    pub comment: String,
+}
+
+impl Command {
+    pub fn latex(&self) -> String {
+        format!(r"{dummy:ind$}\{name}{opts}{{{args}}}{comment_sep}{comment}",
+                name = self.name,
+                opts = self.opts,
+                args = self.args,
+                comment = self.comment,
+                dummy = "",
+                ind = self.indent_depth,
+                comment_sep = if self.comment.is_empty() { "" } else { " " })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Item {
+    pub indent_depth: usize,
+    pub text: String,
+}
+
+impl Item {
+    pub fn latex(&self) -> String {
+        format!(r"{dummy:ind$}\item{item_sep}{text}",
+                text = self.text,
+                dummy = "",
+                ind = self.indent_depth,
+                item_sep = if self.text.is_empty() { "" } else { " " })
+    }
 }
 
 
 // Hashline parsers
+// Lines are complete, hence all parsers work on `CompleteByteSlice`
 named!(
-    list_env_parser<&[u8]>,
+    list_env_parser<CompleteByteSlice, CompleteByteSlice>,
     ws!(alt!(tag!("itemize") | tag!("enumerate") | tag!("description")))
 );
-named!(escaped_colon<u8>, preceded!(specific_byte!(b'\\'), specific_byte!(b':')));
-named!(escaped_percent<u8>, preceded!(specific_byte!(b'\\'), specific_byte!(b'%')));
-named!(name_parser<u8>, alt!(escaped_colon | none_of_bytes_as_bytes!(b":%([{ \t")));
-named!(opts_parser<u8>, alt!(escaped_colon | escaped_percent | none_of_bytes_as_bytes!(b":%")));
-named!(args_parser<u8>, alt!(escaped_percent | none_of_bytes_as_bytes!(b"%")));
+named!(escaped_colon<CompleteByteSlice, u8>, preceded!(specific_byte!(b'\\'), specific_byte!(b':')));
+named!(escaped_percent<CompleteByteSlice, u8>, preceded!(specific_byte!(b'\\'), specific_byte!(b'%')));
+named!(name_parser<CompleteByteSlice, u8>, alt!(escaped_colon | none_of_bytes_as_bytes!(b":%([{ \t")));
+named!(opts_parser<CompleteByteSlice, u8>, alt!(escaped_colon | escaped_percent | none_of_bytes_as_bytes!(b":%")));
+named!(args_parser<CompleteByteSlice, u8>, alt!(escaped_percent | none_of_bytes_as_bytes!(b"%")));
 named!(
-    hashline_parser<Hashline>,
+    hashline_parser<CompleteByteSlice, (CompleteByteSlice, Vec<u8>, Vec<u8>, Vec<u8>, CompleteByteSlice)>,
     do_parse!(
         ws: opt!(is_a!(" ")) >>
         tag!("# ") >>
@@ -64,13 +186,48 @@ named!(
         tag!(":") >>
         args: many0!(args_parser) >>
         comment: call!(nom::rest) >>
-        (hashline_helper(ws.unwrap_or(&b""[..]), &name, &opts, &args, comment))
+        ((ws.unwrap_or(CompleteByteSlice(b"")), name, opts, args, comment))
+    )
+);
+named!(
+    hashline_sections_parser<CompleteByteSlice, (CompleteByteSlice, CompleteByteSlice, CompleteByteSlice)>,
+    do_parse!(
+        head: recognize!(tuple!(opt!(is_a!(" ")), tag!("# "), many1!(name_parser))) >>
+        opts: recognize!(many0!(opts_parser)) >>
+        tag!(":") >>
+        args: recognize!(many0!(args_parser)) >>
+        ((head, opts, args))
+    )
+);
+// `# begin name [opts]`, which opens an environment closed by `# end` instead of by indentation
+named!(
+    begin_parser<CompleteByteSlice, (CompleteByteSlice, Vec<u8>, Vec<u8>, CompleteByteSlice)>,
+    do_parse!(
+        ws: opt!(is_a!(" ")) >>
+        tag!("# begin ") >>
+        name: many1!(name_parser) >>
+        opts: many0!(opts_parser) >>
+        not!(tag!(":")) >>
+        comment: call!(nom::rest) >>
+        ((ws.unwrap_or(CompleteByteSlice(b"")), name, opts, comment))
+    )
+);
+// `# end`, optionally followed by the name of the environment and a comment
+named!(
+    end_parser<CompleteByteSlice, Option<Vec<u8>>>,
+    do_parse!(
+        opt!(is_a!(" ")) >>
+        tag!("# end") >>
+        name: opt!(preceded!(is_a!(" "), many1!(name_parser))) >>
+        opt!(is_a!(" ")) >>
+        peek!(alt!(eof!() | tag!("%"))) >>
+        (name)
     )
 );
 #[inline]
 fn hashline_helper(ws: &[u8], name: &[u8], opts: &[u8], args: &[u8], comment: &[u8]) -> Hashline {
     use std::str::from_utf8;
-    use self::Hashline::{PlainLine, OpenEnv};
+    use self::Hashline::OpenEnv;
 
     // It is ok to unwrap here, since we have checked for UTF-8 when we read the file
     let name_utf8 = from_utf8(name).unwrap().trim();
@@ -85,67 +242,144 @@ fn hashline_helper(ws: &[u8], name: &[u8], opts: &[u8], args: &[u8], comment: &[
             name: name_utf8.to_string(),
             opts: opts_utf8.to_string(),
             comment: comment_utf8.to_string(),
-            is_list_like: list_env_parser(name).is_done(),
+            is_list_like: list_env_parser(CompleteByteSlice(name)).is_ok(),
+            is_row_based: ROW_ENVIRONMENTS.contains(&name_utf8),
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
         OpenEnv(env)
     } else {
         // If there are some args, it's a single-line command
-        let ws_utf8 = from_utf8(ws).unwrap();
-        PlainLine(format!(r"{indent}\{name}{opts}{{{args}}}{comment_sep}{comment}",
-                          indent = ws_utf8,
-                          name = name_utf8,
-                          opts = opts_utf8,
-                          args = args_utf8,
-                          comment_sep = if comment_utf8.is_empty() { "" } else { " " },
-                          comment = comment_utf8))
+        Hashline::Command(Command {
+            indent_depth: ws.len(),
+            name: name_utf8.to_string(),
+            opts: opts_utf8,
+            args: args_utf8,
+            comment: comment_utf8.to_string(),
+        })
     }
 }
 
 // Hashline processing
 #[inline]
 fn process_hashline<T: AsRef<str>>(line: T) -> Option<Hashline> {
-    use nom::IResult::{Done, Error, Incomplete};
+    if let Ok((_, (ws, name, opts, comment))) = begin_parser(CompleteByteSlice(line.as_ref().as_bytes())) {
+        return match hashline_helper(&ws, &name, &opts, b"", &comment) {
+            Hashline::OpenEnv(env) => Some(Hashline::OpenEnv(Environment { is_closed_explicitly: true, ..env })),
+            _ => None,
+        };
+    }
+    match hashline_parser(CompleteByteSlice(line.as_ref().as_bytes())) {
+        Ok((_, (ws, name, opts, args, comment))) => Some(hashline_helper(&ws, &name, &opts, &args, &comment)),
+        Err(_) => None,
+    }
+}
+
+pub fn parse_hashline<T: AsRef<str>>(line: T) -> Option<HashlineParts> {
+    use std::str::from_utf8;
+
+    match hashline_parser(CompleteByteSlice(line.as_ref().as_bytes())) {
+        Ok((_, (ws, name, opts, args, comment))) => Some(HashlineParts {
+            indent_depth: ws.len(),
+            name: from_utf8(&name).unwrap().trim().to_string(),
+            opts: from_utf8(&opts).unwrap().trim().to_string(),
+            args: from_utf8(&args).unwrap().trim().to_string(),
+            comment: from_utf8(&comment).unwrap().trim().to_string(),
+        }),
+        Err(_) => None,
+    }
+}
+
+/// Name of the environment closed by an `# end` line, which is empty for a bare `# end`
+pub fn parse_closer<T: AsRef<str>>(line: T) -> Option<String> {
+    use std::str::from_utf8;
+
+    match end_parser(CompleteByteSlice(line.as_ref().as_bytes())) {
+        Ok((_, name)) => Some(name.map_or_else(String::new, |n| from_utf8(&n).unwrap().to_string())),
+        Err(_) => None,
+    }
+}
 
-    match hashline_parser(line.as_ref().as_bytes()) {
-        Done(_, r) => Some(r),
-        Error(_) | Incomplete(_) => None,
+/// Byte ranges of the untrimmed options and arguments of a hashline
+pub fn hashline_ranges<T: AsRef<str>>(line: T) -> Option<(Range<usize>, Range<usize>)> {
+    match hashline_sections_parser(CompleteByteSlice(line.as_ref().as_bytes())) {
+        Ok((_, (head, opts, args))) => {
+            let opts_end = head.len() + opts.len();
+            // Skip the colon
+            Some((head.len()..opts_end, opts_end + 1..opts_end + 1 + args.len()))
+        }
+        Err(_) => None,
     }
 }
 
+/// Suggest a hashline for a line which almost is one, e.g. `#section: Foo` or `# section Foo`
+///
+/// Only lines with `#` followed by a letter are considered, which leaves out macro parameters.
+pub fn suggest_hashline<T: AsRef<str>>(line: T) -> Option<String> {
+    let line = line.as_ref();
+    if parse_hashline(line).is_some() {
+        return None;
+    }
+    let indent = &line[..line.len() - line.trim_start_matches(' ').len()];
+    let named = line[indent.len()..].strip_prefix('#')?.trim_start_matches([' ', '\t']);
+    if !named.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    // The separator is not a single space
+    let candidate = format!("{}# {}", indent, named);
+    if parse_hashline(&candidate).is_some() {
+        return Some(candidate);
+    }
+
+    // The colon is missing, insert it after the name and any options
+    let mut end = named.find(|c: char| c.is_whitespace() || ":%([{".contains(c)).unwrap_or(named.len());
+    loop {
+        let opts = named[end..].trim_start();
+        let close = match opts.chars().next() {
+            Some('[') => ']',
+            Some('(') => ')',
+            Some('{') => '}',
+            _ => break,
+        };
+        match opts.find(close) {
+            Some(i) => end = named.len() - opts.len() + i + 1,
+            None => break,
+        }
+    }
+    // A comment right after the name leaves the intention unclear
+    if named[end..].trim_start().starts_with('%') {
+        return None;
+    }
+    let candidate = format!("{}# {}:{}", indent, &named[..end], &named[end..]);
+    parse_hashline(&candidate).map(|_| candidate)
+}
+
 
 // Itemline parsers
 named!(
-    itemline_parser<Hashline>,
+    itemline_parser<CompleteByteSlice, Hashline>,
     do_parse!(
         ws: opt!(is_a!(" ")) >>
         tag!("*") >>
         item: call!(nom::rest) >>
-        (itemline_helper(ws.unwrap_or(&b""[..]), item))
+        (itemline_helper(&ws.unwrap_or(CompleteByteSlice(b"")), &item))
     )
 );
 #[inline]
 fn itemline_helper(ws: &[u8], item: &[u8]) -> Hashline {
     use std::str::from_utf8;
-    use self::Hashline::PlainLine;
 
-    let ws_utf8 = from_utf8(ws).unwrap();
     let item_utf8 = from_utf8(item).unwrap().trim();
 
-    PlainLine(format!(r"{indent}\item{item_sep}{content}",
-                      indent = ws_utf8,
-                      content = item_utf8,
-                      item_sep = if item_utf8.is_empty() { "" } else { " " }))
+    Hashline::Item(Item { indent_depth: ws.len(), text: item_utf8.to_string() })
 }
 
 // Itemline processing
 #[inline]
 fn process_itemline<T: AsRef<str>>(line: T) -> Option<Hashline> {
-    use nom::IResult::{Done, Error, Incomplete};
-
-    match itemline_parser(line.as_ref().as_bytes()) {
-        Done(_, r) => Some(r),
-        Error(_) | Incomplete(_) => None,
-    }
+    itemline_parser(CompleteByteSlice(line.as_ref().as_bytes())).ok().map(|(_, r)| r)
 }
 
 // Fully process line
@@ -164,8 +398,8 @@ pub fn process_line<T>(line: T, list_like_active: bool) -> Hashline
 
 #[cfg(test)]
 mod tests {
-    use nom::IResult::{Done, Error, Incomplete};
-    use nom::{ErrorKind, Needed};
+    use nom::{Err, ErrorKind};
+    use nom::types::CompleteByteSlice;
 
     macro_rules! nil { () => ("".as_bytes()); }
     macro_rules! ws_1 { () => (" ".as_bytes()); }
@@ -176,17 +410,23 @@ mod tests {
     macro_rules! bar { () => ("bar".as_bytes()); }
     macro_rules! qux { () => ("qux".as_bytes()); }
     macro_rules! itemize { () => ("itemize".as_bytes()); }
+    macro_rules! align { () => ("align*".as_bytes()); }
+
+    macro_rules! cbs { ($s:expr) => (CompleteByteSlice(&$s[..])); }
+    macro_rules! parse_error {
+        ($i:expr, $kind:ident) => (Err(Err::Error(error_position!(cbs!($i), ErrorKind::$kind))));
+    }
 
     #[test]
     fn hashline_helper_plain_lines() {
-        use super::{Hashline, hashline_helper};
-
-        assert_eq!(hashline_helper(nil!(), foo!(), nil!(), bar!(), nil!()),
-                   Hashline::PlainLine("\\foo{bar}".to_string()));
-        assert_eq!(hashline_helper(ws_2!(), foo!(), nil!(), bar!(), qux!()),
-                   Hashline::PlainLine("  \\foo{bar} qux".to_string()));
-        assert_eq!(hashline_helper(ws_4!(), foo!(), bar!(), qux!(), nil!()),
-                   Hashline::PlainLine("    \\foobar{qux}".to_string()));
+        use super::hashline_helper;
+
+        assert_eq!(hashline_helper(nil!(), foo!(), nil!(), bar!(), nil!()).latex(),
+                   "\\foo{bar}");
+        assert_eq!(hashline_helper(ws_2!(), foo!(), nil!(), bar!(), qux!()).latex(),
+                   "  \\foo{bar} qux");
+        assert_eq!(hashline_helper(ws_4!(), foo!(), bar!(), qux!(), nil!()).latex(),
+                   "    \\foobar{qux}");
     }
 
     #[test]
@@ -199,6 +439,10 @@ mod tests {
             opts: "bar".to_string(),
             comment: "".to_string(),
             is_list_like: false,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
         assert_eq!(hashline_helper(nil!(), foo!(), bar!(), nil!(), nil!()),
                    Hashline::OpenEnv(env_ref_1));
@@ -209,6 +453,10 @@ mod tests {
             opts: "".to_string(),
             comment: "bar".to_string(),
             is_list_like: false,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
         assert_eq!(hashline_helper(ws_2!(), foo!(), nil!(), nil!(), bar!()),
                    Hashline::OpenEnv(env_ref_2));
@@ -219,6 +467,10 @@ mod tests {
             opts: "bar".to_string(),
             comment: "qux".to_string(),
             is_list_like: false,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
         assert_eq!(hashline_helper(ws_4!(), foo!(), bar!(), nil!(), qux!()),
                    Hashline::OpenEnv(env_ref_3));
@@ -229,41 +481,164 @@ mod tests {
             opts: "bar".to_string(),
             comment: "qux".to_string(),
             is_list_like: true,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
         assert_eq!(hashline_helper(nil!(), itemize!(), bar!(), nil!(), qux!()),
                    Hashline::OpenEnv(env_ref_4));
+
+        let env_ref_5 = Environment {
+            indent_depth: 2,
+            name: "align*".to_string(),
+            opts: "".to_string(),
+            comment: "".to_string(),
+            is_list_like: false,
+            is_row_based: true,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
+        };
+        assert_eq!(hashline_helper(ws_2!(), align!(), nil!(), nil!(), nil!()),
+                   Hashline::OpenEnv(env_ref_5));
+    }
+
+    #[test]
+    fn parse_hashline() {
+        use super::{HashlineParts, parse_hashline};
+
+        assert_eq!(parse_hashline("  # foo [bar] : baz | qux % abc"),
+                   Some(HashlineParts {
+                       indent_depth: 2,
+                       name: "foo".to_string(),
+                       opts: "[bar]".to_string(),
+                       args: "baz | qux".to_string(),
+                       comment: "% abc".to_string(),
+                   }));
+        assert_eq!(parse_hashline(r"# foo: 50\%"),
+                   Some(HashlineParts {
+                       indent_depth: 0,
+                       name: "foo".to_string(),
+                       opts: "".to_string(),
+                       args: "50%".to_string(),
+                       comment: "".to_string(),
+                   }));
+        assert_eq!(parse_hashline("foo: bar"), None);
+        assert_eq!(parse_hashline("#foo: bar"), None);
+    }
+
+    #[test]
+    fn explicit_environments() {
+        use super::process_line;
+        use super::Hashline::{Command, OpenEnv, PlainLine};
+
+        match process_line("  # begin figure [h] % wide", false) {
+            OpenEnv(ref e) => {
+                assert!(e.is_closed_explicitly());
+                assert_eq!(e.latex_begin(), r"  \begin{figure}[h] % wide");
+                assert_eq!(e.latex_end(), r"  \end{figure}");
+            }
+            ref h => panic!("expected an environment, got {:?}", h),
+        }
+        match process_line("# figure:", false) {
+            OpenEnv(ref e) => assert!(!e.is_closed_explicitly()),
+            ref h => panic!("expected an environment, got {:?}", h),
+        }
+        // The command `\begin{document}` and the environment `begin` stay as they were
+        match process_line("# begin: document", false) {
+            Command(ref c) => assert_eq!(c.latex(), r"\begin{document}"),
+            ref h => panic!("expected a command, got {:?}", h),
+        }
+        match process_line("# begin document:", false) {
+            OpenEnv(ref e) => assert_eq!(e.name(), "begin"),
+            ref h => panic!("expected an environment, got {:?}", h),
+        }
+        assert_eq!(process_line("# begin", false), PlainLine("# begin".to_string()));
+    }
+
+    #[test]
+    fn parse_closer() {
+        use super::parse_closer;
+
+        assert_eq!(parse_closer("# end"), Some("".to_string()));
+        assert_eq!(parse_closer("  # end document"), Some("document".to_string()));
+        assert_eq!(parse_closer("# end align* % equations"), Some("align*".to_string()));
+        assert_eq!(parse_closer("# end % done"), Some("".to_string()));
+        assert_eq!(parse_closer("# end: document"), None);
+        assert_eq!(parse_closer("# end document:"), None);
+        assert_eq!(parse_closer("# end of story"), None);
+        assert_eq!(parse_closer("# endnote"), None);
+        assert_eq!(parse_closer("#end"), None);
+    }
+
+    #[test]
+    fn hashline_ranges() {
+        use super::hashline_ranges;
+
+        let line = r"  # foo [a\: b] : c \% d % e";
+        let (opts, args) = hashline_ranges(line).unwrap();
+        assert_eq!(&line[opts], r" [a\: b] ");
+        assert_eq!(&line[args], r" c \% d ");
+        let (opts, args) = hashline_ranges("# foo:").unwrap();
+        assert!(opts.is_empty());
+        assert_eq!(args, 6..6);
+        assert_eq!(hashline_ranges("#foo: bar"), None);
+    }
+
+    #[test]
+    fn suggest_hashline() {
+        use super::suggest_hashline;
+
+        assert_eq!(suggest_hashline("#section: Foo"), Some("# section: Foo".to_string()));
+        assert_eq!(suggest_hashline("  #  section: Foo"), Some("  # section: Foo".to_string()));
+        assert_eq!(suggest_hashline("#\tsection: Foo"), Some("# section: Foo".to_string()));
+        assert_eq!(suggest_hashline("# section Foo"), Some("# section: Foo".to_string()));
+        assert_eq!(suggest_hashline("# section"), Some("# section:".to_string()));
+        assert_eq!(suggest_hashline("#figure [h]"), Some("# figure [h]:".to_string()));
+        assert_eq!(suggest_hashline("#figure: % x"), Some("# figure: % x".to_string()));
+        assert_eq!(suggest_hashline("# section [Intro] Introduction"),
+                   Some("# section [Intro]: Introduction".to_string()));
+        assert_eq!(suggest_hashline("# section: Foo"), None);
+        assert_eq!(suggest_hashline("#1 and #2"), None);
+        assert_eq!(suggest_hashline("# 1"), None);
+        assert_eq!(suggest_hashline("# sec%tion: foo"), None);
+        assert_eq!(suggest_hashline("# section % foo: bar"), None);
+        assert_eq!(suggest_hashline("#"), None);
+        assert_eq!(suggest_hashline("foo # bar"), None);
+        assert_eq!(suggest_hashline("\t# section: Foo"), None);
     }
 
     #[test]
     fn itemline_helper() {
-        use super::{Hashline, itemline_helper};
+        use super::itemline_helper;
 
-        assert_eq!(itemline_helper(ws_2!(), foo!()),
-                   Hashline::PlainLine("  \\item foo".to_string()));
+        assert_eq!(itemline_helper(ws_2!(), foo!()).latex(),
+                   "  \\item foo");
         // Test that no whitespace is put after `\item` if no item is given
-        assert_eq!(itemline_helper(ws_1!(), nil!()),
-                   Hashline::PlainLine(" \\item".to_string()));
+        assert_eq!(itemline_helper(ws_1!(), nil!()).latex(),
+                   " \\item");
     }
 
     #[test]
     fn process_itemline() {
-        use super::{Hashline, process_itemline};
+        use super::process_itemline;
 
         // Valid itemlines
-        assert_eq!(process_itemline("*"),
-                   Some(Hashline::PlainLine("\\item".to_string())));
-        assert_eq!(process_itemline("*  "),
-                   Some(Hashline::PlainLine("\\item".to_string())));
-        assert_eq!(process_itemline("  *"),
-                   Some(Hashline::PlainLine("  \\item".to_string())));
-        assert_eq!(process_itemline("  *  "),
-                   Some(Hashline::PlainLine("  \\item".to_string())));
-        assert_eq!(process_itemline("* foo"),
-                   Some(Hashline::PlainLine("\\item foo".to_string())));
-        assert_eq!(process_itemline("  * bar"),
-                   Some(Hashline::PlainLine("  \\item bar".to_string())));
-        assert_eq!(process_itemline("****"),
-                   Some(Hashline::PlainLine("\\item ***".to_string())));
+        assert_eq!(process_itemline("*").map(|h| h.latex()),
+                   Some("\\item".to_string()));
+        assert_eq!(process_itemline("*  ").map(|h| h.latex()),
+                   Some("\\item".to_string()));
+        assert_eq!(process_itemline("  *").map(|h| h.latex()),
+                   Some("  \\item".to_string()));
+        assert_eq!(process_itemline("  *  ").map(|h| h.latex()),
+                   Some("  \\item".to_string()));
+        assert_eq!(process_itemline("* foo").map(|h| h.latex()),
+                   Some("\\item foo".to_string()));
+        assert_eq!(process_itemline("  * bar").map(|h| h.latex()),
+                   Some("  \\item bar".to_string()));
+        assert_eq!(process_itemline("****").map(|h| h.latex()),
+                   Some("\\item ***".to_string()));
 
         // Not an itemline
         assert_eq!(process_itemline("  baz"), None);
@@ -283,11 +658,16 @@ mod tests {
             opts: "bar".to_string(),
             comment: "% baz".to_string(),
             is_list_like: true,
+            is_row_based: false,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
 
         assert_eq!(env_1.latex_begin(), "\\begin{foo}bar % baz");
         assert_eq!(env_1.latex_end(), "\\end{foo}");
-        assert_eq!(env_1.is_list_like(), true);
+        assert!(env_1.is_list_like());
+        assert!(!env_1.is_row_based());
         assert_eq!(env_1.indent_depth(), 0);
 
         let env_2 = Environment {
@@ -296,11 +676,16 @@ mod tests {
             opts: "def".to_string(),
             comment: "".to_string(),
             is_list_like: false,
+            is_row_based: true,
+            is_closed_explicitly: false,
+            begin: None,
+            end: None,
         };
 
         assert_eq!(env_2.latex_begin(), "  \\begin{abc}def");
         assert_eq!(env_2.latex_end(), "  \\end{abc}");
-        assert_eq!(env_2.is_list_like(), false);
+        assert!(!env_2.is_list_like());
+        assert!(env_2.is_row_based());
         assert_eq!(env_2.indent_depth(), 2);
     }
 
@@ -314,11 +699,11 @@ mod tests {
         let d = b"item";
         let e = b"foobar";
 
-        assert_eq!(list_env_parser(&a[..]), Done(&b""[..], &a[..]));
-        assert_eq!(list_env_parser(&b[..]), Done(&b"*"[..], &b"enumerate"[..]));
-        assert_eq!(list_env_parser(&c[..]), Done(&b"*"[..], &b"description"[..]));
-        assert_eq!(list_env_parser(&d[..]), Incomplete(Needed::Size(7)));
-        assert_eq!(list_env_parser(&e[..]), Error(error_position!(ErrorKind::Alt, &e[..])));
+        assert_eq!(list_env_parser(cbs!(a)), Ok((cbs!(b""), cbs!(a))));
+        assert_eq!(list_env_parser(cbs!(b)), Ok((cbs!(b"*"), cbs!(b"enumerate"))));
+        assert_eq!(list_env_parser(cbs!(c)), Ok((cbs!(b"*"), cbs!(b"description"))));
+        assert_eq!(list_env_parser(cbs!(d)), parse_error!(d, Alt));
+        assert_eq!(list_env_parser(cbs!(e)), parse_error!(e, Alt));
     }
 
     #[test]
@@ -328,9 +713,9 @@ mod tests {
         let a = br"\:";
         let c = b"ab";
 
-        assert_eq!(escaped_colon(&a[..]), Done(&b""[..], ':' as u8));
-        assert_eq!(escaped_colon(nil!()), Incomplete(Needed::Size(1)));
-        assert_eq!(escaped_colon(&c[..]), Error(error_position!(ErrorKind::Char, &c[..])));
+        assert_eq!(escaped_colon(cbs!(a)), Ok((cbs!(b""), b':')));
+        assert_eq!(escaped_colon(cbs!(nil!())), parse_error!(nil!(), Char));
+        assert_eq!(escaped_colon(cbs!(c)), parse_error!(c, Char));
     }
 
     #[test]
@@ -340,21 +725,21 @@ mod tests {
         let a = br"\%";
         let c = b"ab";
 
-        assert_eq!(escaped_percent(&a[..]), Done(&b""[..], '%' as u8));
-        assert_eq!(escaped_percent(nil!()), Incomplete(Needed::Size(1)));
-        assert_eq!(escaped_percent(&c[..]), Error(error_position!(ErrorKind::Char, &c[..])));
+        assert_eq!(escaped_percent(cbs!(a)), Ok((cbs!(b""), b'%')));
+        assert_eq!(escaped_percent(cbs!(nil!())), parse_error!(nil!(), Char));
+        assert_eq!(escaped_percent(cbs!(c)), parse_error!(c, Char));
     }
 
     #[test]
     fn name_parser() {
         use super::name_parser;
 
-        assert_eq!(name_parser(&br"abc"[..]), Done(&b"bc"[..], 'a' as u8));
-        assert_eq!(name_parser(&br"\:abc"[..]), Done(&b"abc"[..], ':' as u8));
-        assert_eq!(name_parser(&b""[..]), Incomplete(Needed::Size(1)));
+        assert_eq!(name_parser(cbs!(br"abc")), Ok((cbs!(b"bc"), b'a')));
+        assert_eq!(name_parser(cbs!(br"\:abc")), Ok((cbs!(b"abc"), b':')));
+        assert_eq!(name_parser(cbs!(b"")), parse_error!(b"", Alt));
 
-        for e in vec![b":E", b"%E", b"(E", b"[E", b"{E", b" E", b"\tE"] {
-            assert_eq!(name_parser(&e[..]), Error(error_position!(ErrorKind::Alt, &e[..])));
+        for e in &[b":E", b"%E", b"(E", b"[E", b"{E", b" E", b"\tE"] {
+            assert_eq!(name_parser(cbs!(e)), parse_error!(e, Alt));
         }
     }
 
@@ -362,16 +747,16 @@ mod tests {
     fn opts_parser() {
         use super::opts_parser;
 
-        assert_eq!(opts_parser(&br"abc"[..]), Done(&b"bc"[..], 'a' as u8));
-        assert_eq!(opts_parser(&br"\:abc"[..]), Done(&b"abc"[..], ':' as u8));
-        assert_eq!(opts_parser(&br"\%abc"[..]), Done(&b"abc"[..], '%' as u8));
-        assert_eq!(opts_parser(&br"(abc"[..]), Done(&b"abc"[..], '(' as u8));
-        assert_eq!(opts_parser(&br"[abc"[..]), Done(&b"abc"[..], '[' as u8));
-        assert_eq!(opts_parser(&br" abc"[..]), Done(&b"abc"[..], ' ' as u8));
-        assert_eq!(opts_parser(&b""[..]), Incomplete(Needed::Size(1)));
+        assert_eq!(opts_parser(cbs!(br"abc")), Ok((cbs!(b"bc"), b'a')));
+        assert_eq!(opts_parser(cbs!(br"\:abc")), Ok((cbs!(b"abc"), b':')));
+        assert_eq!(opts_parser(cbs!(br"\%abc")), Ok((cbs!(b"abc"), b'%')));
+        assert_eq!(opts_parser(cbs!(br"(abc")), Ok((cbs!(b"abc"), b'(')));
+        assert_eq!(opts_parser(cbs!(br"[abc")), Ok((cbs!(b"abc"), b'[')));
+        assert_eq!(opts_parser(cbs!(br" abc")), Ok((cbs!(b"abc"), b' ')));
+        assert_eq!(opts_parser(cbs!(b"")), parse_error!(b"", Alt));
 
-        for e in vec![b":E", b"%E"] {
-            assert_eq!(opts_parser(&e[..]), Error(error_position!(ErrorKind::Alt, &e[..])));
+        for e in &[b":E", b"%E"] {
+            assert_eq!(opts_parser(cbs!(e)), parse_error!(e, Alt));
         }
     }
 
@@ -379,14 +764,14 @@ mod tests {
     fn args_parser() {
         use super::args_parser;
 
-        assert_eq!(args_parser(&br"abc"[..]), Done(&b"bc"[..], 'a' as u8));
-        assert_eq!(args_parser(&br"\:abc"[..]), Done(&b":abc"[..], '\\' as u8));
-        assert_eq!(args_parser(&br"\%abc"[..]), Done(&b"abc"[..], '%' as u8));
-        assert_eq!(args_parser(&br"(abc"[..]), Done(&b"abc"[..], '(' as u8));
-        assert_eq!(args_parser(&br"[abc"[..]), Done(&b"abc"[..], '[' as u8));
-        assert_eq!(args_parser(&br" abc"[..]), Done(&b"abc"[..], ' ' as u8));
-        assert_eq!(args_parser(&b""[..]), Incomplete(Needed::Size(1)));
+        assert_eq!(args_parser(cbs!(br"abc")), Ok((cbs!(b"bc"), b'a')));
+        assert_eq!(args_parser(cbs!(br"\:abc")), Ok((cbs!(b":abc"), b'\\')));
+        assert_eq!(args_parser(cbs!(br"\%abc")), Ok((cbs!(b"abc"), b'%')));
+        assert_eq!(args_parser(cbs!(br"(abc")), Ok((cbs!(b"abc"), b'(')));
+        assert_eq!(args_parser(cbs!(br"[abc")), Ok((cbs!(b"abc"), b'[')));
+        assert_eq!(args_parser(cbs!(br" abc")), Ok((cbs!(b"abc"), b' ')));
+        assert_eq!(args_parser(cbs!(b"")), parse_error!(b"", Alt));
 
-        assert_eq!(args_parser(&b"%E"[..]), Error(error_position!(ErrorKind::Alt, &b"%E"[..])));
+        assert_eq!(args_parser(cbs!(b"%E")), parse_error!(b"%E", Alt));
     }
 }
@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use file_utils::{FileResolver, INDENTEX_EXTENSION, OUTPUT_SUFFIX};
+use parsers::{hashline_ranges, parse_hashline};
+use transpile::{Dependency, SourceLine};
+
+
+const REFERENCE_COMMANDS: [&str; 3] = ["input", "include", "subfile"];
+pub const TEX_EXTENSION: &str = ".tex";
+
+
+/// Point `\input`, `\include` and `\subfile` (and their hashlines) at the transpiled files
+///
+/// Paths are relative to `base_dir`, the directory of the main file, since LaTeX resolves them against its working
+/// directory, no matter which file contains the reference. The source files of all existing references are added to
+/// `references`. References are only reported as missing if `files` knows that they do not exist.
+pub fn rewrite_references(lines: Vec<SourceLine>,
+                          base_dir: &Path,
+                          files: &dyn FileResolver,
+                          references: &mut Vec<Dependency>,
+                          diagnostics: &mut Vec<Diagnostic>)
+                          -> Vec<SourceLine> {
+    lines.into_iter()
+        .map(|line| {
+            let mut report = |target: &str| {
+                let msg = format!("referenced file '{}' does not exist", base_dir.join(target).display());
+                diagnostics.push(Diagnostic::warning(&line, msg));
+            };
+            let mut found = Vec::new();
+
+            let text = match (parse_hashline(&line), hashline_ranges(&line)) {
+                (Some(ref p), Some((_, ref args))) if REFERENCE_COMMANDS.contains(&p.name.as_str()) &&
+                                                      p.opts.is_empty() && !p.args.is_empty() => {
+                    // Only the target changes, like in `rewrite_commands`
+                    let raw = &line.text[args.clone()];
+                    let start = args.start + raw.len() - raw.trim_start().len();
+                    let end = args.start + raw.trim_end().len();
+                    resolve_reference(&p.name, &line.text[start..end], base_dir, files, &mut found, &mut report)
+                        .map(|target| format!("{}{}{}", &line.text[..start], target, &line.text[end..]))
+                }
+                _ => rewrite_commands(&line.text, base_dir, files, &mut found, &mut report),
+            };
+            references.extend(found.into_iter().map(|path| Dependency { from: line.file.clone(), path }));
+
+            match text {
+                Some(t) => line.with_text(t),
+                None => line,
+            }
+        })
+        .collect()
+}
+
+// Rewrite all references in a line of LaTeX, `None` if nothing has changed
+fn rewrite_commands<F>(line: &str, dir: &Path, files: &dyn FileResolver, found: &mut Vec<PathBuf>, report: &mut F)
+                       -> Option<String>
+    where F: FnMut(&str)
+{
+    use transpile::strip_comment;
+
+    let code = strip_comment(line);
+    let mut rewritten = String::with_capacity(line.len());
+    let mut changed = false;
+    let mut pos = 0;
+
+    while let Some((start, command)) = find_command(&code[pos..]) {
+        let arg_start = pos + start + command.len() + r"\{".len();
+        let arg_end = match code[arg_start..].find('}') {
+            Some(e) => arg_start + e,
+            None => break,
+        };
+        rewritten.push_str(&line[pos..arg_start]);
+        match resolve_reference(command, code[arg_start..arg_end].trim(), dir, files, found, report) {
+            Some(target) => {
+                rewritten.push_str(&target);
+                changed = true;
+            }
+            None => rewritten.push_str(&line[arg_start..arg_end]),
+        }
+        pos = arg_end;
+    }
+
+    if changed {
+        rewritten.push_str(&line[pos..]);
+        Some(rewritten)
+    } else {
+        None
+    }
+}
+
+// Find the first unescaped `\command{` and return its position and the command name
+pub fn find_command(code: &str) -> Option<(usize, &'static str)> {
+    REFERENCE_COMMANDS.iter()
+        .filter_map(|c| {
+            let pattern = format!(r"\{}{{", c);
+            let mut from = 0;
+            while let Some(i) = code[from..].find(&pattern) {
+                let start = from + i;
+                // An odd number of backslashes in front means that the backslash is escaped
+                let before = &code[..start];
+                if (before.len() - before.trim_end_matches('\\').len()) % 2 == 0 {
+                    return Some((start, *c));
+                }
+                from = start + 1;
+            }
+            None
+        })
+        .min_by_key(|&(start, _)| start)
+}
+
+// Return the name of the transpiled file if the target is an indentex source
+fn resolve_reference<F>(command: &str,
+                        target: &str,
+                        dir: &Path,
+                        files: &dyn FileResolver,
+                        found: &mut Vec<PathBuf>,
+                        report: &mut F)
+                        -> Option<String>
+    where F: FnMut(&str)
+{
+    let bare_suffix = &OUTPUT_SUFFIX[..OUTPUT_SUFFIX.len() - TEX_EXTENSION.len()];
+    // `\include` does not accept file extensions
+    let suffix = if command == "include" { bare_suffix } else { OUTPUT_SUFFIX };
+    // Outputs of indentex files may not exist yet, but their sources do. `None` if it is unknown.
+    let mut check = |path: PathBuf| match indentex_source(&path) {
+        _ if files.is_file(&path)? => {
+            found.push(path);
+            Some(true)
+        }
+        Some(ref source) if files.is_file(source)? => {
+            found.push(source.clone());
+            Some(true)
+        }
+        _ => Some(false),
+    };
+
+    if let Some(stem) = target.strip_suffix(INDENTEX_EXTENSION) {
+        if check(dir.join(target)) == Some(false) {
+            report(target);
+        }
+        return Some(format!("{}{}", stem, suffix));
+    }
+
+    if target.ends_with(TEX_EXTENSION) {
+        if check(dir.join(target)) == Some(false) {
+            report(target);
+        }
+        return None;
+    }
+
+    // LaTeX appends `.tex` to targets without this extension, so we keep omitting it
+    if check(dir.join(format!("{}{}", target, INDENTEX_EXTENSION))) == Some(true) {
+        Some(format!("{}{}", target, bare_suffix))
+    } else {
+        if check(dir.join(format!("{}{}", target, TEX_EXTENSION))) == Some(false) &&
+           check(dir.join(target)) == Some(false) {
+            report(target);
+        }
+        None
+    }
+}
+
+// Map the output of a transpiled file back to its source
+pub fn indentex_source(path: &Path) -> Option<PathBuf> {
+    path.to_str()
+        .and_then(|p| p.strip_suffix(OUTPUT_SUFFIX))
+        .map(|stem| PathBuf::from(format!("{}{}", stem, INDENTEX_EXTENSION)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use file_utils::FileSystem;
+    use test_utils::TempDir;
+    use transpile::SourceLine;
+
+    fn temp_dir() -> TempDir {
+        let dir = TempDir::new("references");
+        fs::create_dir_all(dir.join("ch")).unwrap();
+        fs::write(dir.join("ch/intro.inden.tex"), "").unwrap();
+        fs::write(dir.join("ch/plain.tex"), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn find_command() {
+        use super::find_command;
+
+        assert_eq!(find_command(r"a \include{b} \input{c}"), Some((2, "include")));
+        assert_eq!(find_command(r"\\input{c} \subfile{d}"), Some((11, "subfile")));
+        assert_eq!(find_command(r"\includegraphics{a} \inputencoding{b}"), None);
+    }
+
+    #[test]
+    fn rewrite_commands() {
+        use super::rewrite_commands;
+
+        let dir = temp_dir();
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+        let mut r = |l: &str| rewrite_commands(l, &dir, &FileSystem, &mut found, &mut |t: &str| {
+            missing.push(t.to_string())
+        });
+
+        assert_eq!(r(r"\input{ch/intro.inden.tex} \include{ch/intro.inden.tex}"),
+                   Some(r"\input{ch/intro_indentex.tex} \include{ch/intro_indentex}".to_string()));
+        assert_eq!(r(r"\subfile{ch/intro} % \input{ch/intro}"),
+                   Some(r"\subfile{ch/intro_indentex} % \input{ch/intro}".to_string()));
+        assert_eq!(r(r"\input{ch/plain} \input{ch/plain.tex}"), None);
+        assert_eq!(r(r"\input{ch/intro_indentex.tex}"), None);
+        assert_eq!(r(r"\input{ch/missing} \input{ch/gone.inden.tex}"),
+                   Some(r"\input{ch/missing} \input{ch/gone_indentex.tex}".to_string()));
+        assert_eq!(missing, ["ch/missing", "ch/gone.inden.tex"]);
+        assert_eq!(found, [dir.join("ch/intro.inden.tex"), dir.join("ch/intro.inden.tex"), dir.join("ch/intro.inden.tex"),
+                           dir.join("ch/plain.tex"), dir.join("ch/plain.tex"), dir.join("ch/intro.inden.tex")]);
+    }
+
+    #[test]
+    fn rewrite_references() {
+        use super::rewrite_references;
+
+        let dir = temp_dir();
+        // Only the target is rewritten, spaces and escapes are kept
+        let mut lines = SourceLine::number(&["  # input:  ch/intro   % c", "# include [x]: ch/intro", r"\include{ch/nope}",
+                                             r"# input: 100\%.inden.tex"]);
+        // Included files refer to paths relative to the main file as well
+        lines.extend(SourceLine::number_in_file(&[r"\input{ch/intro}", r"\input{intro}"],
+                                                Some(Arc::new(dir.join("ch/part.inden.tex")))));
+        let mut references = Vec::new();
+        let mut diagnostics = Vec::new();
+        let rewritten = rewrite_references(lines, &dir, &FileSystem, &mut references, &mut diagnostics);
+
+        let texts: Vec<_> = rewritten.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["  # input:  ch/intro_indentex   % c", "# include [x]: ch/intro", r"\include{ch/nope}",
+                           r"# input: 100\%_indentex.tex", r"\input{ch/intro_indentex}", r"\input{intro}"]);
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [3, 4, 2]);
+        let paths: Vec<_> = references.iter().map(|r| r.path.clone()).collect();
+        assert_eq!(paths, [dir.join("ch/intro.inden.tex"), dir.join("ch/intro.inden.tex")]);
+    }
+
+    #[test]
+    fn without_file_system() {
+        use super::rewrite_commands;
+        use file_utils::NoFileSystem;
+        use std::path::Path;
+
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+        let mut r = |l: &str| rewrite_commands(l, Path::new("doc"), &NoFileSystem, &mut found, &mut |t: &str| {
+            missing.push(t.to_string())
+        });
+
+        // Only the extension tells whether a target is an indentex file
+        assert_eq!(r(r"\input{ch/intro.inden.tex} \input{ch/intro} \include{ch/plain.tex}"),
+                   Some(r"\input{ch/intro_indentex.tex} \input{ch/intro} \include{ch/plain.tex}".to_string()));
+        assert!(missing.is_empty());
+        assert!(found.is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use error::IndentexError;
+use transpile::{SourceLocation, Transpiled};
+
+
+// Lines of a transpiled file together with the file they come from
+struct Mapped {
+    source: PathBuf,
+    lines: Vec<Option<SourceLocation>>,
+}
+
+/// Map lines of transpiled files back to the indentex sources
+#[derive(Default)]
+pub struct SourceMap {
+    // Keyed by the normalised path of the output
+    outputs: HashMap<PathBuf, Mapped>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    pub fn add<T: AsRef<Path>>(&mut self, source: T, transpiled: &Transpiled) -> Result<(), IndentexError> {
+        use file_utils::rename_indentex_file;
+
+        let output = rename_indentex_file(source.as_ref())?;
+        self.outputs.insert(normalise(&output),
+                            Mapped { source: source.as_ref().to_path_buf(), lines: transpiled.line_map.clone() });
+        Ok(())
+    }
+
+    /// Return the source and the one-based line of a line in a transpiled file
+    ///
+    /// `None` if the file is unknown or the line has been generated, e.g. the notice.
+    pub fn locate<T: AsRef<Path>>(&self, output: T, line: usize) -> Option<(PathBuf, usize)> {
+        let mapped = self.outputs.get(&normalise(output.as_ref()))?;
+        let location = mapped.lines.get(line.checked_sub(1)?)?.as_ref()?;
+        let file = location.file.as_ref().map_or_else(|| mapped.source.clone(), |f| f.as_ref().clone());
+        Some((file, location.line))
+    }
+}
+
+// Outputs need not exist, hence only the directory is canonicalised
+fn normalise(path: &Path) -> PathBuf {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    match (dir.canonicalize(), path.file_name()) {
+        (Ok(d), Some(f)) => d.join(f),
+        _ => path.to_path_buf(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[test]
+    fn locate() {
+        use super::SourceMap;
+        use transpile::{SourceLocation, Transpiled};
+
+        let dir = ::std::env::temp_dir();
+        let location = |file: Option<&str>, line| {
+            Some(SourceLocation { file: file.map(|f| Arc::new(PathBuf::from(f))), line })
+        };
+        let transpiled = Transpiled {
+            text: String::new(),
+            diagnostics: Vec::new(),
+            includes: Vec::new(),
+            references: Vec::new(),
+            line_map: vec![None, location(None, 3), location(Some("part.inden.tex"), 1)],
+        };
+        let mut map = SourceMap::new();
+        map.add(dir.join("paper.inden.tex"), &transpiled).unwrap();
+
+        let output = dir.join("paper_indentex.tex");
+        assert_eq!(map.locate(&output, 1), None);
+        assert_eq!(map.locate(dir.join(".").join("paper_indentex.tex"), 2), Some((dir.join("paper.inden.tex"), 3)));
+        assert_eq!(map.locate(&output, 2), Some((dir.join("paper.inden.tex"), 3)));
+        assert_eq!(map.locate(&output, 3), Some((PathBuf::from("part.inden.tex"), 1)));
+        assert_eq!(map.locate(&output, 4), None);
+        assert_eq!(map.locate(Path::new("other.tex"), 1), None);
+    }
+}
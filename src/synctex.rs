@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+use error::IndentexError;
+use source_map::SourceMap;
+
+
+const INPUT_RECORD: &str = "Input:";
+const CONTENT_RECORD: &str = "Content:";
+// Records of boxes, glue, kerns etc. which refer to a position `tag,line` in an input file
+const POSITION_RECORDS: &str = "[(vhxkg$r";
+const ANCHOR: char = '!';
+const GZIP_EXTENSION: &str = "gz";
+
+
+pub fn read_synctex<T: AsRef<Path>>(path: T) -> Result<String, IndentexError> {
+    use flate2::read::GzDecoder;
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut file = File::open(path.as_ref())?;
+    let mut text = String::new();
+    if path.as_ref().extension().is_some_and(|e| e == GZIP_EXTENSION) {
+        GzDecoder::new(file).read_to_string(&mut text)?;
+    } else {
+        file.read_to_string(&mut text)?;
+    }
+    Ok(text)
+}
+
+pub fn write_synctex<T: AsRef<Path>>(path: T, text: &str) -> Result<(), IndentexError> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use file_utils::write_to_file;
+    use std::fs::File;
+    use std::io::Write;
+
+    if path.as_ref().extension().is_some_and(|e| e == GZIP_EXTENSION) {
+        let mut encoder = GzEncoder::new(File::create(path.as_ref())?, Compression::default());
+        encoder.write_all(text.as_bytes())?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        write_to_file(path, text)
+    }
+}
+
+/// Files named in the `Input:` records
+pub fn synctex_inputs(text: &str) -> Vec<PathBuf> {
+    text.lines().filter_map(parse_input).map(|(_, p)| PathBuf::from(p)).collect()
+}
+
+/// Point all records in transpiled files to the indentex sources
+///
+/// Each source gets a new `Input:` record, the records of the transpiled files are kept
+/// for generated lines such as the notice.
+pub fn rewrite_synctex(text: &str, source_map: &SourceMap) -> String {
+    let inputs: HashMap<u32, PathBuf> = text.lines()
+        .filter_map(parse_input)
+        .map(|(t, p)| (t, PathBuf::from(p)))
+        .collect();
+    let mut next_tag = inputs.keys().max().map_or(1, |t| t + 1);
+    let mut new_inputs: Vec<(u32, PathBuf)> = Vec::new();
+    let mut cache: HashMap<(u32, usize), Option<(u32, usize)>> = HashMap::new();
+
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let rewritten = parse_position(line).and_then(|(tag, line_num, rest_at)| {
+            let target = *cache.entry((tag, line_num)).or_insert_with(|| {
+                let (source, source_line) = source_map.locate(inputs.get(&tag)?, line_num)?;
+                let source_tag = match new_inputs.iter().find(|(_, p)| *p == source) {
+                    Some(&(t, _)) => t,
+                    None => {
+                        new_inputs.push((next_tag, source));
+                        next_tag += 1;
+                        next_tag - 1
+                    }
+                };
+                Some((source_tag, source_line))
+            });
+            target.map(|(t, l)| format!("{}{},{}{}", &line[..1], t, l, &line[rest_at..]))
+        });
+        lines.push(rewritten.unwrap_or_else(|| line.to_string()));
+    }
+
+    // New inputs are declared together with the existing ones in the preamble
+    let preamble = lines.iter().position(|l| l.starts_with(CONTENT_RECORD)).unwrap_or(lines.len());
+    let insert_at = lines[..preamble].iter()
+        .rposition(|l| l.starts_with(INPUT_RECORD))
+        .map_or(1, |i| i + 1)
+        .min(lines.len());
+    let records: Vec<String> = new_inputs.iter()
+        .map(|(t, p)| format!("{}{}:{}", INPUT_RECORD, t, p.display()))
+        .collect();
+    lines.splice(insert_at..insert_at, records);
+
+    recompute_anchors(&lines)
+}
+
+// Parse `Input:<tag>:<path>`
+fn parse_input(line: &str) -> Option<(u32, &str)> {
+    let rest = line.strip_prefix(INPUT_RECORD)?;
+    let i = rest.find(':')?;
+    Some((rest[..i].parse().ok()?, &rest[i + 1..]))
+}
+
+// Parse the `tag,line` of a position record, return also where the rest of the record starts
+fn parse_position(line: &str) -> Option<(u32, usize, usize)> {
+    let first = line.chars().next()?;
+    if !POSITION_RECORDS.contains(first) {
+        return None;
+    }
+    let rest = &line[1..];
+    let comma = rest.find(',')?;
+    let tag = rest[..comma].parse().ok()?;
+    let line_len = rest[comma + 1..].find(|c: char| !c.is_ascii_digit())?;
+    let line_num = rest[comma + 1..comma + 1 + line_len].parse().ok()?;
+    Some((tag, line_num, 1 + comma + 1 + line_len))
+}
+
+// Anchors `!<n>` store the number of bytes since the previous anchor, which have changed
+fn recompute_anchors(lines: &[String]) -> String {
+    let mut text = String::new();
+    let mut last_anchor = 0;
+
+    for l in lines {
+        let is_anchor = l.len() > 1 && l.starts_with(ANCHOR) && l[1..].chars().all(|c| c.is_ascii_digit());
+        if is_anchor {
+            let start = text.len();
+            text.push_str(&format!("{}{}\n", ANCHOR, start - last_anchor));
+            last_anchor = start;
+        } else {
+            text.push_str(l);
+            text.push('\n');
+        }
+    }
+
+    text
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_position() {
+        use super::parse_position;
+
+        assert_eq!(parse_position("[1,12:4736286,5467865:22609920,0,0"), Some((1, 12, 5)));
+        assert_eq!(parse_position("x12,345,6:100,200"), Some((12, 345, 7)));
+        assert_eq!(parse_position("}1"), None);
+        assert_eq!(parse_position("Input:1:a.tex"), None);
+    }
+
+    #[test]
+    fn rewrite_synctex() {
+        use super::rewrite_synctex;
+        use source_map::SourceMap;
+        use transpile::{SourceLocation, Transpiled};
+
+        let transpiled = Transpiled {
+            text: String::new(),
+            diagnostics: Vec::new(),
+            includes: Vec::new(),
+            references: Vec::new(),
+            line_map: vec![None,
+                           Some(SourceLocation { file: None, line: 1 }),
+                           Some(SourceLocation { file: Some(Arc::new(PathBuf::from("part.inden.tex"))), line: 7 })],
+        };
+        let mut map = SourceMap::new();
+        map.add("paper.inden.tex", &transpiled).unwrap();
+
+        let input = "SyncTeX Version:1\nInput:1:paper_indentex.tex\nInput:2:/tex/article.cls\nOutput:pdf\n\
+                     Content:\n!90\n{1\n[1,1:0,0:1,1,0\nh1,2:0,0:1,1,0\nx1,3:5,5\nk2,4:1,1:1\n]1\n}1\n!50\n\
+                     Postamble:\n";
+        // The anchors count the bytes since the previous one, which change with the inputs
+        let expected = "SyncTeX Version:1\nInput:1:paper_indentex.tex\nInput:2:/tex/article.cls\n\
+                        Input:3:paper.inden.tex\nInput:4:part.inden.tex\nOutput:pdf\nContent:\n!137\n\
+                        {1\n[1,1:0,0:1,1,0\nh3,1:0,0:1,1,0\nx4,7:5,5\nk2,4:1,1:1\n]1\n}1\n!64\nPostamble:\n";
+
+        assert_eq!(rewrite_synctex(input, &map), expected);
+    }
+
+    #[test]
+    fn recompute_anchors() {
+        use super::recompute_anchors;
+
+        let lines: Vec<String> = ["a", "!1", "bc", "!1", "!x"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(recompute_anchors(&lines), "a\n!2\nbc\n!6\n!x\n");
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Temporary directory of a single test, which is removed with its content when dropped
+///
+/// The name is unique across processes and threads, hence tests running in parallel or files
+/// left behind by an aborted run cannot interfere.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        use std::env;
+        use std::process;
+
+        let unique = format!("indentex_{}_{}_{}", name, process::id(), NEXT_ID.fetch_add(1, Ordering::SeqCst));
+        let path = env::temp_dir().join(unique);
+        fs::create_dir_all(&path).unwrap();
+        // Included files are compared by their canonical paths
+        TempDir { path: path.canonicalize().unwrap() }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Failing to clean up must not fail the test
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn temp_dir() {
+        use super::TempDir;
+        use std::fs;
+
+        let a = TempDir::new("test_utils");
+        let b = TempDir::new("test_utils");
+        assert_ne!(a.to_path_buf(), b.to_path_buf());
+        fs::write(a.join("x"), "x").unwrap();
+        let path = a.to_path_buf();
+        drop(a);
+        assert!(!path.exists());
+    }
+}
@@ -1,21 +1,181 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::vec::Vec;
+use ast::{Node, SyntaxTree};
+use diagnostics::Diagnostic;
 use error::IndentexError;
+use file_utils::FileResolver;
+use handlers::HashlineHandler;
+use macros::MacroTable;
+use names::KnownNames;
+use parsers::Environment;
 
 
-const LINESEP: &'static str = "\n";
+const LINESEP: &str = "\n";
 const LATEX_TO_INDENTEX_FACTOR: f64 = 1.5;
-const DO_NOT_EDIT_NOTICE: &'static str = "\
+pub const DO_NOT_EDIT_NOTICE: &str = "\
     % ============================================================== %\n\
     %                                                                %\n\
     % THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY. %\n\
     %                                                                %\n\
     % ============================================================== %\n";
+const ROW_TERMINATOR: &str = r" \\";
+// Lines starting with these commands are placed between rows, not in them
+const ROW_SEPARATORS: [&str; 8] = [r"\hline", r"\cline", r"\toprule", r"\midrule", r"\bottomrule",
+                                   r"\cmidrule", r"\addlinespace", r"\intertext"];
+// Lines starting with `#` in these are code rather than broken hashlines
+const VERBATIM_ENVIRONMENTS: [&str; 5] = ["verbatim", "Verbatim", "lstlisting", "minted", "comment"];
 
 
+#[derive(Clone)]
 pub struct TranspileOptions {
     pub flatten_output: bool,
     pub prepend_do_not_edit_notice: bool,
+    // Report lines which almost are hashlines as errors instead of warnings
+    pub strict_hashlines: bool,
+    // Warn about unknown environments and misspelt commands, `None` disables the check
+    pub known_names: Option<Arc<KnownNames>>,
+    pub macros: MacroTable,
+    pub defines: HashMap<String, bool>,
+    // Defaults for the variables of the front matter, e.g. from the config file
+    pub default_variables: HashMap<String, String>,
+    // Override the variables from the front matter
+    pub variables: HashMap<String, String>,
+    // Config and macro files the options have been loaded from
+    pub config_files: Vec<PathBuf>,
+    // Row-based environments in addition to `parsers::ROW_ENVIRONMENTS`
+    pub row_environments: Vec<String>,
+    // Custom hashlines, the first handler which does not defer wins
+    pub handlers: Vec<Arc<dyn HashlineHandler>>,
+    // External commands rewriting the syntax tree before the emission
+    pub filters: Vec<String>,
+    // Access to included and referenced files
+    pub files: Arc<dyn FileResolver>,
+    // Directory of the main file, which references are relative to, the directory of the transpiled file if `None`
+    pub base_dir: Option<PathBuf>,
+}
+
+impl Default for TranspileOptions {
+    fn default() -> TranspileOptions {
+        use file_utils::FileSystem;
+
+        TranspileOptions {
+            flatten_output: false,
+            prepend_do_not_edit_notice: false,
+            strict_hashlines: false,
+            known_names: None,
+            macros: MacroTable::new(),
+            defines: HashMap::new(),
+            default_variables: HashMap::new(),
+            variables: HashMap::new(),
+            config_files: Vec::new(),
+            row_environments: Vec::new(),
+            handlers: Vec::new(),
+            filters: Vec::new(),
+            files: Arc::new(FileSystem),
+            base_dir: None,
+        }
+    }
+}
+
+impl TranspileOptions {
+    pub fn add_handler<H: HashlineHandler + 'static>(&mut self, handler: H) {
+        self.handlers.push(Arc::new(handler));
+    }
+}
+
+pub struct Transpiled {
+    pub text: String,
+    pub diagnostics: Vec<Diagnostic>,
+    // Files inlined with `# include:`
+    pub includes: Vec<Dependency>,
+    // Existing files referenced with `\input`, `\include` or `\subfile`
+    pub references: Vec<Dependency>,
+    // Origin of each output line, `None` for the notice
+    pub line_map: Vec<Option<SourceLocation>>,
+}
+
+impl Transpiled {
+    /// Lines of the text located in the indentex files they come from, `source` being the transpiled file
+    ///
+    /// Generated lines like the notice are located on line 0 of the transpiled file.
+    pub fn source_lines(&self, source: &Path) -> Vec<SourceLine> {
+        let source = Arc::new(source.to_path_buf());
+        self.text
+            .lines()
+            .enumerate()
+            .map(|(i, t)| match self.line_map.get(i) {
+                Some(Some(l)) => SourceLine {
+                    text: t.to_string(),
+                    line: l.line,
+                    file: Some(l.file.clone().unwrap_or_else(|| source.clone())),
+                },
+                _ => SourceLine { text: t.to_string(), line: 0, file: Some(source.clone()) },
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    // `None` for the file being transpiled, otherwise the included file
+    pub file: Option<Arc<PathBuf>>,
+    // One-based line number
+    pub line: usize,
+}
+
+// A file the transpiled source depends on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    // The included file containing the reference, `None` for the file being transpiled
+    pub from: Option<Arc<PathBuf>>,
+    pub path: PathBuf,
+}
+
+// An environment opened by a hashline, lines are zero-based
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentSpan {
+    pub name: String,
+    pub start: usize,
+    // Last line of the body, after which the environment is closed
+    pub end: usize,
+    // Number of enclosing environments
+    pub depth: usize,
+}
+
+// A line of indentex source together with its origin
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    // One-based line number
+    pub line: usize,
+    // `None` for the file being transpiled, otherwise the included file
+    pub file: Option<Arc<PathBuf>>,
+}
+
+impl SourceLine {
+    pub fn number<T: AsRef<str>>(lines: &[T]) -> Vec<SourceLine> {
+        SourceLine::number_in_file(lines, None)
+    }
+
+    pub fn number_in_file<T: AsRef<str>>(lines: &[T], file: Option<Arc<PathBuf>>) -> Vec<SourceLine> {
+        lines.iter()
+            .enumerate()
+            .map(|(i, l)| SourceLine { text: l.as_ref().to_string(), line: i + 1, file: file.clone() })
+            .collect()
+    }
+
+    // Create a line with the same origin
+    pub fn with_text(&self, text: String) -> SourceLine {
+        SourceLine { text, line: self.line, file: self.file.clone() }
+    }
+}
+
+impl AsRef<str> for SourceLine {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
 }
 
 // Indentation processing
@@ -24,11 +184,11 @@ fn count_left_indent<T: AsRef<str>>(line: T) -> Option<usize> {
     if line.as_ref().is_empty() {
         None
     } else {
-        Some(line.as_ref().chars().count() - line.as_ref().trim_left().chars().count())
+        Some(line.as_ref().chars().count() - line.as_ref().trim_start().chars().count())
     }
 }
 
-fn scan_indents<T: AsRef<str>>(lines: &[T]) -> Vec<usize> {
+pub fn scan_indents<T: AsRef<str>>(lines: &[T]) -> Vec<usize> {
     let raw_indents = lines.iter().map(count_left_indent).collect::<Vec<_>>();
 
     let mut adjusted_indents: Vec<usize> = Vec::with_capacity(raw_indents.len() + 1);
@@ -51,76 +211,378 @@ fn scan_indents<T: AsRef<str>>(lines: &[T]) -> Vec<usize> {
 }
 
 
+// Row processing
+#[derive(Debug, PartialEq)]
+enum RowLine {
+    // Blank lines and comments
+    NotARow,
+    // Rules and the like, which require the preceding row to be terminated
+    Separator,
+    Terminated,
+    // Stores the byte offset at which the row terminator has to be inserted
+    Unterminated(usize),
+}
+
+#[inline]
+pub fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '%' if !escaped => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+fn scan_row<T: AsRef<str>>(line: T) -> RowLine {
+    let content = strip_comment(line.as_ref()).trim_end();
+    let trimmed = content.trim_start();
+
+    if trimmed.is_empty() {
+        return RowLine::NotARow;
+    }
+    if ROW_SEPARATORS.iter().any(|s| trimmed.starts_with(s)) {
+        return RowLine::Separator;
+    }
+    // Also consider rows with an additional vertical space, e.g. `a & b \\[2pt]`
+    let is_terminated = content.ends_with(r"\\") ||
+                        match content.rfind(r"\\[") {
+                            Some(i) => content.ends_with(']') && !content[i..content.len() - 1].contains(']'),
+                            None => false,
+                        };
+    if is_terminated {
+        RowLine::Terminated
+    } else {
+        RowLine::Unterminated(content.len())
+    }
+}
+
+
 // Transpilation
-fn transpile<T: AsRef<str>>(lines: &[T], options: &TranspileOptions) -> String {
-    use parsers::Environment;
-    use parsers::Hashline::{PlainLine, OpenEnv};
-    use parsers::process_line;
+#[inline]
+fn terminate_row(transpiled: &mut String, open_rows: &mut [Option<usize>]) {
+    if let Some(pos) = open_rows.last_mut().and_then(Option::take) {
+        transpiled.insert_str(pos, ROW_TERMINATOR);
+    }
+}
+
+/// Transpile right-trimmed lines, `path` is used to resolve includes and references
+pub fn transpile<T: AsRef<str>>(lines: &[T], path: Option<&Path>, options: &TranspileOptions) -> Transpiled {
+    let mut transpiled = Transpiled {
+        text: String::new(),
+        diagnostics: Vec::new(),
+        includes: Vec::new(),
+        references: Vec::new(),
+        line_map: Vec::new(),
+    };
+
+    let rewritten = preprocess_source(lines, path, options, &mut transpiled);
+    let (text, line_map) = emit_mapped(&rewritten, options, &mut transpiled.diagnostics);
+    transpiled.text = text;
+    transpiled.line_map = line_map;
+
+    transpiled
+}
+
+/// Extract the front matter of right-trimmed lines and run all passes before the LaTeX emission
+pub fn preprocess_source<T: AsRef<str>>(lines: &[T],
+                                        path: Option<&Path>,
+                                        options: &TranspileOptions,
+                                        transpiled: &mut Transpiled)
+                                        -> Vec<SourceLine> {
+    use variables::extract_front_matter;
+
+    let mut variables = options.default_variables.clone();
+    let body = extract_front_matter(SourceLine::number(lines), &mut variables, &mut transpiled.diagnostics);
+    variables.extend(options.variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    // Macro definitions are local to each file, hence work on a copy
+    let mut macros = options.macros.clone();
+    preprocess(body, path, &variables, &mut macros, options, transpiled)
+}
+
+/// Run all passes before the LaTeX emission on lines without front matter
+///
+/// Macro definitions are added to `macros`, diagnostics and dependencies to `transpiled`.
+pub fn preprocess(lines: Vec<SourceLine>,
+                  path: Option<&Path>,
+                  variables: &HashMap<String, String>,
+                  macros: &mut MacroTable,
+                  options: &TranspileOptions,
+                  transpiled: &mut Transpiled)
+                  -> Vec<SourceLine> {
+    use conditionals::evaluate_conditionals;
+    use delimiters::check_delimiters;
+    use includes::expand_includes;
+    use references::rewrite_references;
+    use variables::substitute_variables;
+
+    let diagnostics = &mut transpiled.diagnostics;
+    check_delimiters(&lines, diagnostics);
+    let evaluated = evaluate_conditionals(lines, &options.defines, diagnostics);
+    // Relative paths are resolved against the working directory if the path is unknown
+    let file_dir = path.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    let base_dir = options.base_dir.as_ref().map_or(file_dir, PathBuf::as_path);
+    let files = options.files.as_ref();
+    let mut include_stack: Vec<PathBuf> = path.and_then(|p| files.canonicalize(p).ok()).into_iter().collect();
+    let included = expand_includes(evaluated,
+                                   file_dir,
+                                   files,
+                                   &options.defines,
+                                   &mut include_stack,
+                                   &mut transpiled.includes,
+                                   diagnostics);
+    let substituted = substitute_variables(included, variables, diagnostics);
+    let expanded = macros.expand(substituted, diagnostics);
+    rewrite_references(expanded, base_dir, files, &mut transpiled.references, diagnostics)
+}
+
+/// Emit the LaTeX code of preprocessed lines together with the origin of each output line
+pub fn emit_mapped(lines: &[SourceLine], options: &TranspileOptions, diagnostics: &mut Vec<Diagnostic>)
+                   -> (String, Vec<Option<SourceLocation>>) {
+    use ast::parse_checked;
+    use diagnostics::Severity;
+    use filters::run_filters;
+
+    let mut errors = Vec::new();
+    let mut tree = parse_checked(lines, &options.handlers, &mut errors);
+    diagnostics.extend(errors.into_iter().map(|e| Diagnostic::error(&lines[e.line], e.message)));
+    check_near_misses(&tree.children, lines, options, diagnostics);
+    if let Some(ref known) = options.known_names {
+        check_names(&tree.children, lines, known, diagnostics);
+    }
+    if !options.filters.is_empty() {
+        match run_filters(tree.clone(), &options.filters) {
+            Ok(t) => tree = t,
+            // Filters see the whole file, hence report failures at its first line
+            Err(message) => {
+                diagnostics.push(Diagnostic { severity: Severity::Error, file: None, line: 1, column: None, message })
+            }
+        }
+    }
+
+    let (text, origins) = emit_latex(&tree, lines, options);
+    // Filters may move nodes to lines which do not exist
+    let line_map = origins.into_iter()
+        .map(|o| o.and_then(|i| lines.get(i)).map(|l| SourceLocation { file: l.file.clone(), line: l.line }))
+        .collect();
+    (text, line_map)
+}
+
+// Report text lines which were probably meant to be hashlines
+fn check_near_misses(nodes: &[Node], lines: &[SourceLine], options: &TranspileOptions, diagnostics: &mut Vec<Diagnostic>) {
+    use diagnostics::Severity;
+    use parsers::suggest_hashline;
 
-    // The number of environments is not known beforehand
-    let mut env_stack: Vec<Environment> = Vec::new();
+    for node in nodes {
+        match *node {
+            Node::Environment { ref environment, ref children, .. }
+                if !VERBATIM_ENVIRONMENTS.contains(&environment.name().trim_end_matches('*')) => {
+                check_near_misses(children, lines, options, diagnostics);
+            }
+            Node::Text { ref text, ref span } => {
+                if let Some(suggestion) = suggest_hashline(text) {
+                    let severity = if options.strict_hashlines { Severity::Error } else { Severity::Warning };
+                    let message = format!("line is copied verbatim since it is not a hashline, did you mean '{}'?",
+                                          suggestion.trim_start());
+                    diagnostics.push(Diagnostic::new(severity, &lines[span.start_line - 1], message));
+                }
+            }
+            _ => {}
+        }
+    }
+}
 
+// Report unknown environments, but only misspelt commands since many packages add further ones
+fn check_names(nodes: &[Node], lines: &[SourceLine], known: &KnownNames, diagnostics: &mut Vec<Diagnostic>) {
+    for node in nodes {
+        match *node {
+            Node::Environment { ref environment, ref children, ref span } => {
+                let name = environment.name();
+                if !environment.is_custom() && !known.is_environment(name) {
+                    let message = match known.suggest_environment(name) {
+                        Some(s) => format!("unknown environment '{}', did you mean '{}'?", name, s),
+                        None => format!("unknown environment '{}'", name),
+                    };
+                    diagnostics.push(Diagnostic::warning(&lines[span.start_line - 1], message));
+                }
+                check_names(children, lines, known, diagnostics);
+            }
+            Node::Command { ref command, ref span } if !known.is_command(&command.name) => {
+                if let Some(s) = known.suggest_command(&command.name) {
+                    let message = format!("unknown command '\\{}', did you mean '\\{}'?", command.name, s);
+                    diagnostics.push(Diagnostic::warning(&lines[span.start_line - 1], message));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Return the LaTeX code and the index of the input line for each output line
+fn emit_latex<T: AsRef<str>>(tree: &SyntaxTree, lines: &[T], options: &TranspileOptions)
+                             -> (String, Vec<Option<usize>>) {
     // Input size is the sum of all line lengths plus the number of lines (for lineseps)
     let input_size = lines.iter().fold(0, |sum, l| sum + l.as_ref().len()) + lines.len();
     // We do not know how much larger the transpiled LaTeX file will be, but we can guess...
     let indentex_size = (LATEX_TO_INDENTEX_FACTOR * (input_size as f64)).round() as usize;
-    let mut transpiled = String::with_capacity(indentex_size);
 
-    let adjusted_indents = scan_indents(lines);
+    let mut emitter = Emitter {
+        options,
+        transpiled: String::with_capacity(indentex_size),
+        origins: Vec::with_capacity(lines.len()),
+        open_rows: Vec::new(),
+    };
 
     if options.prepend_do_not_edit_notice {
-        transpiled.push_str(DO_NOT_EDIT_NOTICE);
+        emitter.transpiled.push_str(DO_NOT_EDIT_NOTICE);
+        emitter.origins.extend(DO_NOT_EDIT_NOTICE.lines().map(|_| None));
     }
 
-    for (line_num, line) in lines.iter().enumerate() {
-        let list_like_active = match env_stack.last() {
-            None => false, // No environment is active at all
-            Some(d) => d.is_list_like(),
-        };
+    emitter.emit(&tree.children, None);
 
-        let tl = match process_line(line.as_ref(), list_like_active) {
-            PlainLine(l) => l,
-            OpenEnv(e) => {
-                let tag_begin = e.latex_begin();
-                env_stack.push(e);
-                tag_begin
-            }
-        };
-        if options.flatten_output {
-            transpiled.push_str(tl.trim_left());
-        } else {
-            transpiled.push_str(&tl);
-        }
-        transpiled.push_str(LINESEP);
-
-        // Check if we are in an environment and close as many as needed
-        while match env_stack.last() {
-            None => false,
-            Some(d) => d.indent_depth() >= adjusted_indents[line_num + 1],
-        } {
-            // `unwrap()` is safe here since we have already checked if the stack is empty
-            let tag_end = env_stack.pop().unwrap().latex_end();
-            if options.flatten_output {
-                transpiled.push_str(tag_end.trim_left());
-            } else {
-                transpiled.push_str(&tag_end);
+    (emitter.transpiled, emitter.origins)
+}
+
+struct Emitter<'a> {
+    options: &'a TranspileOptions,
+    transpiled: String,
+    origins: Vec<Option<usize>>,
+    // Positions in `transpiled` where the last unterminated row of each environment ends
+    open_rows: Vec<Option<usize>>,
+}
+
+impl<'a> Emitter<'a> {
+    fn emit(&mut self, nodes: &[Node], parent: Option<&Environment>) {
+        let row_based_active =
+            parent.is_some_and(|e| e.is_row_based() || self.options.row_environments.iter().any(|r| r == e.name()));
+
+        for node in nodes {
+            let span = node.span();
+            let lines = match *node {
+                Node::Environment { ref environment, ref children, .. } => {
+                    // A nested environment continues the current row
+                    if let Some(r) = self.open_rows.last_mut() {
+                        *r = None;
+                    }
+                    let tag_begin = self.flatten(environment.latex_begin());
+                    self.push_line(&tag_begin, span.start_line.checked_sub(1));
+                    self.open_rows.push(None);
+
+                    self.emit(children, Some(environment));
+
+                    self.open_rows.pop();
+                    let tag_end = self.flatten(environment.latex_end());
+                    self.transpiled.push_str(&tag_end);
+                    // A closed nested environment may be the last part of a row
+                    if row_based_active {
+                        *self.open_rows.last_mut().unwrap() = Some(self.transpiled.len());
+                    }
+                    self.transpiled.push_str(LINESEP);
+                    // Closing tags belong to the line which ends the environment
+                    self.origins.push(span.end_line.checked_sub(1));
+                    continue;
+                }
+                Node::Command { ref command, .. } => vec![command.latex()],
+                Node::Item { ref item, .. } => vec![item.latex()],
+                Node::Text { ref text, .. } | Node::Comment { ref text, .. } => vec![text.clone()],
+                Node::Custom { ref lines, .. } => lines.clone(),
+            };
+
+            for line in lines {
+                let l = self.flatten(line);
+                if row_based_active {
+                    let row = scan_row(&l);
+                    if row != RowLine::NotARow {
+                        terminate_row(&mut self.transpiled, &mut self.open_rows);
+                    }
+                    if let RowLine::Unterminated(pos) = row {
+                        // `unwrap()` is safe here since a row-based environment is active
+                        *self.open_rows.last_mut().unwrap() = Some(self.transpiled.len() + pos);
+                    }
+                }
+                self.push_line(&l, span.start_line.checked_sub(1));
             }
-            transpiled.push_str(LINESEP);
         }
     }
 
-    transpiled
+    fn flatten(&self, line: String) -> String {
+        if self.options.flatten_output { line.trim_start().to_string() } else { line }
+    }
+
+    // Nodes added by filters have no origin
+    fn push_line(&mut self, line: &str, origin: Option<usize>) {
+        self.transpiled.push_str(line);
+        self.transpiled.push_str(LINESEP);
+        self.origins.push(origin);
+    }
+}
+
+/// Find the environments opened by hashlines and where `emit_latex` closes them
+pub fn scan_environments<T: AsRef<str>>(lines: &[T]) -> Vec<EnvironmentSpan> {
+    use variables::front_matter_len;
+
+    // Environments opened by `# begin` do not continue across the end of the front matter
+    let front_matter = front_matter_len(lines);
+    let mut spans = scan_body_environments(&lines[..front_matter]);
+    spans.extend(scan_body_environments(&lines[front_matter..]).into_iter().map(|s| EnvironmentSpan {
+        start: s.start + front_matter,
+        end: s.end + front_matter,
+        ..s
+    }));
+    spans
 }
 
-pub fn transpile_file<T: AsRef<Path>>(path: T, options: &TranspileOptions) -> Result<(), IndentexError> {
-    use file_utils::{read_and_trim_lines, rename_indentex_file, write_to_file};
+/// Find the environments like `scan_environments` in lines without a front matter
+pub fn scan_body_environments<T: AsRef<str>>(lines: &[T]) -> Vec<EnvironmentSpan> {
+    use ast::parse;
+
+    let mut spans = Vec::new();
+    collect_environments(&parse(lines).children, 0, &mut spans);
+    spans
+}
+
+fn collect_environments(nodes: &[Node], depth: usize, spans: &mut Vec<EnvironmentSpan>) {
+    for node in nodes {
+        if let Node::Environment { ref environment, ref span, ref children } = *node {
+            spans.push(EnvironmentSpan {
+                name: environment.name().to_string(),
+                start: span.start_line - 1,
+                end: span.end_line - 1,
+                depth,
+            });
+            collect_environments(children, depth + 1, spans);
+        }
+    }
+}
+
+// Transpile a file without writing the output
+pub fn transpile_path<T: AsRef<Path>>(path: T, options: &TranspileOptions) -> Result<Transpiled, IndentexError> {
+    use file_utils::read_and_trim_lines;
 
     let lines = read_and_trim_lines(path.as_ref())?;
-    let transpiled_text = transpile(&lines, options);
+    let transpiled = transpile(&lines, Some(path.as_ref()), options);
+    if transpiled.diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(IndentexError::Transpilation(transpiled.diagnostics));
+    }
+
+    Ok(transpiled)
+}
+
+pub fn write_output<T: AsRef<Path>>(path: T, transpiled: &Transpiled) -> Result<(), IndentexError> {
+    use file_utils::{rename_indentex_file, write_to_file};
+
     let path_out = rename_indentex_file(path)?;
-    write_to_file(path_out, &transpiled_text)?;
+    write_to_file(path_out, &transpiled.text)
+}
 
-    Ok(())
+pub fn transpile_file<T: AsRef<Path>>(path: T, options: &TranspileOptions) -> Result<Transpiled, IndentexError> {
+    let transpiled = transpile_path(path.as_ref(), options)?;
+    write_output(path, &transpiled)?;
+
+    Ok(transpiled)
 }
 
 
@@ -160,4 +622,165 @@ mod tests {
         assert_eq!(scan_indents(&d), [0, 1, 1, 1, 3, 3, 2, 5, 0]);
         assert_eq!(scan_indents(&d).capacity(), 9);
     }
+
+    #[test]
+    fn strip_comment() {
+        use super::strip_comment;
+
+        assert_eq!(strip_comment("a & b"), "a & b");
+        assert_eq!(strip_comment("a & b % c"), "a & b ");
+        assert_eq!(strip_comment(r"50\% & b % c"), r"50\% & b ");
+        assert_eq!(strip_comment(r"a \\% b"), r"a \\");
+        assert_eq!(strip_comment("% a"), "");
+    }
+
+    #[test]
+    fn scan_row() {
+        use super::{scan_row, RowLine};
+
+        assert_eq!(scan_row(""), RowLine::NotARow);
+        assert_eq!(scan_row("  % foo"), RowLine::NotARow);
+        assert_eq!(scan_row(r"  \hline"), RowLine::Separator);
+        assert_eq!(scan_row(r"\cmidrule{1-2} % bar"), RowLine::Separator);
+        assert_eq!(scan_row(r"a & b \\"), RowLine::Terminated);
+        assert_eq!(scan_row(r"a & b \\[2pt] % baz"), RowLine::Terminated);
+        assert_eq!(scan_row("a & b"), RowLine::Unterminated(5));
+        assert_eq!(scan_row("  a & [b] % qux"), RowLine::Unterminated(9));
+        assert_eq!(scan_row(r"a & \\[b] c"), RowLine::Unterminated(11));
+    }
+
+    #[test]
+    fn row_environments() {
+        use super::{transpile, TranspileOptions};
+
+        let lines = ["# tabularx {X}:", "  a", "  b", "# alignment:", "  c", "  d"];
+        let mut options = TranspileOptions::default();
+        let t = transpile(&lines, None, &options);
+        // Only environments with exactly these names are row-based
+        assert_eq!(t.text,
+                   "\\begin{tabularx}{X}\n  a\n  b\n\\end{tabularx}\n\\begin{alignment}\n  c\n  d\n\\end{alignment}\n");
+
+        options.row_environments.push("tabularx".to_string());
+        let t = transpile(&lines, None, &options);
+        assert!(t.text.starts_with("\\begin{tabularx}{X}\n  a \\\\\n  b\n\\end{tabularx}\n"));
+    }
+
+    #[test]
+    fn line_map() {
+        use super::{transpile, TranspileOptions};
+        use std::path::Path;
+
+        let options = TranspileOptions {
+            prepend_do_not_edit_notice: true,
+            ..Default::default()
+        };
+        let t = transpile(&["---", "v: 1", "---", "# itemize:", "  * {{v}}", "text"], None, &options);
+
+        assert_eq!(t.text.lines().count(), t.line_map.len());
+        let lines: Vec<_> = t.line_map.iter().map(|l| l.as_ref().map(|l| l.line)).collect();
+        assert_eq!(lines, [None, None, None, None, None, Some(4), Some(5), Some(5), Some(6)]);
+        assert!(t.line_map.iter().flatten().all(|l| l.file.is_none()));
+
+        let source_lines = t.source_lines(Path::new("a.inden.tex"));
+        let lines: Vec<_> = source_lines.iter().map(|l| l.line).collect();
+        assert_eq!(lines, [0, 0, 0, 0, 0, 4, 5, 5, 6]);
+        assert!(source_lines.iter().all(|l| l.file.as_ref().is_some_and(|f| f.as_path() == Path::new("a.inden.tex"))));
+        assert_eq!(source_lines[6].text, "  \\item 1");
+    }
+
+    #[test]
+    fn variable_precedence() {
+        use super::{transpile, TranspileOptions};
+
+        let assign = |names: &[&str], value: &str| names.iter().map(|n| (n.to_string(), value.to_string())).collect();
+        let options = TranspileOptions {
+            default_variables: assign(&["a", "b", "c"], "config"),
+            variables: assign(&["a"], "set"),
+            ..Default::default()
+        };
+        let t = transpile(&["---", "a: front", "b: front", "---", "{{a}} {{b}} {{c}}"], None, &options);
+        assert_eq!(t.text, "set front config\n");
+    }
+
+    #[test]
+    fn near_misses() {
+        use super::{transpile, TranspileOptions};
+        use diagnostics::Severity;
+
+        let mut options = TranspileOptions::default();
+        let lines = ["#section: Foo", "# center:", "  # emph Bar", "  % # emph Bar", "# lstlisting:", "  #include <a.h>"];
+        let t = transpile(&lines, None, &options);
+        assert_eq!(t.text, "#section: Foo\n\\begin{center}\n  # emph Bar\n  % # emph Bar\n\\end{center}\n\
+                            \\begin{lstlisting}\n  #include <a.h>\n\\end{lstlisting}\n");
+        let messages: Vec<_> = t.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages,
+                   ["1: warning: line is copied verbatim since it is not a hashline, did you mean '# section: Foo'?",
+                    "3: warning: line is copied verbatim since it is not a hashline, did you mean '# emph: Bar'?"]);
+
+        options.strict_hashlines = true;
+        let t = transpile(&lines, None, &options);
+        assert_eq!(t.diagnostics.len(), 2);
+        assert!(t.diagnostics.iter().all(|d| d.severity == Severity::Error));
+    }
+
+    #[test]
+    fn unknown_names() {
+        use super::{transpile, TranspileOptions};
+        use names::KnownNames;
+        use std::sync::Arc;
+
+        let mut known = KnownNames::builtin();
+        known.harvest(r"\newenvironment{note}{}{}");
+        let options = TranspileOptions {
+            known_names: Some(Arc::new(known)),
+            ..Default::default()
+        };
+        let lines = ["# equaton:", "  x", "# note:", "  # mytheorem:", "    y", "# sectoin: Foo", "# foo: bar",
+                     "# figure*:", "  z"];
+        let t = transpile(&lines, None, &options);
+        let messages: Vec<_> = t.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages,
+                   ["1: warning: unknown environment 'equaton', did you mean 'equation'?",
+                    "4: warning: unknown environment 'mytheorem'",
+                    "6: warning: unknown command '\\sectoin', did you mean '\\section'?"]);
+
+        let t = transpile(&lines, None, &TranspileOptions { known_names: None, ..options });
+        assert!(t.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn explicit_environments() {
+        use super::{transpile, TranspileOptions};
+
+        let options = TranspileOptions::default();
+        let lines = ["# documentclass: article", "# begin document", "# section: Intro", "# itemize:", "  * a", "Text",
+                     "# end document"];
+        let t = transpile(&lines, None, &options);
+        assert_eq!(t.text, "\\documentclass{article}\n\\begin{document}\n\\section{Intro}\n\\begin{itemize}\n  \\item a\n\
+                            \\end{itemize}\nText\n\\end{document}\n");
+        // The closing tag belongs to the line which closes the environment
+        assert_eq!(t.line_map.last().unwrap().as_ref().map(|l| l.line), Some(7));
+        assert!(t.diagnostics.is_empty());
+
+        let t = transpile(&["# begin document", "x", "# end center", "# end"], None, &options);
+        assert_eq!(t.text, "\\begin{document}\nx\n\\end{document}\n");
+        let messages: Vec<_> = t.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages,
+                   ["3: error: '# end center' does not match '# begin document' on line 1",
+                    "4: error: '# end' without '# begin'"]);
+    }
+
+    #[test]
+    fn scan_environments() {
+        use super::{scan_environments, EnvironmentSpan};
+
+        let span = |name: &str, start, end, depth| EnvironmentSpan { name: name.to_string(), start, end, depth };
+        let lines = ["# figure [h]:", "  # center:", "    a", "", "  # caption: b", "# itemize:", "  * c", "# empty:",
+                     "d"];
+        assert_eq!(scan_environments(&lines),
+                   [span("figure", 0, 4, 0), span("center", 1, 2, 1), span("itemize", 5, 6, 0), span("empty", 7, 7, 0)]);
+        // Environments end with the front matter
+        assert_eq!(scan_environments(&["---", "# begin quote", "---", "# begin center", "a", "# end"]),
+                   [span("quote", 1, 2, 0), span("center", 3, 5, 0)]);
+    }
 }
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::vec::Vec;
+use diagnostics::Diagnostic;
+use transpile::SourceLine;
+
+
+pub const FRONT_MATTER_DELIMITER: &str = "---";
+const OPENING: &str = "{{";
+const CLOSING: &str = "}}";
+
+
+#[inline]
+pub fn is_valid_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+/// Number of lines of a terminated front matter including its delimiters
+pub fn front_matter_len<T: AsRef<str>>(lines: &[T]) -> usize {
+    match lines.first() {
+        Some(l) if l.as_ref() == FRONT_MATTER_DELIMITER => {
+            lines.iter().skip(1).position(|l| l.as_ref() == FRONT_MATTER_DELIMITER).map_or(0, |e| e + 2)
+        }
+        _ => 0,
+    }
+}
+
+/// Strip a leading `---` block of `key: value` lines and collect its variables
+pub fn extract_front_matter(lines: Vec<SourceLine>,
+                            variables: &mut HashMap<String, String>,
+                            diagnostics: &mut Vec<Diagnostic>)
+                            -> Vec<SourceLine> {
+    match lines.first() {
+        Some(l) if l.text == FRONT_MATTER_DELIMITER => {}
+        _ => return lines,
+    }
+    let end = match lines.iter().skip(1).position(|l| l.text == FRONT_MATTER_DELIMITER) {
+        Some(e) => e + 1,
+        None => {
+            diagnostics.push(Diagnostic::error(&lines[0], "front matter is not terminated by '---'"));
+            return lines;
+        }
+    };
+
+    for l in &lines[1..end] {
+        if l.text.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = match l.text.find(':') {
+            Some(i) => (l.text[..i].trim(), l.text[i + 1..].trim()),
+            None => ("", ""),
+        };
+        if is_valid_variable_name(key) {
+            variables.insert(key.to_string(), value.to_string());
+        } else {
+            diagnostics.push(Diagnostic::error(l, "expected 'name: value' in front matter"));
+        }
+    }
+
+    lines.into_iter().skip(end + 1).collect()
+}
+
+/// Replace `{{name}}` with the value of the variable, `\{{name}}` yields a literal `{{name}}`
+pub fn substitute_variables(lines: Vec<SourceLine>,
+                            variables: &HashMap<String, String>,
+                            diagnostics: &mut Vec<Diagnostic>)
+                            -> Vec<SourceLine> {
+    lines.into_iter()
+        .map(|l| {
+            if !l.text.contains(OPENING) {
+                return l;
+            }
+            let text = substitute_line(&l.text, variables, |name| {
+                let msg = format!("undefined variable '{}'", name);
+                diagnostics.push(Diagnostic::error(&l, msg));
+            });
+            l.with_text(text)
+        })
+        .collect()
+}
+
+fn substitute_line<F>(line: &str, variables: &HashMap<String, String>, mut undefined: F) -> String
+    where F: FnMut(&str)
+{
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = rest.find(OPENING) {
+        let (before, candidate) = rest.split_at(start);
+        let placeholder = candidate.find(CLOSING)
+            .map(|end| &candidate[..end + CLOSING.len()])
+            .filter(|p| is_valid_variable_name(p[OPENING.len()..p.len() - CLOSING.len()].trim()));
+        let placeholder = match placeholder {
+            Some(p) => p,
+            None => {
+                // Not a variable, e.g. `{{\bf x}}`, but `{{{x}}}` may still contain one
+                result.push_str(before);
+                result.push('{');
+                rest = &candidate[1..];
+                continue;
+            }
+        };
+
+        // An odd number of backslashes escapes the placeholder, an even one is a line break
+        let num_backslashes = before.len() - before.trim_end_matches('\\').len();
+        if num_backslashes % 2 == 1 {
+            result.push_str(&before[..before.len() - 1]);
+            result.push_str(placeholder);
+        } else {
+            result.push_str(before);
+            let name = placeholder[OPENING.len()..placeholder.len() - CLOSING.len()].trim();
+            match variables.get(name) {
+                Some(v) => result.push_str(v),
+                None => {
+                    undefined(name);
+                    result.push_str(placeholder);
+                }
+            }
+        }
+        rest = &candidate[placeholder.len()..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use transpile::SourceLine;
+
+    fn variables() -> HashMap<String, String> {
+        let mut v = HashMap::new();
+        v.insert("version".to_string(), "1.2".to_string());
+        v.insert("grant_no".to_string(), "AB-3".to_string());
+        v
+    }
+
+    #[test]
+    fn is_valid_variable_name() {
+        use super::is_valid_variable_name;
+
+        assert!(is_valid_variable_name("foo"));
+        assert!(is_valid_variable_name("_foo.bar-2"));
+        assert!(!is_valid_variable_name(""));
+        assert!(!is_valid_variable_name("2foo"));
+        assert!(!is_valid_variable_name(r"\bf x"));
+    }
+
+    #[test]
+    fn substitute_line() {
+        use super::substitute_line;
+
+        let v = variables();
+        let mut undefined = Vec::new();
+        let mut s = |l: &str| substitute_line(l, &v, |n| undefined.push(n.to_string()));
+
+        assert_eq!(s("v{{version}}, {{ grant_no }}"), "v1.2, AB-3");
+        assert_eq!(s(r"\{{version}} {{version}}"), "{{version}} 1.2");
+        assert_eq!(s(r"a \\{{version}}"), r"a \\1.2");
+        assert_eq!(s(r"{{\bf x}} {{{version}}}"), r"{{\bf x}} {1.2}");
+        assert_eq!(s("{{foo}} {{version"), "{{foo}} {{version");
+        assert_eq!(undefined, ["foo"]);
+    }
+
+    #[test]
+    fn extract_front_matter() {
+        use super::extract_front_matter;
+
+        let mut v = HashMap::new();
+        let mut d = Vec::new();
+        let lines = SourceLine::number(&["---", "version: 1.2", "", "title: a: b", "---", "foo"]);
+        let stripped = extract_front_matter(lines, &mut v, &mut d);
+        assert_eq!(stripped, [SourceLine { text: "foo".to_string(), line: 6, file: None }]);
+        assert_eq!(v.get("version").map(String::as_str), Some("1.2"));
+        assert_eq!(v.get("title").map(String::as_str), Some("a: b"));
+        assert!(d.is_empty());
+
+        let lines = SourceLine::number(&["---", "not a variable", "---"]);
+        assert!(extract_front_matter(lines, &mut v, &mut d).is_empty());
+        assert_eq!(d.len(), 1);
+
+        let lines = SourceLine::number(&["---", "foo"]);
+        assert_eq!(extract_front_matter(lines, &mut v, &mut d).len(), 2);
+        assert_eq!(d.len(), 2);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Options {
+    flatten_output: bool,
+    prepend_do_not_edit_notice: bool,
+    strict_hashlines: bool,
+    defines: HashMap<String, bool>,
+    variables: HashMap<String, String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            flatten_output: false,
+            prepend_do_not_edit_notice: true,
+            strict_hashlines: false,
+            defines: HashMap::new(),
+            variables: HashMap::new(),
+        }
+    }
+}
+
+/// Transpile a source with options given as JSON and return the output and the diagnostics as JSON
+///
+/// Without a file system, includes cannot be read and are reported as errors, references are not checked.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn transpile(source: &str, options_json: &str) -> String {
+    transpile_json(source, options_json)
+}
+
+pub fn transpile_json(source: &str, options_json: &str) -> String {
+    use file_utils::NoFileSystem;
+    use std::sync::Arc;
+    use transpile::{transpile, TranspileOptions};
+
+    // An empty string selects the defaults
+    let options: Options = if options_json.trim().is_empty() {
+        Options::default()
+    } else {
+        match ::serde_json::from_str(options_json) {
+            Ok(o) => o,
+            Err(e) => return json!({ "error": format!("invalid options: {}", e) }).to_string(),
+        }
+    };
+    let options = TranspileOptions {
+        flatten_output: options.flatten_output,
+        prepend_do_not_edit_notice: options.prepend_do_not_edit_notice,
+        strict_hashlines: options.strict_hashlines,
+        defines: options.defines,
+        variables: options.variables,
+        files: Arc::new(NoFileSystem),
+        ..Default::default()
+    };
+
+    let lines: Vec<&str> = source.lines().map(str::trim_end).collect();
+    let transpiled = transpile(&lines, None, &options);
+    let diagnostics: Vec<_> = transpiled.diagnostics
+        .iter()
+        .map(|d| {
+            json!({
+                "severity": d.severity.to_string(),
+                "file": d.file.as_ref().map(|f| f.display().to_string()),
+                "line": d.line,
+                "column": d.column,
+                "message": d.message,
+            })
+        })
+        .collect();
+    json!({ "text": transpiled.text, "diagnostics": diagnostics }).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn transpile_json() {
+        use serde_json::{from_str, Value};
+        use super::transpile_json;
+
+        let result: Value = from_str(&transpile_json("# if draft:\n  # section: {{title}}\n",
+                                                     r#"{"prepend_do_not_edit_notice": false,
+                                                        "defines": {"draft": true},
+                                                        "variables": {"title": "Foo"}}"#))
+            .unwrap();
+        assert_eq!(result, json!({ "text": "\\section{Foo}\n", "diagnostics": [] }));
+
+        let result: Value = from_str(&transpile_json("# else:\n", "")).unwrap();
+        assert!(result["text"].as_str().unwrap().starts_with("% ="));
+        assert_eq!(result["diagnostics"][0]["severity"], "error");
+        assert_eq!(result["diagnostics"][0]["line"], 1);
+        assert_eq!(result["diagnostics"][0]["file"], Value::Null);
+        assert_eq!(result["diagnostics"][0]["column"], Value::Null);
+
+        // Without a file system, references are not reported as missing
+        let result: Value = from_str(&transpile_json("\\input{intro}\n# include: a.inden.tex\n", "")).unwrap();
+        assert_eq!(result["diagnostics"].as_array().unwrap().len(), 1);
+        assert_eq!(result["diagnostics"][0]["line"], 2);
+        assert_eq!(result["diagnostics"][0]["message"], "could not include 'a.inden.tex': no file system available");
+
+        let result: Value = from_str(&transpile_json("#section: Foo\n", r#"{"strict_hashlines": true}"#)).unwrap();
+        assert_eq!(result["diagnostics"][0]["severity"], "error");
+
+        let result: Value = from_str(&transpile_json("", r#"{"flatten": true}"#)).unwrap();
+        assert!(result["error"].as_str().unwrap().starts_with("invalid options: unknown field `flatten`"));
+    }
+}
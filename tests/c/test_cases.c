@@ -0,0 +1,117 @@
+/* Transpile the test cases through the C API and compare them against the references.
+ *
+ * Usage: test_cases <test_cases directory> <references directory>
+ */
+#include <dirent.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "indentex.h"
+
+/* Same settings as tests/indentex.toml */
+static const char *const DEFINES[] = {"draft"};
+static const char *const VARIABLES[] = {"funder=DFG"};
+
+static char *read_file(const char *path, size_t *len) {
+    FILE *f = fopen(path, "rb");
+    if (f == NULL) {
+        return NULL;
+    }
+    fseek(f, 0, SEEK_END);
+    long size = ftell(f);
+    fseek(f, 0, SEEK_SET);
+    char *data = malloc((size_t)size + 1);
+    if (data != NULL && fread(data, 1, (size_t)size, f) == (size_t)size) {
+        data[size] = '\0';
+        *len = (size_t)size;
+    } else {
+        free(data);
+        data = NULL;
+    }
+    fclose(f);
+    return data;
+}
+
+/* Return zero if the transpiled test case equals the reference */
+static int compare(const char *test_cases, const char *references, const char *name) {
+    char source_path[4096], reference_path[4096];
+    size_t stem_len = strlen(name) - strlen(".tex");
+    snprintf(source_path, sizeof source_path, "%s/%.*s.inden.tex", test_cases, (int)stem_len, name);
+    snprintf(reference_path, sizeof reference_path, "%s/%s", references, name);
+
+    size_t source_len, reference_len;
+    char *source = read_file(source_path, &source_len);
+    char *reference = read_file(reference_path, &reference_len);
+    if (source == NULL || reference == NULL) {
+        fprintf(stderr, "could not read '%s' or '%s'\n", source_path, reference_path);
+        free(source);
+        free(reference);
+        return 1;
+    }
+
+    indentex_options options = {
+        .flatten_output = false,
+        .prepend_do_not_edit_notice = true,
+        .path = source_path,
+        .defines = DEFINES,
+        .num_defines = sizeof DEFINES / sizeof DEFINES[0],
+        .variables = VARIABLES,
+        .num_variables = sizeof VARIABLES / sizeof VARIABLES[0],
+    };
+    char *out = NULL;
+    indentex_diagnostics *diags = NULL;
+    int ret = indentex_transpile(source, source_len, &options, &out, &diags);
+
+    int status = 0;
+    if (ret != INDENTEX_OK) {
+        fprintf(stderr, "%s: indentex_transpile returned %d\n", source_path, ret);
+        for (size_t i = 0; diags != NULL && i < diags->len; ++i) {
+            const indentex_diagnostic *d = &diags->items[i];
+            if (d->column > 0) {
+                fprintf(stderr, "%s:%zu:%zu: %s\n", source_path, d->line, d->column, d->message);
+            } else {
+                fprintf(stderr, "%s:%zu: %s\n", source_path, d->line, d->message);
+            }
+        }
+        status = 1;
+    } else if (strcmp(out, reference) != 0) {
+        fprintf(stderr, "%s: output differs from '%s'\n", source_path, reference_path);
+        status = 1;
+    }
+
+    indentex_string_free(out);
+    indentex_diagnostics_free(diags);
+    free(source);
+    free(reference);
+    return status;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <test_cases directory> <references directory>\n", argv[0]);
+        return 2;
+    }
+
+    DIR *dir = opendir(argv[2]);
+    if (dir == NULL) {
+        fprintf(stderr, "could not open '%s'\n", argv[2]);
+        return 2;
+    }
+    int status = 0, count = 0;
+    struct dirent *entry;
+    while ((entry = readdir(dir)) != NULL) {
+        size_t len = strlen(entry->d_name);
+        if (len > 4 && strcmp(entry->d_name + len - 4, ".tex") == 0) {
+            status |= compare(argv[1], argv[2], entry->d_name);
+            ++count;
+        }
+    }
+    closedir(dir);
+
+    if (count == 0) {
+        fprintf(stderr, "no references found in '%s'\n", argv[2]);
+        return 2;
+    }
+    return status;
+}
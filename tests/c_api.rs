@@ -0,0 +1,45 @@
+// Build the C test program against the shared library and run it on the test cases
+#![cfg(all(unix, feature = "cli"))]
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+
+// The shared library is placed next to the directory of the test executable
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(Path::parent).unwrap().to_path_buf()
+}
+
+#[test]
+fn test_cases() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = library_dir();
+    let program = lib_dir.join("indentex_c_test_cases");
+
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg(root.join("tests/c/test_cases.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lindentex")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "could not compile the C test program");
+
+    let status = Command::new(&program)
+        .arg("tests/test_cases")
+        .arg("tests/references")
+        .current_dir(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
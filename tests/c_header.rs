@@ -0,0 +1,27 @@
+// Check that the C header in `include/` matches the functions in `src/capi.rs`
+extern crate cbindgen;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+
+#[test]
+fn header_is_up_to_date() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let header = root.join("include/indentex.h");
+    let config = cbindgen::Config::from_file(root.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_src(root.join("src/capi.rs"))
+        .with_config(config)
+        .generate()
+        .expect("could not generate the C header")
+        .write(&mut generated);
+
+    if env::var_os("INDENTEX_UPDATE_HEADER").is_some() {
+        fs::write(&header, &generated).unwrap();
+    }
+    assert!(fs::read(&header).unwrap() == generated,
+            "include/indentex.h is outdated, rerun this test with INDENTEX_UPDATE_HEADER=1 to regenerate it");
+}
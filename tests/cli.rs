@@ -0,0 +1,78 @@
+// Run the command line tool and check the exit status of failing subcommands
+#![cfg(feature = "cli")]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+
+// A directory for the files of one test, removed at the end of it
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("indentex-cli-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn exit_code<T: AsRef<Path>>(args: &[&str], path: T) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_indentex"))
+        .args(args)
+        .arg(path.as_ref())
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+#[test]
+fn fmt_check() {
+    let dir = TempDir::new("fmt");
+    let formatted = dir.write("formatted.inden.tex", "# itemize:\n  * a\n");
+    let unformatted = dir.write("unformatted.inden.tex", "# itemize:\n      * a\n");
+
+    assert_eq!(exit_code(&["fmt", "--check"], formatted), Some(0));
+    assert_eq!(exit_code(&["fmt", "--check"], unformatted), Some(224));
+}
+
+#[test]
+fn lint() {
+    let dir = TempDir::new("lint");
+    let path = dir.write("paper.inden.tex", "* a\n");
+
+    assert_eq!(exit_code(&["lint"], &path), Some(0));
+    assert_eq!(exit_code(&["lint", "--rule", "item-outside-list=error"], &path), Some(240));
+}
+
+#[cfg(unix)]
+#[test]
+fn build() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new("build");
+    let main = dir.write("paper.inden.tex", "# itemize:\n  * \\foo\n");
+    // The fake engine writes a log with an error in the second line of the output
+    let engine = dir.write("fake-engine",
+                           "#!/bin/sh\nfor f; do :; done\n\
+                            printf '(./%s\\n./%s:2: Undefined control sequence.\\n)\\n' \"$f\" \"$f\" \
+                            > \"${f%.tex}.log\"\nexit 1\n");
+    fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_eq!(exit_code(&["build", "--engine-command", engine.to_str().unwrap()], main), Some(192));
+}